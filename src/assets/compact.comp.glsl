@@ -0,0 +1,22 @@
+#version 450
+
+layout(local_size_x = 64) in;
+
+#include "culling.glsl"
+
+layout(set=0, binding=3)
+buffer VisibleInstances {
+    uint visible_instances[];
+};
+
+// The visible instances come first in the sorted items, so their object indices are
+// copied in order.
+void main() {
+    uint slot = gl_GlobalInvocationID.x;
+
+    if (slot >= instance_count) {
+        return;
+    }
+
+    visible_instances[slot] = sort_items[slot].y;
+}
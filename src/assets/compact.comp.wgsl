@@ -0,0 +1,17 @@
+#include "culling.wgsl"
+
+@group(0) @binding(3)
+var<storage, read_write> visible_instances: array<u32>;
+
+// The visible instances come first in the sorted items, so their object indices are
+// copied in order.
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let slot = global_id.x;
+
+    if (slot >= atomicLoad(&draw_arguments.instance_count)) {
+        return;
+    }
+
+    visible_instances[slot] = sort_items[slot].y;
+}
@@ -0,0 +1,362 @@
+#version 450
+#extension GL_EXT_samplerless_texture_functions : require
+
+layout(location=0) in vec2 tex_coord;
+layout(location=0) out vec4 f_color;
+
+#include "scene_base.glsl"
+#include "lights.glsl"
+#include "inverse.glsl"
+
+// See `GpuLightCount`, freed lights are disabled.
+layout(set = 1, binding = 0)
+readonly buffer Lights {
+    uint light_count;
+    GpuLight u_lights[];
+};
+
+layout(set=2, binding=0) uniform sampler layer_sampler;
+layout(set=2, binding=1) uniform texture2D gAlbedo;
+layout(set=2, binding=2) uniform texture2D gPosition;
+layout(set=2, binding=3) uniform texture2D gNormal;
+layout(set=2, binding=5) uniform texture2D gMaterial;
+
+// See `LIGHT_TILES`.
+struct LightTile {
+    uint count;
+    uint lights[MAX_LIGHTS_PER_TILE];
+};
+
+layout(set=2, binding=4)
+readonly buffer LightTiles {
+    LightTile light_tiles[];
+};
+
+struct ShadowCascade {
+    mat4 light_view_mat;
+    vec4 split; // x: view space depth at which the next cascade takes over, y: penumbra scale
+};
+
+// The directional light, see `GpuLightView`:
+layout(set = 3, binding = 0) uniform ShadowUniforms {
+    ShadowCascade cascades[SHADOW_CASCADES];
+    vec4 light_direction; // w: 1 if enabled
+    vec4 light_color; // a: intensity
+    vec4 light_bias; // x: constant, y: slope scaled
+    vec4 light_filtering; // x: 0 hardware, 1 poisson, 2 pcss, y: taps, z: filter radius in texels, w: light size
+};
+// The cube faces of the point light shadows and the views of the spot light shadows, see
+// `GpuPointShadows`:
+layout(set = 3, binding = 1) uniform PointShadows {
+    mat4 point_shadow_face_view_mats[POINT_SHADOW_LAYERS];
+};
+layout(set=4, binding=0) uniform samplerShadow shadow_sampler;
+layout(set=4, binding=1) uniform texture2DArray shadow;
+layout(set=4, binding=2) uniform texture2DArray point_shadow;
+// The shadow map again, for reading the depths themselves during the blocker search:
+layout(set=4, binding=3) uniform texture2DArray shadow_depths;
+
+layout(set = 5, binding = 0) uniform texture2D ssao_texture;
+
+// Spread out within every prefix, so fewer taps can use the start of it.
+const vec2 poisson_disk[32] = vec2[](
+    vec2(0.3320, 0.4622),
+    vec2(-0.5448, -0.8267),
+    vec2(-0.8952, 0.3334),
+    vec2(0.6135, -0.5828),
+    vec2(-0.2352, 0.9199),
+    vec2(-0.0452, -0.1738),
+    vec2(0.9600, 0.1474),
+    vec2(-0.8515, -0.2651),
+    vec2(0.1480, -0.8436),
+    vec2(0.2023, 0.9614),
+    vec2(-0.2302, 0.4411),
+    vec2(0.4632, -0.1070),
+    vec2(-0.4683, -0.2827),
+    vec2(0.7021, 0.5193),
+    vec2(-0.7018, 0.7103),
+    vec2(-0.1368, -0.5498),
+    vec2(-0.5066, 0.1085),
+    vec2(-0.2281, -0.8561),
+    vec2(0.4844, 0.7552),
+    vec2(0.0076, 0.2175),
+    vec2(0.8950, -0.3227),
+    vec2(-0.0071, 0.7285),
+    vec2(0.1776, -0.3882),
+    vec2(-0.9911, 0.0066),
+    vec2(0.4670, 0.2159),
+    vec2(0.7692, -0.0254),
+    vec2(-0.6138, 0.3954),
+    vec2(-0.3193, 0.6929),
+    vec2(0.4447, -0.8625),
+    vec2(0.3492, -0.5730),
+    vec2(-0.6384, -0.5442),
+    vec2(0.4800, -0.3534)
+);
+
+// Upper limit of the blocker search and penumbra of PCSS, which bounds the cost of distant
+// blockers and keeps the lookups close to the cascade.
+const float MAX_PENUMBRA_TEXELS = 24.0;
+
+float compare_shadow(int cascade, vec2 coords, float depth) {
+    return texture(sampler2DArrayShadow(shadow, shadow_sampler), vec4(coords, cascade, depth));
+}
+
+// Averages the lookups on the Poisson disk with a radius in uv.
+float poisson_shadow(int cascade, vec2 coords, float depth, float radius, mat2 rotation) {
+    int taps = int(light_filtering.y);
+    float lit = 0.0;
+    for (int i = 0; i < taps; i++) {
+        lit += compare_shadow(cascade, coords + rotation * poisson_disk[i] * radius, depth);
+    }
+    return lit / float(taps);
+}
+
+// The average depth of the texels closer to the light than `depth`, or -1 if there are none.
+float find_blockers(int cascade, vec2 coords, float depth, float radius, mat2 rotation) {
+    vec2 size = vec2(textureSize(shadow, 0).xy);
+    int taps = int(light_filtering.y);
+
+    float sum = 0.0;
+    float count = 0.0;
+    for (int i = 0; i < taps; i++) {
+        vec2 texel = clamp((coords + rotation * poisson_disk[i] * radius) * size, vec2(0.0), size - 1.0);
+        float blocker = texelFetch(shadow_depths, ivec3(ivec2(texel), cascade), 0).r;
+        if (blocker < depth) {
+            sum += blocker;
+            count += 1.0;
+        }
+    }
+
+    if (count == 0.0) {
+        return -1.0;
+    }
+    return sum / count;
+}
+
+float fetch_shadow(int cascade, vec3 world_position, float bias, mat2 rotation) {
+    vec4 homogeneous_coords = cascades[cascade].light_view_mat * vec4(world_position, 1.0);
+    if (homogeneous_coords.w <= 0.0) {
+        return 1.0;
+    }
+
+    const vec2 flip_correction = vec2(0.5, -0.5);
+
+    vec3 light_local = vec3(
+        homogeneous_coords.xy * flip_correction/homogeneous_coords.w + 0.5,
+        homogeneous_coords.z / homogeneous_coords.w - bias
+    );
+
+    float texel_size = 1.0 / float(textureSize(shadow, 0).x);
+    float filter_radius = light_filtering.z * texel_size;
+
+    switch (int(light_filtering.x)) {
+        case 1:
+            return poisson_shadow(cascade, light_local.xy, light_local.z, filter_radius, rotation);
+        case 2: {
+            // Blockers between the light and the surface widen the penumbra with their distance
+            // to it:
+            float penumbra_scale = light_filtering.w * cascades[cascade].split.y;
+            float max_radius = MAX_PENUMBRA_TEXELS * texel_size;
+
+            float search_radius = min(penumbra_scale * light_local.z, max_radius);
+            float blocker = find_blockers(cascade, light_local.xy, light_local.z, search_radius, rotation);
+            if (blocker < 0.0) {
+                return 1.0;
+            }
+
+            float penumbra = clamp(penumbra_scale * (light_local.z - blocker), filter_radius, max_radius);
+            return poisson_shadow(cascade, light_local.xy, light_local.z, penumbra, rotation);
+        }
+        default:
+            return compare_shadow(cascade, light_local.xy, light_local.z);
+    }
+}
+
+// The cube face a direction points into, in the order +x, -x, +y, -y, +z, -z.
+int cube_face(vec3 direction) {
+    vec3 magnitude = abs(direction);
+    if (magnitude.x >= magnitude.y && magnitude.x >= magnitude.z) {
+        return direction.x > 0.0 ? 0 : 1;
+    }
+    if (magnitude.y >= magnitude.z) {
+        return direction.y > 0.0 ? 2 : 3;
+    }
+    return direction.z > 0.0 ? 4 : 5;
+}
+
+// The slot of a point light has a layer per cube face, the one of a spot light a single layer.
+float fetch_light_shadow(GpuLight light, vec3 world_position) {
+    vec3 to_light = light.position.xyz - world_position;
+    int layer = int(light.shadow_layer);
+    if (light.direction_type.w == 0.0) {
+        layer += cube_face(-to_light);
+    }
+
+    // Moving the surface towards the light avoids self-shadowing:
+    vec3 biased_position = world_position + normalize(to_light) * POINT_SHADOW_BIAS;
+    vec4 homogeneous_coords = point_shadow_face_view_mats[layer] * vec4(biased_position, 1.0);
+
+    const vec2 flip_correction = vec2(0.5, -0.5);
+    vec3 light_local = vec3(
+        homogeneous_coords.xy * flip_correction/homogeneous_coords.w + 0.5,
+        homogeneous_coords.z / homogeneous_coords.w
+    );
+
+    return texture(sampler2DArrayShadow(point_shadow, shadow_sampler), vec4(light_local.xy, layer, light_local.z));
+}
+
+// See `GpuShading`:
+layout(set = 3, binding = 2) uniform Shading {
+    uint shading_model; // 0 lambert, 1 pbr
+};
+
+const float PI = 3.14159265;
+
+// Keeps the inverse square falloff finite close to the light.
+const float MIN_LIGHT_DISTANCE = 0.1;
+
+// See `Falloff`. The window smoothly reaches zero at the radius, so the lights can be culled
+// there without a visible edge.
+float attenuation(float light_distance, float radius, float falloff) {
+    float ratio = light_distance / radius;
+    float window = clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0);
+
+    float curve = 1.0;
+    if (falloff == 0.0) {
+        curve = 1.0 / max(light_distance * light_distance, MIN_LIGHT_DISTANCE * MIN_LIGHT_DISTANCE);
+    } else if (falloff == 1.0) {
+        curve = 1.0 - ratio;
+    }
+
+    return curve * window * window;
+}
+
+// The light reflected towards the viewer per incoming light, including the cosine term.
+// Lambert leaves the albedo to the ambient light. The Cook-Torrance BRDF is scaled by pi,
+// so a white diffuse surface reflects as much light as under Lambert.
+vec3 reflectance(vec3 normal, vec3 to_viewer, vec3 to_light, vec3 albedo, vec2 material) {
+    float n_dot_l = max(0.0, dot(normal, to_light));
+    if (shading_model == 0) {
+        return vec3(n_dot_l);
+    }
+
+    float metallic = material.x;
+    float roughness = max(material.y, 0.05);
+
+    vec3 half_vector = normalize(to_viewer + to_light);
+    float n_dot_v = max(dot(normal, to_viewer), 1e-4);
+    float n_dot_h = max(dot(normal, half_vector), 0.0);
+
+    // GGX distribution, Smith-Schlick geometry and Schlick Fresnel terms:
+    float alpha_squared = roughness * roughness * roughness * roughness;
+    float denominator = n_dot_h * n_dot_h * (alpha_squared - 1.0) + 1.0;
+    float distribution = alpha_squared / (PI * denominator * denominator);
+
+    float k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+    float geometry = n_dot_v / (n_dot_v * (1.0 - k) + k) * n_dot_l / (n_dot_l * (1.0 - k) + k);
+
+    vec3 f0 = mix(vec3(0.04), albedo, metallic);
+    vec3 fresnel = f0 + (1.0 - f0) * pow(1.0 - max(dot(half_vector, to_viewer), 0.0), 5.0);
+
+    vec3 specular = distribution * geometry * fresnel / (4.0 * n_dot_v * max(n_dot_l, 1e-4));
+    vec3 diffuse = (1.0 - fresnel) * (1.0 - metallic) * albedo / PI;
+
+    return (diffuse + specular) * PI * n_dot_l;
+}
+
+void main() {
+    vec4 f_albedo = texture(sampler2D(gAlbedo, layer_sampler), tex_coord);
+    vec3 f_position = texture(sampler2D(gPosition, layer_sampler), tex_coord).xyz;
+    vec3 f_normal = normalize(texture(sampler2D(gNormal, layer_sampler), tex_coord).xyz * 2.0 - 1.0);
+    vec2 f_material = texture(sampler2D(gMaterial, layer_sampler), tex_coord).rg;
+    vec3 to_viewer = normalize(-f_position);
+
+    // Blur the ssao texture:
+
+    vec2 texelSize = 1.0 / vec2(textureSize(ssao_texture, 0));
+    vec2 ssao_tex_coord = vec2(0.0, 1.0) - (tex_coord) * vec2(-1.0, 1.0);
+    float result = 0.0;
+
+    for(int i=-2; i < 2; i++) {
+        for(int j=-2; j < 2; j++) {
+            vec2 offset = vec2(float(i), float(j)) * texelSize;
+            result += texture(sampler2D(ssao_texture, layer_sampler), ssao_tex_coord + offset).r;
+        }
+    }
+
+    float f_occlusion = result / (4.0 * 4.0);
+
+    // Lambert Lighting
+
+    vec4 ambient_light = vec4(0.6, 0.6, 0.6, 1.0);
+
+    vec4 color = f_albedo * ambient_light;
+
+    vec3 world_position = (inverseNoExt(view_mat) * vec4(f_position, 1.0)).xyz;
+
+    //*** DIRECTIONAL LIGHT AND SHADOW MAPPING ***///
+
+    if (light_direction.w > 0.0) {
+        vec3 to_light = normalize((view_mat * vec4(-light_direction.xyz, 0.0)).xyz);
+        float n_dot_l = max(0.0, dot(f_normal, to_light));
+
+        // Surfaces hit at grazing angles need a larger bias to avoid self-shadowing:
+        float bias = light_bias.x + light_bias.y * (1.0 - n_dot_l);
+
+        // The first cascade reaching beyond the fragment, blended into the next one before
+        // its end:
+        float depth = -f_position.z;
+        int cascade = 0;
+        while (cascade < SHADOW_CASCADES - 1 && depth > cascades[cascade].split.x) {
+            cascade++;
+        }
+
+        // Interleaved gradient noise rotates the Poisson disk per pixel:
+        float angle = 6.2831853 * fract(52.9829189 * fract(dot(gl_FragCoord.xy, vec2(0.06711056, 0.00583715))));
+        mat2 rotation = mat2(cos(angle), sin(angle), -sin(angle), cos(angle));
+
+        float shadow_f = fetch_shadow(cascade, world_position, bias, rotation);
+
+        float split = cascades[cascade].split.x;
+        float blend = (depth - split * (1.0 - CASCADE_BLEND)) / (split * CASCADE_BLEND);
+        if (cascade < SHADOW_CASCADES - 1 && blend > 0.0) {
+            shadow_f = mix(shadow_f, fetch_shadow(cascade + 1, world_position, bias, rotation), blend);
+        }
+
+        vec3 reflected = reflectance(f_normal, to_viewer, to_light, f_albedo.rgb, f_material);
+        color += vec4(reflected * light_color.rgb * light_color.a * shadow_f, 0.0);
+    }
+
+    // Only the lights binned into the pixel's tile by the light culling pass can reach it:
+    uvec2 tile = uvec2(gl_FragCoord.xy) / LIGHT_TILE_SIZE;
+    uint tiles_x = (textureSize(sampler2D(gAlbedo, layer_sampler), 0).x + LIGHT_TILE_SIZE - 1) / LIGHT_TILE_SIZE;
+    uint tile_index = tile.y * tiles_x + tile.x;
+
+    for(uint i=0; i < light_tiles[tile_index].count; ++i) {
+        GpuLight light = u_lights[light_tiles[tile_index].lights[i]];
+        vec4 view_space_light_pos = view_mat * light.position;
+        float light_distance = distance(view_space_light_pos.xyz, f_position);
+        if (light_distance < light.radius) {
+            vec3 light_dir = normalize(view_space_light_pos.xyz - f_position);
+            float attenuation_f = attenuation(light_distance, light.radius, light.cone_falloff.z);
+
+            // Spot lights fade out between their inner and outer cone:
+            float cone_f = 1.0;
+            if (light.direction_type.w == 1.0) {
+                vec3 spot_direction = normalize((view_mat * vec4(light.direction_type.xyz, 0.0)).xyz);
+                cone_f = smoothstep(light.cone_falloff.y, light.cone_falloff.x, dot(-light_dir, spot_direction));
+            }
+
+            float shadow_f = 1.0;
+            if (cone_f > 0.0 && light.shadow_layer >= 0.0) {
+                shadow_f = fetch_light_shadow(light, world_position);
+            }
+
+            vec3 reflected = reflectance(f_normal, to_viewer, light_dir, f_albedo.rgb, f_material);
+            color += vec4(reflected * light.color.xyz * light.intensity * attenuation_f * cone_f * shadow_f, 0.0);
+        }
+    }
+
+    f_color = color * f_occlusion;
+}
@@ -0,0 +1,364 @@
+#include "scene_base.wgsl"
+#include "lights.wgsl"
+#include "inverse.wgsl"
+
+// See `GpuLightCount`, freed lights are disabled.
+struct Lights {
+    count: u32,
+    lights: array<GpuLight>,
+};
+
+struct ShadowCascade {
+    light_view_mat: mat4x4<f32>,
+    split: vec4<f32>, // x: view space depth at which the next cascade takes over, y: penumbra scale
+};
+
+// The directional light, see `GpuLightView`.
+struct ShadowUniforms {
+    cascades: array<ShadowCascade, SHADOW_CASCADES>,
+    direction: vec4<f32>, // w: 1 if enabled
+    color: vec4<f32>, // a: intensity
+    bias: vec4<f32>, // x: constant, y: slope scaled
+    filtering: vec4<f32>, // x: 0 hardware, 1 poisson, 2 pcss, y: taps, z: filter radius in texels, w: light size
+};
+
+@group(1) @binding(0) var<storage, read> lights: Lights;
+
+@group(2) @binding(0) var layer_sampler: sampler;
+@group(2) @binding(1) var g_albedo: texture_2d<f32>;
+@group(2) @binding(2) var g_position: texture_2d<f32>;
+@group(2) @binding(3) var g_normal: texture_2d<f32>;
+@group(2) @binding(5) var g_material: texture_2d<f32>;
+
+// See `LIGHT_TILES`.
+struct LightTile {
+    count: u32,
+    lights: array<u32, MAX_LIGHTS_PER_TILE>,
+};
+
+@group(2) @binding(4) var<storage, read> light_tiles: array<LightTile>;
+
+// The cube faces of the point light shadows and the views of the spot light shadows, see
+// `GpuPointShadows`.
+struct PointShadows {
+    face_view_mats: array<mat4x4<f32>, POINT_SHADOW_LAYERS>,
+};
+
+@group(3) @binding(0) var<uniform> shadow_uniforms: ShadowUniforms;
+@group(3) @binding(1) var<uniform> point_shadows: PointShadows;
+
+// See `GpuShading`.
+struct Shading {
+    model: u32, // 0 lambert, 1 pbr
+};
+
+@group(3) @binding(2) var<uniform> shading: Shading;
+@group(4) @binding(0) var shadow_sampler: sampler_comparison;
+@group(4) @binding(1) var shadow: texture_depth_2d_array;
+@group(4) @binding(2) var point_shadow: texture_depth_2d_array;
+// The shadow map again, for reading the depths themselves during the blocker search. Not
+// every backend can load from depth textures.
+@group(4) @binding(3) var shadow_depths: texture_2d_array<f32>;
+
+@group(5) @binding(0) var ssao_texture: texture_2d<f32>;
+
+// Spread out within every prefix, so fewer taps can use the start of it.
+var<private> poisson_disk: array<vec2<f32>, 32> = array<vec2<f32>, 32>(
+    vec2<f32>(0.3320, 0.4622),
+    vec2<f32>(-0.5448, -0.8267),
+    vec2<f32>(-0.8952, 0.3334),
+    vec2<f32>(0.6135, -0.5828),
+    vec2<f32>(-0.2352, 0.9199),
+    vec2<f32>(-0.0452, -0.1738),
+    vec2<f32>(0.9600, 0.1474),
+    vec2<f32>(-0.8515, -0.2651),
+    vec2<f32>(0.1480, -0.8436),
+    vec2<f32>(0.2023, 0.9614),
+    vec2<f32>(-0.2302, 0.4411),
+    vec2<f32>(0.4632, -0.1070),
+    vec2<f32>(-0.4683, -0.2827),
+    vec2<f32>(0.7021, 0.5193),
+    vec2<f32>(-0.7018, 0.7103),
+    vec2<f32>(-0.1368, -0.5498),
+    vec2<f32>(-0.5066, 0.1085),
+    vec2<f32>(-0.2281, -0.8561),
+    vec2<f32>(0.4844, 0.7552),
+    vec2<f32>(0.0076, 0.2175),
+    vec2<f32>(0.8950, -0.3227),
+    vec2<f32>(-0.0071, 0.7285),
+    vec2<f32>(0.1776, -0.3882),
+    vec2<f32>(-0.9911, 0.0066),
+    vec2<f32>(0.4670, 0.2159),
+    vec2<f32>(0.7692, -0.0254),
+    vec2<f32>(-0.6138, 0.3954),
+    vec2<f32>(-0.3193, 0.6929),
+    vec2<f32>(0.4447, -0.8625),
+    vec2<f32>(0.3492, -0.5730),
+    vec2<f32>(-0.6384, -0.5442),
+    vec2<f32>(0.4800, -0.3534),
+);
+
+// Upper limit of the blocker search and penumbra of PCSS, which bounds the cost of distant
+// blockers and keeps the lookups close to the cascade.
+const MAX_PENUMBRA_TEXELS = 24.0;
+
+fn compare_shadow(cascade: u32, coords: vec2<f32>, depth: f32) -> f32 {
+    return textureSampleCompareLevel(shadow, shadow_sampler, coords, cascade, depth);
+}
+
+// Averages the lookups on the Poisson disk with a radius in uv.
+fn poisson_shadow(cascade: u32, coords: vec2<f32>, depth: f32, radius: f32, rotation: mat2x2<f32>) -> f32 {
+    let taps = u32(shadow_uniforms.filtering.y);
+    var lit = 0.0;
+    for (var i = 0u; i < taps; i++) {
+        lit += compare_shadow(cascade, coords + rotation * poisson_disk[i] * radius, depth);
+    }
+    return lit / f32(taps);
+}
+
+// The average depth of the texels closer to the light than `depth`, or -1 if there are none.
+fn find_blockers(cascade: u32, coords: vec2<f32>, depth: f32, radius: f32, rotation: mat2x2<f32>) -> f32 {
+    let size = vec2<f32>(textureDimensions(shadow));
+    let taps = u32(shadow_uniforms.filtering.y);
+
+    var sum = 0.0;
+    var count = 0.0;
+    for (var i = 0u; i < taps; i++) {
+        let texel = clamp((coords + rotation * poisson_disk[i] * radius) * size, vec2<f32>(0.0), size - 1.0);
+        let blocker = textureLoad(shadow_depths, vec2<i32>(texel), cascade, 0).r;
+        if (blocker < depth) {
+            sum += blocker;
+            count += 1.0;
+        }
+    }
+
+    if (count == 0.0) {
+        return -1.0;
+    }
+    return sum / count;
+}
+
+fn fetch_shadow(cascade: u32, world_position: vec3<f32>, bias: f32, rotation: mat2x2<f32>) -> f32 {
+    let homogeneous_coords = shadow_uniforms.cascades[cascade].light_view_mat * vec4<f32>(world_position, 1.0);
+    if (homogeneous_coords.w <= 0.0) {
+        return 1.0;
+    }
+
+    let flip_correction = vec2<f32>(0.5, -0.5);
+
+    let light_local = vec3<f32>(
+        homogeneous_coords.xy * flip_correction / homogeneous_coords.w + 0.5,
+        homogeneous_coords.z / homogeneous_coords.w - bias,
+    );
+
+    let texel_size = 1.0 / f32(textureDimensions(shadow).x);
+    let filter_radius = shadow_uniforms.filtering.z * texel_size;
+
+    switch (u32(shadow_uniforms.filtering.x)) {
+        case 1u: {
+            return poisson_shadow(cascade, light_local.xy, light_local.z, filter_radius, rotation);
+        }
+        case 2u: {
+            // Blockers between the light and the surface widen the penumbra with their distance
+            // to it:
+            let penumbra_scale = shadow_uniforms.filtering.w * shadow_uniforms.cascades[cascade].split.y;
+            let max_radius = MAX_PENUMBRA_TEXELS * texel_size;
+
+            let search_radius = min(penumbra_scale * light_local.z, max_radius);
+            let blocker = find_blockers(cascade, light_local.xy, light_local.z, search_radius, rotation);
+            if (blocker < 0.0) {
+                return 1.0;
+            }
+
+            let penumbra = clamp(penumbra_scale * (light_local.z - blocker), filter_radius, max_radius);
+            return poisson_shadow(cascade, light_local.xy, light_local.z, penumbra, rotation);
+        }
+        default: {
+            return compare_shadow(cascade, light_local.xy, light_local.z);
+        }
+    }
+}
+
+// The cube face a direction points into, in the order +x, -x, +y, -y, +z, -z.
+fn cube_face(direction: vec3<f32>) -> u32 {
+    let magnitude = abs(direction);
+    if (magnitude.x >= magnitude.y && magnitude.x >= magnitude.z) {
+        return select(1u, 0u, direction.x > 0.0);
+    }
+    if (magnitude.y >= magnitude.z) {
+        return select(3u, 2u, direction.y > 0.0);
+    }
+    return select(5u, 4u, direction.z > 0.0);
+}
+
+// The slot of a point light has a layer per cube face, the one of a spot light a single layer.
+fn fetch_light_shadow(light: GpuLight, world_position: vec3<f32>) -> f32 {
+    let to_light = light.position.xyz - world_position;
+    var layer = u32(light.shadow_layer);
+    if (light.direction_type.w == 0.0) {
+        layer += cube_face(-to_light);
+    }
+
+    // Moving the surface towards the light avoids self-shadowing:
+    let biased_position = world_position + normalize(to_light) * POINT_SHADOW_BIAS;
+    let homogeneous_coords = point_shadows.face_view_mats[layer] * vec4<f32>(biased_position, 1.0);
+
+    let flip_correction = vec2<f32>(0.5, -0.5);
+    let light_local = vec3<f32>(
+        homogeneous_coords.xy * flip_correction / homogeneous_coords.w + 0.5,
+        homogeneous_coords.z / homogeneous_coords.w,
+    );
+
+    return textureSampleCompareLevel(point_shadow, shadow_sampler, light_local.xy, layer, light_local.z);
+}
+
+const PI = 3.14159265;
+
+// Keeps the inverse square falloff finite close to the light.
+const MIN_LIGHT_DISTANCE = 0.1;
+
+// See `Falloff`. The window smoothly reaches zero at the radius, so the lights can be culled
+// there without a visible edge.
+fn attenuation(light_distance: f32, radius: f32, falloff: f32) -> f32 {
+    let ratio = light_distance / radius;
+    let window = clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0);
+
+    var curve = 1.0;
+    if (falloff == 0.0) {
+        curve = 1.0 / max(light_distance * light_distance, MIN_LIGHT_DISTANCE * MIN_LIGHT_DISTANCE);
+    } else if (falloff == 1.0) {
+        curve = 1.0 - ratio;
+    }
+
+    return curve * window * window;
+}
+
+// The light reflected towards the viewer per incoming light, including the cosine term.
+// Lambert leaves the albedo to the ambient light. The Cook-Torrance BRDF is scaled by pi,
+// so a white diffuse surface reflects as much light as under Lambert.
+fn reflectance(normal: vec3<f32>, to_viewer: vec3<f32>, to_light: vec3<f32>, albedo: vec3<f32>, material: vec2<f32>) -> vec3<f32> {
+    let n_dot_l = max(0.0, dot(normal, to_light));
+    if (shading.model == 0u) {
+        return vec3<f32>(n_dot_l);
+    }
+
+    let metallic = material.x;
+    let roughness = max(material.y, 0.05);
+
+    let half_vector = normalize(to_viewer + to_light);
+    let n_dot_v = max(dot(normal, to_viewer), 1e-4);
+    let n_dot_h = max(dot(normal, half_vector), 0.0);
+
+    // GGX distribution, Smith-Schlick geometry and Schlick Fresnel terms:
+    let alpha_squared = roughness * roughness * roughness * roughness;
+    let denominator = n_dot_h * n_dot_h * (alpha_squared - 1.0) + 1.0;
+    let distribution = alpha_squared / (PI * denominator * denominator);
+
+    let k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+    let geometry = n_dot_v / (n_dot_v * (1.0 - k) + k) * n_dot_l / (n_dot_l * (1.0 - k) + k);
+
+    let f0 = mix(vec3<f32>(0.04), albedo, metallic);
+    let fresnel = f0 + (1.0 - f0) * pow(1.0 - max(dot(half_vector, to_viewer), 0.0), 5.0);
+
+    let specular = distribution * geometry * fresnel / (4.0 * n_dot_v * max(n_dot_l, 1e-4));
+    let diffuse = (1.0 - fresnel) * (1.0 - metallic) * albedo / PI;
+
+    return (diffuse + specular) * PI * n_dot_l;
+}
+
+@fragment
+fn main(@builtin(position) frag_coord: vec4<f32>, @location(0) tex_coord: vec2<f32>) -> @location(0) vec4<f32> {
+    let f_albedo = textureSample(g_albedo, layer_sampler, tex_coord);
+    let f_position = textureSample(g_position, layer_sampler, tex_coord).xyz;
+    let f_normal = normalize(textureSample(g_normal, layer_sampler, tex_coord).xyz * 2.0 - 1.0);
+    let f_material = textureSample(g_material, layer_sampler, tex_coord).rg;
+    let to_viewer = normalize(-f_position);
+
+    // Blur the ssao texture:
+
+    let texel_size = 1.0 / vec2<f32>(textureDimensions(ssao_texture, 0));
+    let ssao_tex_coord = vec2<f32>(0.0, 1.0) - tex_coord * vec2<f32>(-1.0, 1.0);
+    var result = 0.0;
+
+    for (var i = -2; i < 2; i++) {
+        for (var j = -2; j < 2; j++) {
+            let offset = vec2<f32>(f32(i), f32(j)) * texel_size;
+            result += textureSampleLevel(ssao_texture, layer_sampler, ssao_tex_coord + offset, 0.0).r;
+        }
+    }
+
+    let f_occlusion = result / (4.0 * 4.0);
+
+    // Lambert Lighting
+
+    let ambient_light = vec4<f32>(0.6, 0.6, 0.6, 1.0);
+
+    var color = f_albedo * ambient_light;
+
+    let world_position = (inverse_no_ext_4x4(scene.view_mat) * vec4<f32>(f_position, 1.0)).xyz;
+
+    //*** DIRECTIONAL LIGHT AND SHADOW MAPPING ***///
+
+    if (shadow_uniforms.direction.w > 0.0) {
+        let to_light = normalize((scene.view_mat * vec4<f32>(-shadow_uniforms.direction.xyz, 0.0)).xyz);
+        let n_dot_l = max(0.0, dot(f_normal, to_light));
+
+        // Surfaces hit at grazing angles need a larger bias to avoid self-shadowing:
+        let bias = shadow_uniforms.bias.x + shadow_uniforms.bias.y * (1.0 - n_dot_l);
+
+        // The first cascade reaching beyond the fragment, blended into the next one before
+        // its end:
+        let depth = -f_position.z;
+        var cascade = 0u;
+        while (cascade < SHADOW_CASCADES - 1 && depth > shadow_uniforms.cascades[cascade].split.x) {
+            cascade++;
+        }
+
+        // Interleaved gradient noise rotates the Poisson disk per pixel:
+        let angle = 6.2831853 * fract(52.9829189 * fract(dot(frag_coord.xy, vec2<f32>(0.06711056, 0.00583715))));
+        let rotation = mat2x2<f32>(cos(angle), sin(angle), -sin(angle), cos(angle));
+
+        var shadow_f = fetch_shadow(cascade, world_position, bias, rotation);
+
+        let split = shadow_uniforms.cascades[cascade].split.x;
+        let blend = (depth - split * (1.0 - CASCADE_BLEND)) / (split * CASCADE_BLEND);
+        if (cascade < SHADOW_CASCADES - 1 && blend > 0.0) {
+            shadow_f = mix(shadow_f, fetch_shadow(cascade + 1u, world_position, bias, rotation), blend);
+        }
+
+        let reflected = reflectance(f_normal, to_viewer, to_light, f_albedo.rgb, f_material);
+        color += vec4<f32>(reflected * shadow_uniforms.color.rgb * shadow_uniforms.color.a * shadow_f, 0.0);
+    }
+
+    // Only the lights binned into the pixel's tile by the light culling pass can reach it:
+    let tile = vec2<u32>(frag_coord.xy) / LIGHT_TILE_SIZE;
+    let tiles_x = (textureDimensions(g_albedo).x + LIGHT_TILE_SIZE - 1u) / LIGHT_TILE_SIZE;
+    let tile_index = tile.y * tiles_x + tile.x;
+
+    for (var i = 0u; i < light_tiles[tile_index].count; i++) {
+        let light = lights.lights[light_tiles[tile_index].lights[i]];
+        let view_space_light_pos = scene.view_mat * light.position;
+        let light_distance = distance(view_space_light_pos.xyz, f_position);
+        if (light_distance < light.radius) {
+            let light_dir = normalize(view_space_light_pos.xyz - f_position);
+            let attenuation_f = attenuation(light_distance, light.radius, light.cone_falloff.z);
+
+            // Spot lights fade out between their inner and outer cone:
+            var cone_f = 1.0;
+            if (light.direction_type.w == 1.0) {
+                let spot_direction = normalize((scene.view_mat * vec4<f32>(light.direction_type.xyz, 0.0)).xyz);
+                cone_f = smoothstep(light.cone_falloff.y, light.cone_falloff.x, dot(-light_dir, spot_direction));
+            }
+
+            var shadow_f = 1.0;
+            if (cone_f > 0.0 && light.shadow_layer >= 0.0) {
+                shadow_f = fetch_light_shadow(light, world_position);
+            }
+
+            let reflected = reflectance(f_normal, to_viewer, light_dir, f_albedo.rgb, f_material);
+            color += vec4<f32>(reflected * light.color.xyz * light.intensity * attenuation_f * cone_f * shadow_f, 0.0);
+        }
+    }
+
+    return color * f_occlusion;
+}
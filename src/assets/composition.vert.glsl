@@ -0,0 +1,9 @@
+#version 450
+
+layout(location=0) in vec3 a_position;
+layout(location=0) out vec2 tex_coord;
+
+void main() {
+    tex_coord = (vec2(0.0,1.0) - a_position.xy + vec2(1.0,0.0)) * vec2(0.5, 0.5);
+    gl_Position = vec4(a_position, 1.0);
+}
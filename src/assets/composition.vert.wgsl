@@ -0,0 +1,12 @@
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coord: vec2<f32>,
+};
+
+@vertex
+fn main(@location(0) position: vec3<f32>) -> VertexOutput {
+    var out: VertexOutput;
+    out.tex_coord = (vec2<f32>(0.0, 1.0) - position.xy + vec2<f32>(1.0, 0.0)) * vec2<f32>(0.5, 0.5);
+    out.clip_position = vec4<f32>(position, 1.0);
+    return out;
+}
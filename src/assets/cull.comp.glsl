@@ -0,0 +1,72 @@
+#version 450
+
+layout(local_size_x = 64) in;
+
+#include "instance.glsl"
+#include "culling.glsl"
+
+layout(set=0, binding=1)
+readonly buffer Instances {
+    Instance instances[];
+};
+
+// Layer in the upper 4 bits, the clip space depth without its sign and lowest 3 bits below.
+// Positive floats order like their bits; depths behind the camera count as zero.
+uint sort_key(uint layer, float depth) {
+    uint depth_bits = floatBitsToUint(max(depth, 0.0));
+    if (layer == LAYER_TRANSPARENT) {
+        depth_bits = 0x7fffffffu - depth_bits;
+    }
+    return (layer << 28) | (depth_bits >> 3);
+}
+
+// Writes the sort item of every instance, and counts the visible ones.
+void main() {
+    uint id = gl_GlobalInvocationID.x;
+
+    if (id >= sort_size) {
+        return;
+    }
+
+    sort_items[id] = INVISIBLE;
+
+    if (id >= count || instances[id].active == 0 || instances[id].layer > MAX_LAYER) {
+        return;
+    }
+
+    mat4 model_view_projection = view_projection * instances[id].model_matrix;
+
+    // The bounding box is outside if all of its corners are outside of the same plane:
+    uint outside_left = 0;
+    uint outside_right = 0;
+    uint outside_bottom = 0;
+    uint outside_top = 0;
+    uint outside_near = 0;
+    uint outside_far = 0;
+
+    for (uint i = 0; i < 8; i++) {
+        vec3 corner = vec3(
+            (i & 1) == 0 ? bounds_min.x : bounds_max.x,
+            (i & 2) == 0 ? bounds_min.y : bounds_max.y,
+            (i & 4) == 0 ? bounds_min.z : bounds_max.z
+        );
+        vec4 clip = model_view_projection * vec4(corner, 1.0);
+
+        outside_left += clip.x < -clip.w ? 1 : 0;
+        outside_right += clip.x > clip.w ? 1 : 0;
+        outside_bottom += clip.y < -clip.w ? 1 : 0;
+        outside_top += clip.y > clip.w ? 1 : 0;
+        outside_near += clip.z < 0.0 ? 1 : 0;
+        outside_far += clip.z > clip.w ? 1 : 0;
+    }
+
+    if (outside_left == 8 || outside_right == 8 ||
+        outside_bottom == 8 || outside_top == 8 ||
+        (use_near_plane != 0 && outside_near == 8) || outside_far == 8) {
+        return;
+    }
+
+    vec4 center = model_view_projection * vec4(0.5 * (bounds_min.xyz + bounds_max.xyz), 1.0);
+    sort_items[id] = uvec2(sort_key(instances[id].layer, center.z), id);
+    atomicAdd(instance_count, 1);
+}
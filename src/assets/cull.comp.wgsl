@@ -0,0 +1,63 @@
+#include "instance.wgsl"
+#include "culling.wgsl"
+
+@group(0) @binding(1)
+var<storage, read> instances: array<Instance>;
+
+// Layer in the upper 4 bits, the clip space depth without its sign and lowest 3 bits below.
+// Positive floats order like their bits; depths behind the camera count as zero.
+fn sort_key(layer: u32, depth: f32) -> u32 {
+    var depth_bits = bitcast<u32>(max(depth, 0.0));
+    if (layer == LAYER_TRANSPARENT) {
+        depth_bits = 0x7fffffffu - depth_bits;
+    }
+    return (layer << 28u) | (depth_bits >> 3u);
+}
+
+// Writes the sort item of every instance, and counts the visible ones.
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let id = global_id.x;
+
+    if (id >= cull.sort_size) {
+        return;
+    }
+
+    sort_items[id] = INVISIBLE;
+
+    if (id >= cull.count || instances[id].is_active == 0u || instances[id].layer > MAX_LAYER) {
+        return;
+    }
+
+    let model_view_projection = cull.view_projection * instances[id].model_matrix;
+
+    // The bounding box is outside if all of its corners are outside of the same plane:
+    var outside_left = 0u;
+    var outside_right = 0u;
+    var outside_bottom = 0u;
+    var outside_top = 0u;
+    var outside_near = 0u;
+    var outside_far = 0u;
+
+    for (var i = 0u; i < 8u; i++) {
+        let corner = select(cull.bounds_min.xyz, cull.bounds_max.xyz, vec3<bool>((i & 1u) != 0u, (i & 2u) != 0u, (i & 4u) != 0u));
+        let clip = model_view_projection * vec4<f32>(corner, 1.0);
+
+        outside_left += select(0u, 1u, clip.x < -clip.w);
+        outside_right += select(0u, 1u, clip.x > clip.w);
+        outside_bottom += select(0u, 1u, clip.y < -clip.w);
+        outside_top += select(0u, 1u, clip.y > clip.w);
+        outside_near += select(0u, 1u, clip.z < 0.0);
+        outside_far += select(0u, 1u, clip.z > clip.w);
+    }
+
+    if (outside_left == 8u || outside_right == 8u ||
+        outside_bottom == 8u || outside_top == 8u ||
+        (cull.use_near_plane != 0u && outside_near == 8u) || outside_far == 8u) {
+        return;
+    }
+
+    let center = model_view_projection * vec4<f32>(0.5 * (cull.bounds_min.xyz + cull.bounds_max.xyz), 1.0);
+    sort_items[id] = vec2<u32>(sort_key(instances[id].layer, center.z), id);
+    atomicAdd(&draw_arguments.instance_count, 1u);
+}
@@ -0,0 +1,44 @@
+// The bindings shared by the culling and sorting shaders, see `InstanceCulling`.
+
+layout(set=0, binding=0)
+uniform CullUniforms {
+    mat4 view_projection;
+    vec4 bounds_min;
+    vec4 bounds_max;
+    // The capacity of the instance buffer.
+    uint count;
+    uint use_near_plane;
+    // `count` rounded up to a power of two, at least `LOCAL_SORT_SIZE`.
+    uint sort_size;
+};
+
+// The sort key and object index of every visible instance, see `draw_order`. The other
+// entries hold `INVISIBLE`, which sorts behind all of them.
+layout(set=0, binding=2)
+buffer SortItems {
+    uvec2 sort_items[];
+};
+
+const uvec2 INVISIBLE = uvec2(0xffffffffu, 0xffffffffu);
+
+// Items with equal keys are ordered by their object index, so the order is stable
+// between frames.
+bool sorts_before(uvec2 a, uvec2 b) {
+    return a.x < b.x || (a.x == b.x && a.y < b.y);
+}
+
+// One step of the bitonic sort, see `sort_steps`.
+layout(set=0, binding=5)
+uniform SortStep {
+    uint k;
+    uint j;
+} sort_step;
+
+layout(set=0, binding=4)
+buffer DrawArguments {
+    uint index_count;
+    uint instance_count;
+    uint first_index;
+    int base_vertex;
+    uint first_instance;
+};
@@ -0,0 +1,48 @@
+// The bindings shared by the culling and sorting shaders, see `InstanceCulling`.
+
+struct CullUniforms {
+    view_projection: mat4x4<f32>,
+    bounds_min: vec4<f32>,
+    bounds_max: vec4<f32>,
+    // The capacity of the instance buffer.
+    count: u32,
+    use_near_plane: u32,
+    // `count` rounded up to a power of two, at least `LOCAL_SORT_SIZE`.
+    sort_size: u32,
+};
+
+@group(0) @binding(0)
+var<uniform> cull: CullUniforms;
+
+// The sort key and object index of every visible instance, see `draw_order`. The other
+// entries hold `INVISIBLE`, which sorts behind all of them.
+@group(0) @binding(2)
+var<storage, read_write> sort_items: array<vec2<u32>>;
+
+const INVISIBLE = vec2<u32>(0xffffffffu, 0xffffffffu);
+
+// Items with equal keys are ordered by their object index, so the order is stable
+// between frames.
+fn sorts_before(a: vec2<u32>, b: vec2<u32>) -> bool {
+    return a.x < b.x || (a.x == b.x && a.y < b.y);
+}
+
+// One step of the bitonic sort, see `sort_steps`.
+struct SortStep {
+    k: u32,
+    j: u32,
+};
+
+@group(0) @binding(5)
+var<uniform> sort_step: SortStep;
+
+struct DrawArguments {
+    index_count: u32,
+    instance_count: atomic<u32>,
+    first_index: u32,
+    base_vertex: i32,
+    first_instance: u32,
+};
+
+@group(0) @binding(4)
+var<storage, read_write> draw_arguments: DrawArguments;
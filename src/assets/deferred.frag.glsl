@@ -0,0 +1,32 @@
+#version 450
+
+layout(location=0) in vec3 world_position;
+layout(location=1) in vec3 normal;
+layout(location=2) in flat uint part_id;
+layout(location=3) in flat uint material_index;
+
+layout(location=0) out vec4 f_albedo;
+layout(location=1) out vec4 f_position;
+layout(location=2) out vec4 f_normal;
+layout(location=3) out vec4 f_material;
+
+// See `GpuMaterial`.
+struct Material {
+    vec4 primary; // base color
+    vec4 secondary; // x: metallic, y: roughness
+    vec4 tertiary;
+    vec4 quaternary;
+    vec4 padding[12];
+};
+
+layout(set=1, binding=0)
+readonly buffer Materials {
+    Material materials[];
+};
+
+void main() {
+    f_position = vec4(world_position, 1.0);
+    f_normal = vec4(normalize(normal) * 0.5 + 0.5, 1.0);
+    f_albedo = materials[material_index].primary;
+    f_material = vec4(materials[material_index].secondary.xy, 0.0, 0.0);
+}
@@ -0,0 +1,35 @@
+// See `GpuMaterial`.
+struct Material {
+    primary: vec4<f32>, // base color
+    secondary: vec4<f32>, // x: metallic, y: roughness
+    tertiary: vec4<f32>,
+    quaternary: vec4<f32>,
+    padding: array<vec4<f32>, 12>,
+};
+
+@group(1) @binding(0)
+var<storage, read> materials: array<Material>;
+
+struct FragmentInput {
+    @location(0) world_position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(2) @interpolate(flat) part_id: u32,
+    @location(3) @interpolate(flat) material_index: u32,
+};
+
+struct GBuffer {
+    @location(0) albedo: vec4<f32>,
+    @location(1) position: vec4<f32>,
+    @location(2) normal: vec4<f32>,
+    @location(3) material: vec4<f32>,
+};
+
+@fragment
+fn main(in: FragmentInput) -> GBuffer {
+    var out: GBuffer;
+    out.position = vec4<f32>(in.world_position, 1.0);
+    out.normal = vec4<f32>(normalize(in.normal) * 0.5 + 0.5, 1.0);
+    out.albedo = materials[in.material_index].primary;
+    out.material = vec4<f32>(materials[in.material_index].secondary.xy, 0.0, 0.0);
+    return out;
+}
@@ -0,0 +1,31 @@
+#version 450
+
+layout(location=0) in vec3 a_position;
+layout(location=1) in vec3 a_normal;
+layout(location=2) in uint a_part_id;
+layout(location=3) in uint a_instance_id;
+
+#include "scene_base.glsl"
+#include "instance.glsl"
+#include "inverse.glsl"
+
+layout(set=2, binding=0)
+readonly buffer Instances {
+    Instance instances[];
+};
+
+layout(location=0) out vec3 world_position;
+layout(location=1) out vec3 normal;
+layout(location=2) out flat uint part_id;
+layout(location=3) out flat uint material_index;
+
+void main() {
+    mat4 a_model_matrix = instances[a_instance_id].model_matrix;
+    vec4 position = view_mat * a_model_matrix * vec4(a_position, 1.0);
+    mat3 normal_matrix = transpose(inverseNoExt(mat3(view_mat * a_model_matrix)));
+    normal = normal_matrix * a_normal;
+    world_position = position.xyz;
+    part_id = a_part_id;
+    material_index = instances[a_instance_id].material;
+    gl_Position = projection_mat * position;
+}
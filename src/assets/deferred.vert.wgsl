@@ -0,0 +1,37 @@
+#include "scene_base.wgsl"
+#include "instance.wgsl"
+#include "inverse.wgsl"
+
+@group(2) @binding(0)
+var<storage, read> instances: array<Instance>;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(2) part_id: u32,
+    @location(3) instance_id: u32,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) world_position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(2) @interpolate(flat) part_id: u32,
+    @location(3) @interpolate(flat) material_index: u32,
+};
+
+@vertex
+fn main(in: VertexInput) -> VertexOutput {
+    let instance = instances[in.instance_id];
+    let model_view = scene.view_mat * instance.model_matrix;
+    let position = model_view * vec4<f32>(in.position, 1.0);
+    let normal_matrix = transpose(inverse_no_ext_3x3(mat3x3<f32>(model_view[0].xyz, model_view[1].xyz, model_view[2].xyz)));
+
+    var out: VertexOutput;
+    out.normal = normal_matrix * in.normal;
+    out.world_position = position.xyz;
+    out.part_id = in.part_id;
+    out.material_index = instance.material;
+    out.clip_position = scene.projection_mat * position;
+    return out;
+}
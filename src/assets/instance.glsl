@@ -0,0 +1,8 @@
+// Matches `GpuInstance`.
+struct Instance {
+    mat4 model_matrix;
+    uint material;
+    uint active;
+    uint layer;
+    uint padding;
+};
@@ -0,0 +1,8 @@
+// Matches `GpuInstance`.
+struct Instance {
+    model_matrix: mat4x4<f32>,
+    material: u32,
+    is_active: u32,
+    layer: u32,
+    padding: u32,
+};
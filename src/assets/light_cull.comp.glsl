@@ -0,0 +1,97 @@
+#version 450
+
+layout(local_size_x = LIGHT_TILE_SIZE, local_size_y = LIGHT_TILE_SIZE) in;
+
+#include "scene_base.glsl"
+#include "lights.glsl"
+
+// See `GpuLightCount`, freed lights are disabled.
+layout(set = 1, binding = 0)
+readonly buffer Lights {
+    uint light_count;
+    GpuLight u_lights[];
+};
+
+// See `LIGHT_TILES`.
+struct LightTile {
+    uint count;
+    uint lights[MAX_LIGHTS_PER_TILE];
+};
+
+layout(set=2, binding=0) uniform texture2D gPosition;
+
+layout(set=2, binding=1)
+buffer LightTiles {
+    LightTile tiles[];
+};
+
+// The view space depth range of the tile. Positive floats order like their bits, so they
+// can be compared as integers:
+shared uint min_depth;
+shared uint max_depth;
+shared uint tile_light_count;
+
+void main() {
+    if (gl_LocalInvocationIndex == 0) {
+        min_depth = floatBitsToUint(3.40282347e38);
+        max_depth = 0;
+        tile_light_count = 0;
+    }
+    barrier();
+
+    uvec2 size = uvec2(textureSize(gPosition, 0));
+    if (all(lessThan(gl_GlobalInvocationID.xy, size))) {
+        vec3 position = texelFetch(gPosition, ivec2(gl_GlobalInvocationID.xy), 0).xyz;
+
+        // The background is cleared to zero, geometry lies in front of the camera:
+        if (position.z < 0.0) {
+            atomicMin(min_depth, floatBitsToUint(-position.z));
+            atomicMax(max_depth, floatBitsToUint(-position.z));
+        }
+    }
+    barrier();
+
+    // Empty for tiles showing only the background:
+    float near = uintBitsToFloat(min_depth);
+    float far = uintBitsToFloat(max_depth);
+
+    // The edges of the tile in normalized device coordinates:
+    vec2 tile_min = vec2(gl_WorkGroupID.xy * LIGHT_TILE_SIZE);
+    vec2 tile_max = min(tile_min + float(LIGHT_TILE_SIZE), vec2(size));
+    vec2 ndc_min = vec2(tile_min.x, tile_max.y) / vec2(size) * vec2(2.0, -2.0) + vec2(-1.0, 1.0);
+    vec2 ndc_max = vec2(tile_max.x, tile_min.y) / vec2(size) * vec2(2.0, -2.0) + vec2(-1.0, 1.0);
+
+    // A view space point lies within the tile if its clip space x and y lie between the edges
+    // times w, which makes a plane of every edge:
+    mat4 projection = transpose(projection_mat);
+    vec4 planes[4] = vec4[4](
+        projection[0] - ndc_min.x * projection[3],
+        ndc_max.x * projection[3] - projection[0],
+        projection[1] - ndc_min.y * projection[3],
+        ndc_max.y * projection[3] - projection[1]
+    );
+
+    uint tile_index = gl_WorkGroupID.y * gl_NumWorkGroups.x + gl_WorkGroupID.x;
+
+    for (uint i = gl_LocalInvocationIndex; i < light_count; i += LIGHT_TILE_SIZE * LIGHT_TILE_SIZE) {
+        GpuLight light = u_lights[i];
+        vec3 center = (view_mat * light.position).xyz;
+
+        bool visible = light.enabled > 0.0 && -center.z + light.radius >= near && -center.z - light.radius <= far;
+        for (int plane = 0; plane < 4; ++plane) {
+            visible = visible && dot(planes[plane].xyz, center) + planes[plane].w >= -light.radius * length(planes[plane].xyz);
+        }
+
+        if (visible) {
+            uint slot = atomicAdd(tile_light_count, 1);
+            if (slot < MAX_LIGHTS_PER_TILE) {
+                tiles[tile_index].lights[slot] = i;
+            }
+        }
+    }
+    barrier();
+
+    if (gl_LocalInvocationIndex == 0) {
+        tiles[tile_index].count = min(tile_light_count, uint(MAX_LIGHTS_PER_TILE));
+    }
+}
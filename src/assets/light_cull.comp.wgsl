@@ -0,0 +1,96 @@
+#include "scene_base.wgsl"
+#include "lights.wgsl"
+
+// See `GpuLightCount`, freed lights are disabled.
+struct Lights {
+    count: u32,
+    lights: array<GpuLight>,
+};
+
+// See `LIGHT_TILES`.
+struct LightTile {
+    count: u32,
+    lights: array<u32, MAX_LIGHTS_PER_TILE>,
+};
+
+@group(1) @binding(0) var<storage, read> lights: Lights;
+
+@group(2) @binding(0) var g_position: texture_2d<f32>;
+@group(2) @binding(1) var<storage, read_write> tiles: array<LightTile>;
+
+// The view space depth range of the tile. Positive floats order like their bits, so they
+// can be compared as integers:
+var<workgroup> min_depth: atomic<u32>;
+var<workgroup> max_depth: atomic<u32>;
+var<workgroup> light_count: atomic<u32>;
+
+@compute @workgroup_size(LIGHT_TILE_SIZE, LIGHT_TILE_SIZE)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(local_invocation_index) local_index: u32,
+    @builtin(workgroup_id) tile_id: vec3<u32>,
+    @builtin(num_workgroups) tile_count: vec3<u32>,
+) {
+    if (local_index == 0u) {
+        atomicStore(&min_depth, bitcast<u32>(3.40282347e38));
+        atomicStore(&max_depth, 0u);
+        atomicStore(&light_count, 0u);
+    }
+    workgroupBarrier();
+
+    let size = textureDimensions(g_position, 0);
+    if (all(global_id.xy < size)) {
+        let position = textureLoad(g_position, global_id.xy, 0).xyz;
+
+        // The background is cleared to zero, geometry lies in front of the camera:
+        if (position.z < 0.0) {
+            atomicMin(&min_depth, bitcast<u32>(-position.z));
+            atomicMax(&max_depth, bitcast<u32>(-position.z));
+        }
+    }
+    workgroupBarrier();
+
+    // Empty for tiles showing only the background:
+    let near = bitcast<f32>(atomicLoad(&min_depth));
+    let far = bitcast<f32>(atomicLoad(&max_depth));
+
+    // The edges of the tile in normalized device coordinates:
+    let tile_min = vec2<f32>(tile_id.xy * LIGHT_TILE_SIZE);
+    let tile_max = min(tile_min + f32(LIGHT_TILE_SIZE), vec2<f32>(size));
+    let ndc_min = vec2<f32>(tile_min.x, tile_max.y) / vec2<f32>(size) * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0);
+    let ndc_max = vec2<f32>(tile_max.x, tile_min.y) / vec2<f32>(size) * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0);
+
+    // A view space point lies within the tile if its clip space x and y lie between the edges
+    // times w, which makes a plane of every edge:
+    let projection = transpose(scene.projection_mat);
+    var planes = array<vec4<f32>, 4>(
+        projection[0] - ndc_min.x * projection[3],
+        ndc_max.x * projection[3] - projection[0],
+        projection[1] - ndc_min.y * projection[3],
+        ndc_max.y * projection[3] - projection[1],
+    );
+
+    let tile_index = tile_id.y * tile_count.x + tile_id.x;
+
+    for (var i = local_index; i < lights.count; i += LIGHT_TILE_SIZE * LIGHT_TILE_SIZE) {
+        let light = lights.lights[i];
+        let center = (scene.view_mat * light.position).xyz;
+
+        var visible = light.enabled > 0.0 && -center.z + light.radius >= near && -center.z - light.radius <= far;
+        for (var plane = 0; plane < 4; plane++) {
+            visible = visible && dot(planes[plane].xyz, center) + planes[plane].w >= -light.radius * length(planes[plane].xyz);
+        }
+
+        if (visible) {
+            let slot = atomicAdd(&light_count, 1u);
+            if (slot < MAX_LIGHTS_PER_TILE) {
+                tiles[tile_index].lights[slot] = i;
+            }
+        }
+    }
+    workgroupBarrier();
+
+    if (local_index == 0u) {
+        tiles[tile_index].count = min(atomicLoad(&light_count), MAX_LIGHTS_PER_TILE);
+    }
+}
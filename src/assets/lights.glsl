@@ -0,0 +1,11 @@
+// Matches `GpuLight`.
+struct GpuLight {
+    vec4 position; // 4 * 4 = 16
+    vec4 color; // 4 * 4 = 16
+    float intensity; // 4
+    float radius; // 4
+    float enabled; // 4
+    float shadow_layer; // 4, the first layer of the shadow slot, -1 without shadows
+    vec4 direction_type; // 4 * 4 = 16, xyz: spot direction, w: 0 point, 1 spot
+    vec4 cone_falloff; // 4 * 4 = 16, x: cosine of the inner angle, y: cosine of the outer angle, z: 0 inverse square, 1 linear, 2 constant
+};
@@ -0,0 +1,11 @@
+// Matches `GpuLight`.
+struct GpuLight {
+    position: vec4<f32>,
+    color: vec4<f32>,
+    intensity: f32,
+    radius: f32,
+    enabled: f32,
+    shadow_layer: f32, // The first layer of the shadow slot, -1 without shadows
+    direction_type: vec4<f32>, // xyz: spot direction, w: 0 point, 1 spot
+    cone_falloff: vec4<f32>, // x: cosine of the inner angle, y: cosine of the outer angle, z: 0 inverse square, 1 linear, 2 constant
+};
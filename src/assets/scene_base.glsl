@@ -0,0 +1,7 @@
+// The scene uniforms written by `SceneBaseResources`, always bound to set 0.
+layout(set=0, binding=0) uniform SceneBase {
+    mat4 view_mat;
+    mat4 projection_mat;
+    vec2 window_size;
+    vec2 z_range; // near and far plane distance
+};
@@ -0,0 +1,9 @@
+// The scene uniforms written by `SceneBaseResources`, always bound to group 0.
+struct SceneBase {
+    view_mat: mat4x4<f32>,
+    projection_mat: mat4x4<f32>,
+    window_size: vec2<f32>,
+    z_range: vec2<f32>, // near and far plane distance
+};
+
+@group(0) @binding(0) var<uniform> scene: SceneBase;
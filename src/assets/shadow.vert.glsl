@@ -0,0 +1,21 @@
+#version 450
+
+layout(location=0) in vec3 a_position;
+layout(location=1) in uint a_instance_id;
+
+layout(set=0, binding=0)
+uniform SceneUniforms {
+    mat4 light_view_matrix;
+};
+
+#include "instance.glsl"
+
+layout(set=1, binding=0)
+readonly buffer Instances {
+    Instance instances[];
+};
+
+void main() {
+    mat4 a_model_matrix = instances[a_instance_id].model_matrix;
+    gl_Position = light_view_matrix * a_model_matrix * vec4(a_position, 1.0);
+}
@@ -0,0 +1,17 @@
+struct SceneUniforms {
+    light_view_matrix: mat4x4<f32>,
+};
+
+#include "instance.wgsl"
+
+@group(0) @binding(0)
+var<uniform> scene: SceneUniforms;
+
+@group(1) @binding(0)
+var<storage, read> instances: array<Instance>;
+
+@vertex
+fn main(@location(0) position: vec3<f32>, @location(1) instance_id: u32) -> @builtin(position) vec4<f32> {
+    let model_matrix = instances[instance_id].model_matrix;
+    return scene.light_view_matrix * model_matrix * vec4<f32>(position, 1.0);
+}
@@ -0,0 +1,27 @@
+#version 450
+
+layout(local_size_x = SORT_WORKGROUP_SIZE) in;
+
+#include "culling.glsl"
+
+// A step of the bitonic sort with a distance of at least `LOCAL_SORT_SIZE`, where the
+// compared items lie in different blocks of `sort_local.comp`. Every invocation
+// compares one pair.
+void main() {
+    uint pair = gl_GlobalInvocationID.x;
+
+    if (pair >= sort_size / 2) {
+        return;
+    }
+
+    uint j = sort_step.j;
+    uint i = 2 * j * (pair / j) + pair % j;
+    uvec2 a = sort_items[i];
+    uvec2 b = sort_items[i + j];
+    bool ascending = (i & sort_step.k) == 0;
+
+    if (sorts_before(b, a) == ascending) {
+        sort_items[i] = b;
+        sort_items[i + j] = a;
+    }
+}
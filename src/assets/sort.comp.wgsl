@@ -0,0 +1,24 @@
+#include "culling.wgsl"
+
+// A step of the bitonic sort with a distance of at least `LOCAL_SORT_SIZE`, where the
+// compared items lie in different blocks of `sort_local.comp`. Every invocation
+// compares one pair.
+@compute @workgroup_size(SORT_WORKGROUP_SIZE)
+fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let pair = global_id.x;
+
+    if (pair >= cull.sort_size / 2u) {
+        return;
+    }
+
+    let j = sort_step.j;
+    let i = 2u * j * (pair / j) + pair % j;
+    let a = sort_items[i];
+    let b = sort_items[i + j];
+    let ascending = (i & sort_step.k) == 0u;
+
+    if (sorts_before(b, a) == ascending) {
+        sort_items[i] = b;
+        sort_items[i + j] = a;
+    }
+}
@@ -0,0 +1,38 @@
+#version 450
+
+layout(local_size_x = LOCAL_SORT_SIZE / 2) in;
+
+#include "culling.glsl"
+
+shared uvec2 block[LOCAL_SORT_SIZE];
+
+// The steps of the bitonic sort with a distance below `LOCAL_SORT_SIZE` in workgroup
+// memory, one block of items per workgroup. The first dispatch sorts the blocks with all
+// stages up to `LOCAL_SORT_SIZE`, later ones finish stage `sort_step.k` after `sort.comp`.
+void main() {
+    uint local_index = gl_LocalInvocationIndex;
+    uint offset = gl_WorkGroupID.x * LOCAL_SORT_SIZE;
+    uint half_size = LOCAL_SORT_SIZE / 2;
+
+    block[local_index] = sort_items[offset + local_index];
+    block[local_index + half_size] = sort_items[offset + local_index + half_size];
+    barrier();
+
+    for (uint k = sort_step.k <= LOCAL_SORT_SIZE ? 2 : sort_step.k; k <= max(sort_step.k, uint(LOCAL_SORT_SIZE)); k *= 2) {
+        for (uint j = min(k, uint(LOCAL_SORT_SIZE)) / 2; j > 0; j /= 2) {
+            uint i = 2 * j * (local_index / j) + local_index % j;
+            uvec2 a = block[i];
+            uvec2 b = block[i + j];
+            bool ascending = ((offset + i) & k) == 0;
+
+            if (sorts_before(b, a) == ascending) {
+                block[i] = b;
+                block[i + j] = a;
+            }
+            barrier();
+        }
+    }
+
+    sort_items[offset + local_index] = block[local_index];
+    sort_items[offset + local_index + half_size] = block[local_index + half_size];
+}
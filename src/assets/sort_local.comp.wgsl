@@ -0,0 +1,36 @@
+#include "culling.wgsl"
+
+var<workgroup> block: array<vec2<u32>, LOCAL_SORT_SIZE>;
+
+// The steps of the bitonic sort with a distance below `LOCAL_SORT_SIZE` in workgroup
+// memory, one block of items per workgroup. The first dispatch sorts the blocks with all
+// stages up to `LOCAL_SORT_SIZE`, later ones finish stage `sort_step.k` after `sort.comp`.
+@compute @workgroup_size(LOCAL_SORT_SIZE / 2)
+fn main(@builtin(local_invocation_index) local_index: u32, @builtin(workgroup_id) workgroup_id: vec3<u32>) {
+    let offset = workgroup_id.x * LOCAL_SORT_SIZE;
+    let half_size = LOCAL_SORT_SIZE / 2u;
+
+    block[local_index] = sort_items[offset + local_index];
+    block[local_index + half_size] = sort_items[offset + local_index + half_size];
+    workgroupBarrier();
+
+    var k = select(sort_step.k, 2u, sort_step.k <= LOCAL_SORT_SIZE);
+
+    for (; k <= max(sort_step.k, LOCAL_SORT_SIZE); k *= 2u) {
+        for (var j = min(k, LOCAL_SORT_SIZE) / 2u; j > 0u; j /= 2u) {
+            let i = 2u * j * (local_index / j) + local_index % j;
+            let a = block[i];
+            let b = block[i + j];
+            let ascending = ((offset + i) & k) == 0u;
+
+            if (sorts_before(b, a) == ascending) {
+                block[i] = b;
+                block[i + j] = a;
+            }
+            workgroupBarrier();
+        }
+    }
+
+    sort_items[offset + local_index] = block[local_index];
+    sort_items[offset + local_index + half_size] = block[local_index + half_size];
+}
@@ -0,0 +1,51 @@
+#version 450
+
+layout(location=0) in vec2 tex_coord;
+layout(location=0) out float f_occlusion;
+
+#include "scene_base.glsl"
+
+layout(set=1, binding=0) uniform sampler layer_sampler;
+layout(set=1, binding=2) uniform texture2D gPosition;
+layout(set=1, binding=3) uniform texture2D gNormal;
+
+layout(set = 2, binding = 0) uniform Hemisphere { vec3 sample_points[SAMPLE_COUNT]; };
+layout(set = 2, binding = 1) uniform sampler random_vec_sampler;
+layout(set = 2, binding = 2) uniform texture2D random_vec_texture;
+
+void main() {
+    vec3 f_position = texture(sampler2D(gPosition, layer_sampler), tex_coord).xyz;
+    vec3 f_normal = normalize(texture(sampler2D(gNormal, layer_sampler), tex_coord).rgb * 2.0 - 1.0);
+
+    vec2 noise_scale = window_size / float(NOISE_SIZE); // scale the noise texture to cover whole screen
+
+    vec3 random_vector = normalize(texture(sampler2D(random_vec_texture, random_vec_sampler), tex_coord * noise_scale).xyz);
+    vec3 tangent = normalize( random_vector - f_normal * dot(random_vector, f_normal) );
+    vec3 bitangent = cross(f_normal, tangent);
+    mat3 tbn = mat3(tangent, bitangent, f_normal);
+
+    float radius = 0.40;
+    float ssao_bias = 0.01;
+    float occ = 0.0;
+    vec3 debug = vec3(0.0, 0.0, 0.0);
+
+    for(int i=0; i < SAMPLE_COUNT; ++i) {
+        vec3 point = tbn * sample_points[i];
+        point = f_position + point * radius;
+
+        vec4 offset = vec4(point, 1.0);
+        offset = projection_mat * offset;
+        offset.xyz /= offset.w;
+        offset.xy = offset.xy * vec2(0.5, -0.5) + 0.5;
+
+        vec3 occluder_position = texture(sampler2D(gPosition, layer_sampler), offset.xy).xyz;
+
+        if(i==32) { debug = occluder_position; }
+
+        float rangeCheck = smoothstep(0.0, 1.0, radius / abs(point.z - occluder_position.z));
+        occ += (occluder_position.z >= (point.z + ssao_bias) ? 1.0 : 0.0) * rangeCheck;
+    }
+
+    // We want shadows only, so we clamp everything above 0.7:
+    f_occlusion = smoothstep(0.0, 0.7, 1.0 - occ / float(SAMPLE_COUNT));
+}
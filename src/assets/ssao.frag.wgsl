@@ -0,0 +1,48 @@
+// SAMPLE_COUNT and NOISE_SIZE are defined by the renderer.
+
+#include "scene_base.wgsl"
+
+struct Hemisphere {
+    sample_points: array<vec3<f32>, SAMPLE_COUNT>,
+};
+
+@group(1) @binding(0) var layer_sampler: sampler;
+@group(1) @binding(2) var g_position: texture_2d<f32>;
+@group(1) @binding(3) var g_normal: texture_2d<f32>;
+
+@group(2) @binding(0) var<uniform> hemisphere: Hemisphere;
+@group(2) @binding(1) var random_vec_sampler: sampler;
+@group(2) @binding(2) var random_vec_texture: texture_2d<f32>;
+
+@fragment
+fn main(@location(0) tex_coord: vec2<f32>) -> @location(0) f32 {
+    let f_position = textureSample(g_position, layer_sampler, tex_coord).xyz;
+    let f_normal = normalize(textureSample(g_normal, layer_sampler, tex_coord).rgb * 2.0 - 1.0);
+
+    let noise_scale = scene.window_size / f32(NOISE_SIZE); // scale the noise texture to cover whole screen
+
+    let random_vector = normalize(textureSample(random_vec_texture, random_vec_sampler, tex_coord * noise_scale).xyz);
+    let tangent = normalize(random_vector - f_normal * dot(random_vector, f_normal));
+    let bitangent = cross(f_normal, tangent);
+    let tbn = mat3x3<f32>(tangent, bitangent, f_normal);
+
+    let radius = 0.40;
+    let ssao_bias = 0.01;
+    var occ = 0.0;
+
+    for (var i = 0; i < SAMPLE_COUNT; i++) {
+        let point = f_position + tbn * hemisphere.sample_points[i] * radius;
+
+        var offset = scene.projection_mat * vec4<f32>(point, 1.0);
+        offset = vec4<f32>(offset.xyz / offset.w, offset.w);
+        let offset_coord = offset.xy * vec2<f32>(0.5, -0.5) + 0.5;
+
+        let occluder_position = textureSampleLevel(g_position, layer_sampler, offset_coord, 0.0).xyz;
+
+        let range_check = smoothstep(0.0, 1.0, radius / abs(point.z - occluder_position.z));
+        occ += select(0.0, 1.0, occluder_position.z >= point.z + ssao_bias) * range_check;
+    }
+
+    // We want shadows only, so we clamp everything above 0.7:
+    return smoothstep(0.0, 0.7, 1.0 - occ / f32(SAMPLE_COUNT));
+}
@@ -0,0 +1,80 @@
+//! Renders the default scene without a window, e.g. on CI machines without a display.
+//! The final frame and the intermediate render targets are read back as images, which the
+//! golden image tests compare against their references and `cells --headless <dir>` writes
+//! out as PNGs.
+
+use specs::prelude::*;
+
+use crate::renderer::{
+    deferred_pass::{GBUFFER_ALBEDO, GBUFFER_NORMAL, GBUFFER_POSITION},
+    offscreen::{read_image, Image},
+    renderer::RendererEvent,
+    setup_headless_rendering,
+    shadow_passes::SHADOW_MAP,
+    ssao_pass::SSAO_OUTPUT,
+};
+
+/// Renders the default scene with the fallback adapter, so that the images don't depend on
+/// the GPU, and reads back the G-buffer, SSAO, first shadow cascade and final frame. The
+/// images are named after their golden images.
+pub fn render_snapshots(width: u32, height: u32) -> Result<Vec<(&'static str, Image)>, wgpu::RequestAdapterError> {
+    let mut world = World::new();
+    let mut renderer = setup_headless_rendering(&mut world, width, height, true)?;
+
+    crate::scene::setup_scene(&mut world);
+    crate::setup_world(&mut world, winit::dpi::PhysicalSize::new(width, height));
+
+    let mut dispatcher = crate::scene_dispatcher_builder().build();
+    dispatcher.setup(&mut world);
+    crate::spawn_default_lights(&mut world);
+
+    // The first dispatch sees the entities created during setup, the second one
+    // the component events they caused.
+    for _ in 0..2 {
+        dispatcher.dispatch(&world);
+        world.maintain();
+    }
+
+    for name in [GBUFFER_ALBEDO, GBUFFER_POSITION, GBUFFER_NORMAL, SSAO_OUTPUT, SHADOW_MAP] {
+        renderer.render_graph.retain(name);
+    }
+
+    // The second frame reuses the point shadows of the first one, since nothing changed:
+    for _ in 0..2 {
+        *world.write_resource::<RendererEvent>() = RendererEvent::Render;
+        renderer.run_now(&world);
+    }
+
+    let device = world.read_resource::<wgpu::Device>();
+    let queue = world.read_resource::<wgpu::Queue>();
+    let texture = |name| renderer.render_graph.texture(name).unwrap();
+
+    let frame = Image::from_texels(
+        width,
+        height,
+        renderer.output_format(),
+        &renderer.read_frame(&device, &queue).unwrap(),
+        (0.0, 1.0),
+    );
+
+    Ok(vec![
+        ("gbuffer_albedo", read_image(&device, &queue, texture(GBUFFER_ALBEDO), (0.0, 1.0))),
+        ("gbuffer_position", read_image(&device, &queue, texture(GBUFFER_POSITION), (-32.0, 32.0))),
+        ("gbuffer_normal", read_image(&device, &queue, texture(GBUFFER_NORMAL), (0.0, 1.0))),
+        ("ssao", read_image(&device, &queue, texture(SSAO_OUTPUT), (0.0, 1.0))),
+        ("shadow_depth", read_image(&device, &queue, texture(SHADOW_MAP), (0.0, 1.0))),
+        ("composition", frame),
+    ])
+}
+
+/// Renders the snapshots and writes them to `<dir>/<name>.png`.
+#[cfg(feature = "headless")]
+pub fn write_snapshots(dir: &std::path::Path, width: u32, height: u32) -> Result<(), wgpu::RequestAdapterError> {
+    for (name, image) in render_snapshots(width, height)? {
+        let path = dir.join(format!("{}.png", name));
+        image.write(&path);
+        log::info!("Wrote {:?}", path);
+    }
+
+    Ok(())
+}
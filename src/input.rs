@@ -86,7 +86,7 @@ impl<'a> System<'a> for InputSystem {
 }
 
 fn cubic_bezier(b0: f32, b1: f32, b2: f32, b3: f32, t: f32) -> f32 {
-    ((-1.0) * b0 + 3.0 * b1 - 3.0 * b2 + b3) * t * t * t
+    (-b0 + 3.0 * b1 - 3.0 * b2 + b3) * t * t * t
         + (3.0 * b0 - 6.0 * b1 + 3.0 * b2) * t * t
         + (-3.0 * b0 + 3.0 * b1) * t
         + b0
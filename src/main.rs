@@ -1,19 +1,18 @@
+#[cfg(any(test, feature = "headless"))]
+mod headless;
 mod input;
 mod renderer;
 mod scene;
 
-use crate::renderer::meshes::MeshResources;
 use crate::scene::playing_field::PlayingField;
-use crate::scene::solid_object::{SolidObject, SolidObjectSystem};
-use imgui::Key;
+use crate::scene::solid_object::SolidObjectSystem;
 use input::{InputMap, InputSystem};
-use renderer::{renderer::RendererEvent, setup_rendering};
+use renderer::{lights::{Falloff, LightsResources}, material::MaterialResources, renderer::RendererEvent, setup_rendering};
 use scene::{
     camera::{ActiveCamera, Camera, CameraSystem},
-    lights::{LightSystem, PointLight},
-    scene_graph::{SceneGraph, Transformation},
+    lights::{DirectionalLight, LightSystem, PointLight, SpotLight},
+    scene_graph::SceneGraph,
     setup_scene,
-    spawning::Spawner,
 };
 use specs::prelude::*;
 use std::time::{Duration, Instant};
@@ -21,7 +20,7 @@ use winit::application::ApplicationHandler;
 use winit::event::{KeyEvent, WindowEvent};
 use winit::event_loop::EventLoop;
 use winit::event_loop::{ActiveEventLoop, ControlFlow};
-use winit::keyboard::{KeyCode, NamedKey};
+use winit::keyboard::KeyCode;
 use winit::window::WindowId;
 use winit::{event, keyboard, window::Window};
 
@@ -57,97 +56,19 @@ impl<'a, 'b> ApplicationHandler for App<'a, 'b> {
             let renderer = setup_rendering(&mut world, window.clone());
             setup_scene(&mut world);
 
-            /* Register Components */
-
-            let active_camera = world
-                .create_entity()
-                .with(Camera::new(
-                    window_size.width as f32 / window_size.height as f32,
-                ))
-                .build();
+            setup_world(&mut world, window_size);
 
             /* Add Resources */
 
             world.insert(window);
 
-            world.insert(InputMap::new());
-            //world.insert(GUItWrapper::new(&mut gui));
-            world.insert(ActiveCamera(active_camera));
-
-            let mut dispatcher = DispatcherBuilder::new()
-                .with(CameraSystem, "Camera System", &[])
-                .with(Spawner::default(), "Test Spawner", &[])
-                .with(SceneGraph::default(), "Scene", &[])
-                .with(LightSystem::default(), "Light System", &[])
-                .with(SolidObjectSystem::new(), "Solid Objects System", &[])
-                .with(
-                    PlayingField::new(),
-                    "Playing Field System",
-                    &["Solid Objects System"],
-                )
-                .with(InputSystem, "InputSystem", &["Camera System"])
+            let mut dispatcher = scene_dispatcher_builder()
                 .with_thread_local(renderer)
                 .build();
 
             dispatcher.setup(&mut world);
 
-            let mut last_update = Instant::now();
-            let mut last_render = Instant::now();
-
-            world
-                .create_entity()
-                .with(PointLight {
-                    position: cgmath::Vector3::new(2.0, 15.0, 2.0),
-                    color: cgmath::Vector3::new(1.0, 1.0, 1.0),
-                    intensity: 0.2625,
-                    radius: 40.0,
-                    light_index: 0,
-                })
-                .build();
-
-            world
-                .create_entity()
-                .with(PointLight {
-                    position: cgmath::Vector3::new(8.0, 3.0, 8.0),
-                    color: cgmath::Vector3::new(1.0, 1.0, 1.0),
-                    intensity: 0.2625,
-                    radius: 20.0,
-                    light_index: 1,
-                })
-                .build();
-
-            world
-                .create_entity()
-                .with(PointLight {
-                    position: cgmath::Vector3::new(-8.0, 3.0, 8.0),
-                    color: cgmath::Vector3::new(1.0, 1.0, 1.0),
-                    intensity: 0.2625,
-                    radius: 20.0,
-                    light_index: 2,
-                })
-                .build();
-
-            world
-                .create_entity()
-                .with(PointLight {
-                    position: cgmath::Vector3::new(-8.0, 3.0, -8.0),
-                    color: cgmath::Vector3::new(1.0, 1.0, 1.0),
-                    intensity: 0.2625,
-                    radius: 20.0,
-                    light_index: 3,
-                })
-                .build();
-
-            world
-                .create_entity()
-                .with(PointLight {
-                    position: cgmath::Vector3::new(8.0, 3.0, -8.0),
-                    color: cgmath::Vector3::new(1.0, 1.0, 1.0),
-                    intensity: 0.2625,
-                    radius: 20.0,
-                    light_index: 4,
-                })
-                .build();
+            spawn_default_lights(&mut world);
 
             self.world = Some(world);
             self.dispatcher = Some(dispatcher);
@@ -157,7 +78,7 @@ impl<'a, 'b> ApplicationHandler for App<'a, 'b> {
     fn window_event(
         &mut self,
         event_loop: &ActiveEventLoop,
-        window_id: WindowId,
+        _window_id: WindowId,
         event: WindowEvent,
     ) {
         match event {
@@ -185,6 +106,42 @@ impl<'a, 'b> ApplicationHandler for App<'a, 'b> {
                 event_loop.exit();
                 log::info!("Closing Application.");
             }
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        physical_key: keyboard::PhysicalKey::Code(key @ (KeyCode::KeyF | KeyCode::KeyG)),
+                        state: event::ElementState::Pressed,
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => {
+                if let Some(world) = &self.world {
+                    let mut lights = world.write_resource::<LightsResources>();
+                    let settings = &mut lights.shadow_settings;
+                    match key {
+                        KeyCode::KeyF => settings.filter = settings.filter.next(),
+                        _ => settings.quality = settings.quality.next(),
+                    }
+                    log::info!("Shadow filter {:?}, quality {:?}", settings.filter, settings.quality);
+                }
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        physical_key: keyboard::PhysicalKey::Code(KeyCode::KeyM),
+                        state: event::ElementState::Pressed,
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => {
+                if let Some(world) = &self.world {
+                    let mut materials = world.write_resource::<MaterialResources>();
+                    materials.shading_model = materials.shading_model.next();
+                    log::info!("Shading model {:?}", materials.shading_model);
+                }
+            }
             WindowEvent::KeyboardInput {
                 event:
                     KeyEvent {
@@ -216,12 +173,9 @@ impl<'a, 'b> ApplicationHandler for App<'a, 'b> {
                 {
                     // this should be a queue!
                     let mut render_event = world.write_resource::<RendererEvent>();
-                    match *render_event {
-                        RendererEvent::None => {
-                            *render_event = RendererEvent::Render;
-                            self.last_render = Instant::now();
-                        }
-                        _ => {}
+                    if let RendererEvent::None = *render_event {
+                        *render_event = RendererEvent::Render;
+                        self.last_render = Instant::now();
                     }
                 }
 
@@ -245,9 +199,140 @@ impl<'a, 'b> ApplicationHandler for App<'a, 'b> {
     }
 }
 
+/// Creates the active camera and the input resources shared by all systems.
+fn setup_world(world: &mut World, window_size: winit::dpi::PhysicalSize<u32>) {
+    /* Register Components */
+
+    let active_camera = world
+        .create_entity()
+        .with(Camera::new(window_size))
+        .build();
+
+    world.insert(InputMap::new());
+    //world.insert(GUItWrapper::new(&mut gui));
+    world.insert(ActiveCamera(active_camera));
+}
+
+/// All systems updating the scene. The renderer is added on top by the caller, since the
+/// windowed app runs it as part of the dispatcher and headless rendering runs it by hand.
+fn scene_dispatcher_builder<'a, 'b>() -> DispatcherBuilder<'a, 'b> {
+    DispatcherBuilder::new()
+        .with(CameraSystem, "Camera System", &[])
+        .with(SceneGraph::default(), "Scene", &["Camera System"])
+        .with(LightSystem::default(), "Light System", &["Camera System"])
+        .with(SolidObjectSystem::new(), "Solid Objects System", &["Scene"])
+        .with(
+            PlayingField::new(),
+            "Playing Field System",
+            &["Solid Objects System"],
+        )
+        .with(InputSystem, "InputSystem", &["Camera System"])
+}
+
+fn spawn_default_lights(world: &mut World) {
+    world
+        .create_entity()
+        .with(DirectionalLight {
+            direction: cgmath::Vector3::new(-5.0, -15.0, 5.0),
+            color: cgmath::Vector3::new(1.0, 1.0, 1.0),
+            intensity: 0.5,
+            depth_bias: 0.0005,
+            slope_bias: 0.002,
+        })
+        .build();
+
+    world
+        .create_entity()
+        .with(PointLight {
+            position: cgmath::Vector3::new(2.0, 15.0, 2.0),
+            color: cgmath::Vector3::new(1.0, 1.0, 1.0),
+            intensity: 0.2625,
+            radius: 40.0,
+            falloff: Falloff::Constant,
+            cast_shadows: false,
+        })
+        .build();
+
+    world
+        .create_entity()
+        .with(PointLight {
+            position: cgmath::Vector3::new(8.0, 3.0, 8.0),
+            color: cgmath::Vector3::new(1.0, 1.0, 1.0),
+            intensity: 6.0,
+            radius: 20.0,
+            falloff: Falloff::InverseSquare,
+            cast_shadows: true,
+        })
+        .build();
+
+    world
+        .create_entity()
+        .with(PointLight {
+            position: cgmath::Vector3::new(-8.0, 3.0, 8.0),
+            color: cgmath::Vector3::new(1.0, 1.0, 1.0),
+            intensity: 6.0,
+            radius: 20.0,
+            falloff: Falloff::InverseSquare,
+            cast_shadows: true,
+        })
+        .build();
+
+    world
+        .create_entity()
+        .with(PointLight {
+            position: cgmath::Vector3::new(-8.0, 3.0, -8.0),
+            color: cgmath::Vector3::new(1.0, 1.0, 1.0),
+            intensity: 6.0,
+            radius: 20.0,
+            falloff: Falloff::InverseSquare,
+            cast_shadows: true,
+        })
+        .build();
+
+    world
+        .create_entity()
+        .with(PointLight {
+            position: cgmath::Vector3::new(8.0, 3.0, -8.0),
+            color: cgmath::Vector3::new(1.0, 1.0, 1.0),
+            intensity: 6.0,
+            radius: 20.0,
+            falloff: Falloff::InverseSquare,
+            cast_shadows: true,
+        })
+        .build();
+
+    world
+        .create_entity()
+        .with(SpotLight {
+            position: cgmath::Vector3::new(0.0, 10.0, 12.0),
+            direction: cgmath::Vector3::new(0.0, -1.0, -1.0),
+            color: cgmath::Vector3::new(1.0, 0.9, 0.7),
+            intensity: 0.4,
+            radius: 30.0,
+            falloff: Falloff::Linear,
+            inner_angle: cgmath::Deg(20.0),
+            outer_angle: cgmath::Deg(30.0),
+            cast_shadows: true,
+        })
+        .build();
+}
+
 fn main() {
     env_logger::init();
 
+    // `cells --headless <dir>` renders the default scene without a window and writes
+    // the pass outputs to `dir`.
+    #[cfg(feature = "headless")]
+    if let [_, flag, dir] = std::env::args().collect::<Vec<_>>().as_slice()
+        && flag == "--headless"
+    {
+        if let Err(error) = headless::write_snapshots(std::path::Path::new(dir), 1280, 720) {
+            log::error!("Could not render headless: {}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let event_loop = EventLoop::new().unwrap();
     event_loop.set_control_flow(ControlFlow::Poll);
 
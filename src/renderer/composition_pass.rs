@@ -1,40 +1,60 @@
 use wgpu::util::*;
 
-use super::{deferred_pass::DeferredPass, lights::LightsResources, utils::GpuVector3};
+use super::{lights::{LightsResources, CASCADE_BLEND, POINT_SHADOW_BIAS, POINT_SHADOW_LAYERS, SHADOW_CASCADES}, utils::GpuVector3};
+use crate::renderer::deferred_pass::{GBUFFER_ALBEDO, GBUFFER_MATERIAL, GBUFFER_NORMAL, GBUFFER_POSITION};
+use crate::renderer::frame::FrameContext;
+use crate::renderer::light_culling_pass::{LIGHT_TILES, LIGHT_TILE_SIZE, MAX_LIGHTS_PER_TILE};
+use crate::renderer::render_graph::{GraphResources, NodeBuilder, RenderInputs, RenderNode, OUTPUT};
 use crate::renderer::scene_base::SceneBaseResources;
-use crate::renderer::shadow_passes::ShadowPasses;
-use crate::renderer::ssao_pass::SSAOPass;
-use cgmath::InnerSpace;
-use std::ops::Not;
+use crate::renderer::shader_cache::ShaderCache;
+use crate::renderer::shaders::{CompiledShaders, ShaderFile, ShaderStage, ShaderWatcher};
+use crate::renderer::shadow_passes::{POINT_SHADOW_MAP, POINT_SHADOW_VIEWS, SHADOW_LIGHT, SHADOW_MAP};
+use crate::renderer::ssao_pass::SSAO_OUTPUT;
 
-#[repr(C, align(256))]
+#[repr(C)]
 #[derive(Clone, Copy, Debug)]
-struct HemisphereSamples {
-    points: [[f32; 3]; 64],
+struct GpuShading {
+    /// The `ShadingModel`.
+    model: u32,
+    padding: [u32; 3],
 }
 
-unsafe impl bytemuck::Pod for HemisphereSamples {}
-unsafe impl bytemuck::Zeroable for HemisphereSamples {}
+unsafe impl bytemuck::Pod for GpuShading {}
+unsafe impl bytemuck::Zeroable for GpuShading {}
 
 pub struct CompositionPass {
+    shaders: ShaderWatcher,
+    render_pipeline_layout: wgpu::PipelineLayout,
+    output_format: wgpu::TextureFormat,
     pub pipeline: wgpu::RenderPipeline,
     pub vertices: wgpu::Buffer,
     pub indices: wgpu::Buffer,
+    gbuffer_bind_group_layout: wgpu::BindGroupLayout,
+    shadow_light_bind_group_layout: wgpu::BindGroupLayout,
+    shadow_bind_group_layout: wgpu::BindGroupLayout,
+    ssao_bind_group_layout: wgpu::BindGroupLayout,
+    layer_sampler: wgpu::Sampler,
+    shadow_sampler: wgpu::Sampler,
+    /// A `GpuShading`, written every frame.
+    shading_buffer: wgpu::Buffer,
+    /// Created once the graph allocated the textures read here.
+    bind_groups: Option<CompositionBindGroups>,
 }
 
-pub fn lerp(a: f32, b: f32, t: f32) -> f32 {
-    a + t * (b - a)
+struct CompositionBindGroups {
+    gbuffer: wgpu::BindGroup,
+    shadow_light: wgpu::BindGroup,
+    shadow: wgpu::BindGroup,
+    ssao: wgpu::BindGroup,
 }
 
 impl CompositionPass {
     pub fn new(
         device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        deferred_pass: &DeferredPass,
-        shadow_passes: &ShadowPasses,
-        ssao_pass: &SSAOPass,
         light_resources: &LightsResources,
         scene_base_resources: &SceneBaseResources,
+        output_format: wgpu::TextureFormat,
+        shader_cache: &ShaderCache,
     ) -> CompositionPass {
         let vertices = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("CompPass Vertex Buffer"),
@@ -53,198 +73,193 @@ impl CompositionPass {
             usage: wgpu::BufferUsages::INDEX,
         });
 
-        let mut compiler = shaderc::Compiler::new().unwrap();
-
-        let vs_code = "
-            #version 450
-
-            layout(location=0) in vec3 a_position;
-            layout(location=0) out vec2 tex_coord;
-
-            void main() {
-                tex_coord = (vec2(0.0,1.0) - a_position.xy + vec2(1.0,0.0)) * vec2(0.5, 0.5);
-                gl_Position = vec4(a_position, 1.0);
-            }
-        "
-        .to_string();
-
-        let fs_code = "
-            #version 450
-            #extension GL_EXT_samplerless_texture_functions : require
-
-            layout(location=0) in vec2 tex_coord;
-            layout(location=0) out vec4 f_color;
-
-            struct GpuLight {
-                vec4 position; // 4 * 4 = 16
-                vec4 color; // 4 * 4 = 16
-                float intensity; // 4
-                float radius; // 4
-                float enabled; // 4
-            };
-
-            layout(set = 0, binding = 0) uniform SceneBase {
-                mat4 view_mat;
-                mat4 projection_mat;
-                vec2 window_size;
-            };
-
-            layout(set = 1, binding = 0) uniform Lights {
-                GpuLight u_point_lights[20];
-            };
-
-            layout(set=2, binding=0) uniform sampler layer_sampler;
-            layout(set=2, binding=1) uniform texture2D gAlbedo;
-            layout(set=2, binding=2) uniform texture2D gPosition;
-            layout(set=2, binding=3) uniform texture2D gNormal;
-
-            layout(set = 3, binding = 0) uniform ShadowUniforms {
-                mat4 light_view_mat;
-            };
-            layout(set=4, binding=0) uniform samplerShadow shadow_sampler;
-            layout(set=4, binding=1) uniform texture2D shadow;
-
-            layout(set = 5, binding = 0) uniform texture2D ssao_texture;
-
-            mat4 inverseNoExt(mat4 m) {
-              float
-                  a00 = m[0][0], a01 = m[0][1], a02 = m[0][2], a03 = m[0][3],
-                  a10 = m[1][0], a11 = m[1][1], a12 = m[1][2], a13 = m[1][3],
-                  a20 = m[2][0], a21 = m[2][1], a22 = m[2][2], a23 = m[2][3],
-                  a30 = m[3][0], a31 = m[3][1], a32 = m[3][2], a33 = m[3][3],
-
-                  b00 = a00 * a11 - a01 * a10,
-                  b01 = a00 * a12 - a02 * a10,
-                  b02 = a00 * a13 - a03 * a10,
-                  b03 = a01 * a12 - a02 * a11,
-                  b04 = a01 * a13 - a03 * a11,
-                  b05 = a02 * a13 - a03 * a12,
-                  b06 = a20 * a31 - a21 * a30,
-                  b07 = a20 * a32 - a22 * a30,
-                  b08 = a20 * a33 - a23 * a30,
-                  b09 = a21 * a32 - a22 * a31,
-                  b10 = a21 * a33 - a23 * a31,
-                  b11 = a22 * a33 - a23 * a32,
-
-                  det = b00 * b11 - b01 * b10 + b02 * b09 + b03 * b08 - b04 * b07 + b05 * b06;
-
-              return mat4(
-                  a11 * b11 - a12 * b10 + a13 * b09,
-                  a02 * b10 - a01 * b11 - a03 * b09,
-                  a31 * b05 - a32 * b04 + a33 * b03,
-                  a22 * b04 - a21 * b05 - a23 * b03,
-                  a12 * b08 - a10 * b11 - a13 * b07,
-                  a00 * b11 - a02 * b08 + a03 * b07,
-                  a32 * b02 - a30 * b05 - a33 * b01,
-                  a20 * b05 - a22 * b02 + a23 * b01,
-                  a10 * b10 - a11 * b08 + a13 * b06,
-                  a01 * b08 - a00 * b10 - a03 * b06,
-                  a30 * b04 - a31 * b02 + a33 * b00,
-                  a21 * b02 - a20 * b04 - a23 * b00,
-                  a11 * b07 - a10 * b09 - a12 * b06,
-                  a00 * b09 - a01 * b07 + a02 * b06,
-                  a31 * b01 - a30 * b03 - a32 * b00,
-                  a20 * b03 - a21 * b01 + a22 * b00) / det;
-            }
-
-            float fetch_shadow(vec4 homogeneous_coords) {
-                if (homogeneous_coords.w <= 0.0) {
-                    return 1.0;
-                }
-
-                const vec2 flip_correction = vec2(0.5, -0.5);
-
-                vec3 light_local = vec3(
-                    homogeneous_coords.xy * flip_correction/homogeneous_coords.w + 0.5,
-                    homogeneous_coords.z / homogeneous_coords.w
-                );
-
-                return texture(sampler2DShadow(shadow, shadow_sampler), light_local);
-            }
-
-            void main() {
-                vec4 f_albedo = texture(sampler2D(gAlbedo, layer_sampler), tex_coord);
-                vec3 f_position = texture(sampler2D(gPosition, layer_sampler), tex_coord).xyz;
-                vec3 f_normal = normalize(texture(sampler2D(gNormal, layer_sampler), tex_coord).xyz);
-
-                //*** SHADOW MAPPING ***///
-
-                float shadow_f = 1.0;
-
-                // Calculate a bias to avoid self-shadowing:
-                vec4 light_position = view_mat * vec4(5.0, 15.0, -5.0, 1.0);
-                vec3 shadow_light_dir = normalize(f_position - light_position.xyz);
-
-                vec3 bias = (1.0 - dot(f_normal, shadow_light_dir)) * shadow_light_dir * 0.0001; //Todo: Provide light position via uniform
-                vec3 world_position = (inverseNoExt(view_mat) * vec4(f_position, 1.0)).xyz;
-                shadow_f = fetch_shadow(light_view_mat * vec4(world_position, 1.0)) + 0.45;
-
-
-                // Blur the ssao texture:
-
-                vec2 texelSize = 1.0 / vec2(textureSize(ssao_texture, 0));
-                vec2 ssao_tex_coord = vec2(0.0, 1.0) - (tex_coord) * vec2(-1.0, 1.0);
-                float result = 0.0;
-
-                for(int i=-2; i < 2; i++) {
-                    for(int j=-2; j < 2; j++) {
-                        vec2 offset = vec2(float(i), float(j)) * texelSize;
-                        result += texture(sampler2D(ssao_texture, layer_sampler), ssao_tex_coord + offset).r;
-                    }
-                }
-
-                float f_occlusion = result / (4.0 * 4.0);
-
-                // Lambert Lighting
-
-                vec4 ambient_light = vec4(0.6, 0.6, 0.6, 1.0);
-
-                vec4 color = f_albedo * ambient_light;
+        let gbuffer_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Composition G-Buffer"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::NonFiltering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+        });
 
-                for(int i=0; i < 20; ++i) {
-                    GpuLight light = u_point_lights[i];
-                    if (light.enabled>0) {
-                        vec4 view_space_light_pos = view_mat * light.position;
-                        vec3 light_dir = normalize(view_space_light_pos.xyz - f_position);
-                        color += vec4(max(0.0, dot(f_normal, light_dir)) * light.color.xyz * light.intensity, 0.0);
-                    }
-                }
+        let shadow_light_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Composition Shadow Light"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
 
-                f_color = color * f_occlusion;
-                //f_color = color * shadow_f * f_occlusion;
-                //f_color = color * shadow_f;
-                //f_color = vec4(1.0, 1.0, 1.0, 1.0) * shadow_f * f_occlusion;
-                //f_color = vec4(0.0, 1.0, 0.0, 1.0);
-            }
-        ".to_string();
+        let shadow_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Composition Shadow Map"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                // The shadow map once more, since the blocker search of PCSS loads depths directly:
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+        });
 
-        let vs_spirv = compiler
-            .compile_into_spirv(
-                &vs_code,
-                shaderc::ShaderKind::Vertex,
-                "composition.vert",
-                "main",
-                None,
-            )
-            .unwrap();
+        let ssao_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Composition SSAO"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                },
+                count: None,
+            }],
+        });
 
-        let fs_spirv = compiler
-            .compile_into_spirv(
-                &fs_code,
-                shaderc::ShaderKind::Fragment,
-                "composition.frag",
-                "main",
-                None,
-            )
-            .unwrap();
+        let layer_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: None,
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: 0.0,
+            lod_max_clamp: 0.0,
+            compare: None,
+            anisotropy_clamp: 1,
+            border_color: None,
+        });
 
-        let vertex_shader_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-            label: Some("Composition Vertex Shader"),
-            source: wgpu::ShaderSource::SpirV(std::borrow::Cow::Borrowed(vs_spirv.as_binary())),
+        let shadow_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("shadow"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            compare: Some(wgpu::CompareFunction::Less),
+            ..Default::default()
         });
-        let fragment_shader_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-            label: Some("Composition Fragment Shader"),
-            source: wgpu::ShaderSource::SpirV(std::borrow::Cow::Borrowed(fs_spirv.as_binary())),
+
+        let shading_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Composition Shading"),
+            size: std::mem::size_of::<GpuShading>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
         });
 
         let render_pipeline_layout =
@@ -252,20 +267,57 @@ impl CompositionPass {
                 bind_group_layouts: &[
                     &scene_base_resources.bind_group_layout,
                     &light_resources.lights_bind_group_layout,
-                    &deferred_pass.gbuffer_bind_group_layout,
-                    &shadow_passes.shadow_light_bind_group_layout,
-                    &shadow_passes.shadow_result_bind_group_layout,
-                    &ssao_pass.bind_group_layout,
+                    &gbuffer_bind_group_layout,
+                    &shadow_light_bind_group_layout,
+                    &shadow_bind_group_layout,
+                    &ssao_bind_group_layout,
                 ],
                 push_constant_ranges: &[],
                 label: None,
             });
 
-        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        let mut shaders = ShaderWatcher::new("Composition Pass", vec![
+            ShaderFile::new("composition.vert", ShaderStage::Vertex),
+            ShaderFile::new("composition.frag", ShaderStage::Fragment)
+                .define_u32("SHADOW_CASCADES", SHADOW_CASCADES as u32)
+                .define_f32("CASCADE_BLEND", CASCADE_BLEND)
+                .define_u32("POINT_SHADOW_LAYERS", POINT_SHADOW_LAYERS as u32)
+                .define_f32("POINT_SHADOW_BIAS", POINT_SHADOW_BIAS)
+                .define_u32("LIGHT_TILE_SIZE", LIGHT_TILE_SIZE)
+                .define_u32("MAX_LIGHTS_PER_TILE", MAX_LIGHTS_PER_TILE),
+        ], shader_cache);
+
+        let pipeline = shaders.build(device, |compiled| Self::create_pipeline(device, &render_pipeline_layout, output_format, compiled));
+
+        CompositionPass {
+            shaders,
+            render_pipeline_layout,
+            output_format,
+            vertices,
+            indices,
+            pipeline,
+            gbuffer_bind_group_layout,
+            shadow_light_bind_group_layout,
+            shadow_bind_group_layout,
+            ssao_bind_group_layout,
+            layer_sampler,
+            shadow_sampler,
+            shading_buffer,
+            bind_groups: None,
+        }
+    }
+
+    fn create_pipeline(
+        device: &wgpu::Device,
+        render_pipeline_layout: &wgpu::PipelineLayout,
+        output_format: wgpu::TextureFormat,
+        compiled: &CompiledShaders,
+    ) -> wgpu::RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
             label: None,
-            layout: Some(&render_pipeline_layout),
+            layout: Some(render_pipeline_layout),
             vertex: wgpu::VertexState {
-                module: &vertex_shader_module,
+                module: &compiled.modules[0],
                 entry_point: Some("main"),
                 compilation_options: Default::default(),
                 buffers: &[wgpu::VertexBufferLayout {
@@ -294,11 +346,11 @@ impl CompositionPass {
                 alpha_to_coverage_enabled: false,
             },
             fragment: Some(wgpu::FragmentState {
-                module: &fragment_shader_module,
+                module: &compiled.modules[1],
                 entry_point: Some("main"),
                 compilation_options: Default::default(),
                 targets: &[Some(wgpu::ColorTargetState {
-                    format: wgpu::TextureFormat::Bgra8UnormSrgb,
+                    format: output_format,
                     blend: Some(wgpu::BlendState {
                         color: wgpu::BlendComponent::REPLACE,
                         alpha: wgpu::BlendComponent::REPLACE,
@@ -307,41 +359,139 @@ impl CompositionPass {
                 })],
             }),
             multiview: None,
-            cache: None,
+            cache: compiled.cache,
+        })
+    }
+}
+
+impl RenderNode for CompositionPass {
+    fn name(&self) -> &str {
+        "Composition Pass"
+    }
+
+    fn declare(&self, builder: &mut NodeBuilder) {
+        builder.read_texture(GBUFFER_ALBEDO);
+        builder.read_texture(GBUFFER_POSITION);
+        builder.read_texture(GBUFFER_NORMAL);
+        builder.read_texture(GBUFFER_MATERIAL);
+        builder.read_buffer(LIGHT_TILES);
+        builder.read_texture(SHADOW_MAP);
+        builder.read_buffer(SHADOW_LIGHT);
+        builder.read_texture(POINT_SHADOW_MAP);
+        builder.read_buffer(POINT_SHADOW_VIEWS);
+        builder.read_texture(SSAO_OUTPUT);
+        builder.write_texture(OUTPUT);
+    }
+
+    fn prepare(&mut self, device: &wgpu::Device, resources: &GraphResources) {
+        let gbuffer = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Composition G-Buffer"),
+            layout: &self.gbuffer_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Sampler(&self.layer_sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(resources.texture_view(GBUFFER_ALBEDO)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(resources.texture_view(GBUFFER_POSITION)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(resources.texture_view(GBUFFER_NORMAL)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: resources.buffer(LIGHT_TILES).as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(resources.texture_view(GBUFFER_MATERIAL)),
+                },
+            ],
         });
 
-        CompositionPass {
-            vertices,
-            indices,
-            pipeline,
-        }
+        let shadow_light = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Composition Shadow Light"),
+            layout: &self.shadow_light_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: resources.buffer(SHADOW_LIGHT).as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: resources.buffer(POINT_SHADOW_VIEWS).as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: self.shading_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        let shadow = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Composition Shadow Map"),
+            layout: &self.shadow_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Sampler(&self.shadow_sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(resources.texture_view(SHADOW_MAP)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(resources.texture_view(POINT_SHADOW_MAP)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(resources.texture_view(SHADOW_MAP)),
+                },
+            ],
+        });
+
+        let ssao = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Composition SSAO"),
+            layout: &self.ssao_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(resources.texture_view(SSAO_OUTPUT)),
+            }],
+        });
+
+        self.bind_groups = Some(CompositionBindGroups {
+            gbuffer,
+            shadow_light,
+            shadow,
+            ssao,
+        });
     }
 
-    pub fn render(
-        &self,
-        device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        surface: &mut wgpu::Surface,
-        scene_base: &SceneBaseResources,
-        light_resources: &LightsResources,
-        deferred_pass: &DeferredPass,
-        shadow_passes: &ShadowPasses,
-        ssao_pass: &SSAOPass,
-    ) {
-        let screen_frame = surface
-            .get_current_texture()
-            .expect("Could not acquire texture for rendering");
+    fn reload_shaders(&mut self, device: &wgpu::Device) {
+        let (render_pipeline_layout, output_format) = (&self.render_pipeline_layout, self.output_format);
+        self.shaders.reload(device, &mut self.pipeline, |compiled| Self::create_pipeline(device, render_pipeline_layout, output_format, compiled));
+    }
 
-        let mut encoder =
-            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    fn run(&mut self, frame: &mut FrameContext, resources: &GraphResources, inputs: &RenderInputs) {
+        let bind_groups = self.bind_groups.as_ref().unwrap();
+
+        frame.queue.write_buffer(&self.shading_buffer, 0, bytemuck::cast_slice(&[GpuShading {
+            model: inputs.materials.shading_model as u32,
+            padding: [0; 3],
+        }]));
 
         {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            let mut render_pass = frame.begin_render_pass(wgpu::RenderPassDescriptor {
                 label: Some("Composition Pass"),
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &screen_frame
-                        .texture
-                        .create_view(&wgpu::TextureViewDescriptor::default()),
+                    view: resources.texture_view(OUTPUT),
                     resolve_target: None,
                     ops: wgpu::Operations {
                         load: wgpu::LoadOp::Clear(wgpu::Color {
@@ -360,18 +510,15 @@ impl CompositionPass {
             });
 
             render_pass.set_pipeline(&self.pipeline);
-            render_pass.set_bind_group(0, &scene_base.bind_group, &[]);
-            render_pass.set_bind_group(1, &light_resources.lights_bind_group, &[]);
-            render_pass.set_bind_group(2, &deferred_pass.gbuffer_bind_group, &[]);
-            render_pass.set_bind_group(3, &shadow_passes.shadow_light_bind_group, &[]);
-            render_pass.set_bind_group(4, &shadow_passes.shadow_result_bind_group, &[]);
-            render_pass.set_bind_group(5, &ssao_pass.bind_group, &[]);
+            render_pass.set_bind_group(0, &inputs.scene_base.bind_group, &[]);
+            render_pass.set_bind_group(1, &inputs.lights.lights_bind_group, &[]);
+            render_pass.set_bind_group(2, &bind_groups.gbuffer, &[]);
+            render_pass.set_bind_group(3, &bind_groups.shadow_light, &[]);
+            render_pass.set_bind_group(4, &bind_groups.shadow, &[]);
+            render_pass.set_bind_group(5, &bind_groups.ssao, &[]);
             render_pass.set_vertex_buffer(0, self.vertices.slice(..));
             render_pass.set_index_buffer(self.indices.slice(..), wgpu::IndexFormat::Uint16);
             render_pass.draw_indexed(0..6, 0, 0..1)
         }
-
-        queue.submit(std::iter::once(encoder.finish()));
-        screen_frame.present();
     }
 }
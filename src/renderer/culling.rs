@@ -0,0 +1,496 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use wgpu::util::*;
+
+use super::draw_order::{LAYER_TRANSPARENT, MAX_LAYER};
+use super::frame::FrameContext;
+use super::meshes::{GpuInstance, MeshResources};
+use super::shader_cache::ShaderCache;
+use super::shaders::{CompiledShaders, ShaderFile, ShaderStage, ShaderWatcher};
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct GpuCullUniforms {
+    view_projection: cgmath::Matrix4<f32>,
+    bounds_min: cgmath::Vector4<f32>,
+    bounds_max: cgmath::Vector4<f32>,
+    count: u32,
+    use_near_plane: u32,
+    sort_size: u32,
+    padding: u32,
+}
+
+unsafe impl bytemuck::Pod for GpuCullUniforms {}
+unsafe impl bytemuck::Zeroable for GpuCullUniforms {}
+
+/// One dispatch of the bitonic sort: compares the items `j` apart in the stage sorting
+/// sequences of `k` items. `j == 0` runs `sort_local.comp`, see `sort_steps`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct GpuSortStep {
+    k: u32,
+    j: u32,
+    padding: [u32; 2],
+}
+
+unsafe impl bytemuck::Pod for GpuSortStep {}
+unsafe impl bytemuck::Zeroable for GpuSortStep {}
+
+/// Items sorted in workgroup memory by one workgroup of `sort_local.comp`.
+const LOCAL_SORT_SIZE: u32 = 512;
+
+/// Invocations of a workgroup of `sort.comp`, each comparing one pair of items.
+const SORT_WORKGROUP_SIZE: u32 = 256;
+
+/// The dispatches sorting `sort_size` items. `sort_local.comp` runs all stages up to
+/// `LOCAL_SORT_SIZE` at once, and for every later stage the steps whose distance is too
+/// large for a workgroup, so only those need a dispatch of their own.
+fn sort_steps(sort_size: u32) -> Vec<GpuSortStep> {
+    let step = |k, j| GpuSortStep { k, j, padding: [0; 2] };
+
+    let mut steps = vec![step(LOCAL_SORT_SIZE, 0)];
+    let mut k = LOCAL_SORT_SIZE * 2;
+
+    while k <= sort_size {
+        let mut j = k / 2;
+        while j >= LOCAL_SORT_SIZE {
+            steps.push(step(k, j));
+            j /= 2;
+        }
+        steps.push(step(k, 0));
+        k *= 2;
+    }
+
+    steps
+}
+
+/// The culling results for one mesh type: the object indices of all visible instances
+/// and the arguments for a single indirect draw of them.
+pub struct CulledMeshType {
+    uniform_buffer: wgpu::Buffer,
+    /// A power of two covering the capacity of the mesh type.
+    sort_size: u32,
+    sort_steps: Vec<GpuSortStep>,
+    pub visible_instances: wgpu::Buffer,
+    pub draw_arguments: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+struct CullingPipelines {
+    cull: wgpu::ComputePipeline,
+    sort: wgpu::ComputePipeline,
+    sort_local: wgpu::ComputePipeline,
+    compact: wgpu::ComputePipeline,
+}
+
+/// The object indices of the instances a view found visible, per mesh type and in draw
+/// order. `generation` counts the results read back so far.
+#[derive(Default)]
+pub struct VisibilityResults {
+    pub mesh_types: Vec<Vec<u32>>,
+    pub generation: u64,
+}
+
+/// The culling results of the camera, for systems that need to know which objects are on
+/// screen. Filled by the culling of the deferred pass, see [`InstanceCulling::with_readback`].
+#[derive(Clone, Default)]
+pub struct VisibleInstances(pub Arc<Mutex<VisibilityResults>>);
+
+/// Copies the culling results to the CPU without stalling it, so they arrive a frame or
+/// more after the culling. Frames are not read back while the previous results are still
+/// being mapped.
+struct VisibilityReadback {
+    visible_instances: VisibleInstances,
+    /// One per mesh type: the draw arguments, followed by the visible instances.
+    buffers: Vec<wgpu::Buffer>,
+    /// How many of the `pending` buffers are mapped.
+    mapped: Arc<AtomicUsize>,
+    pending: usize,
+}
+
+const DRAW_ARGUMENTS_SIZE: usize = std::mem::size_of::<wgpu::util::DrawIndexedIndirectArgs>();
+
+impl VisibilityReadback {
+    /// Collects finished results. Returns whether this frame can be read back.
+    fn collect(&mut self, device: &wgpu::Device) -> bool {
+        if self.pending == 0 {
+            return true;
+        }
+
+        let _ = device.poll(wgpu::PollType::Poll);
+
+        if self.mapped.load(Ordering::Acquire) < self.pending {
+            return false;
+        }
+
+        let mesh_types = self.buffers[..self.pending]
+            .iter()
+            .map(|buffer| {
+                let visible = {
+                    let data = buffer.slice(..).get_mapped_range();
+                    let arguments: &wgpu::util::DrawIndexedIndirectArgs = bytemuck::from_bytes(&data[..DRAW_ARGUMENTS_SIZE]);
+                    let visible: &[u32] = bytemuck::cast_slice(&data[DRAW_ARGUMENTS_SIZE..]);
+                    visible[..arguments.instance_count as usize].to_vec()
+                };
+                buffer.unmap();
+                visible
+            })
+            .collect();
+
+        let mut results = self.visible_instances.0.lock().unwrap();
+        results.mesh_types = mesh_types;
+        results.generation += 1;
+
+        self.mapped.store(0, Ordering::Release);
+        self.pending = 0;
+
+        true
+    }
+
+    fn record(&mut self, frame: &mut FrameContext, culled_mesh_types: &[CulledMeshType]) {
+        for (index, culled_mesh_type) in culled_mesh_types.iter().enumerate() {
+            let visible_instances_size = culled_mesh_type.visible_instances.size();
+
+            if index == self.buffers.len() {
+                self.buffers.push(frame.device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Visibility Readback Buffer"),
+                    size: DRAW_ARGUMENTS_SIZE as u64 + visible_instances_size,
+                    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                    mapped_at_creation: false,
+                }));
+            }
+
+            let buffer = &self.buffers[index];
+            frame.encoder.copy_buffer_to_buffer(&culled_mesh_type.draw_arguments, 0, buffer, 0, DRAW_ARGUMENTS_SIZE as u64);
+            frame.encoder.copy_buffer_to_buffer(&culled_mesh_type.visible_instances, 0, buffer, DRAW_ARGUMENTS_SIZE as u64, visible_instances_size);
+            frame.read_back(buffer, self.mapped.clone());
+        }
+
+        self.pending = culled_mesh_types.len();
+    }
+}
+
+/// Culls the instances of every mesh type against a view projection matrix on the GPU.
+/// Draws are then issued with `draw_indexed_indirect`, one per mesh type, so the CPU
+/// neither tests single instances nor batches them per frame. The culling pass writes
+/// the `draw_order` key of every visible instance, a bitonic sort orders them, and a
+/// last pass copies the object indices of the sorted instances into `visible_instances`.
+pub struct InstanceCulling {
+    label: String,
+    use_near_plane: bool,
+    shaders: ShaderWatcher,
+    pipeline_layout: wgpu::PipelineLayout,
+    pipelines: CullingPipelines,
+    bind_group_layout: wgpu::BindGroupLayout,
+    /// Distance between the entries of the sort step buffers, for dynamic offsets.
+    sort_step_stride: u32,
+    readback: Option<VisibilityReadback>,
+    pub mesh_types: Vec<CulledMeshType>,
+}
+
+impl InstanceCulling {
+    /// Shadow casters must not be culled by the near plane, since they still cast shadows
+    /// when they are behind the light.
+    pub fn new(device: &wgpu::Device, label: &str, use_near_plane: bool, shader_cache: &ShaderCache) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Culling"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(std::mem::size_of::<GpuInstance>() as u64),
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: wgpu::BufferSize::new(std::mem::size_of::<GpuSortStep>() as u64),
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let mut shaders = ShaderWatcher::new(label, vec![
+            ShaderFile::new("cull.comp", ShaderStage::Compute)
+                .define_u32("MAX_LAYER", MAX_LAYER)
+                .define_u32("LAYER_TRANSPARENT", LAYER_TRANSPARENT),
+            ShaderFile::new("sort.comp", ShaderStage::Compute)
+                .define_u32("SORT_WORKGROUP_SIZE", SORT_WORKGROUP_SIZE),
+            ShaderFile::new("sort_local.comp", ShaderStage::Compute)
+                .define_u32("LOCAL_SORT_SIZE", LOCAL_SORT_SIZE),
+            ShaderFile::new("compact.comp", ShaderStage::Compute),
+        ], shader_cache);
+
+        let pipelines = shaders.build(device, |compiled| Self::create_pipelines(device, label, &pipeline_layout, compiled));
+
+        InstanceCulling {
+            label: label.to_string(),
+            use_near_plane,
+            shaders,
+            pipeline_layout,
+            pipelines,
+            bind_group_layout,
+            sort_step_stride: device.limits().min_uniform_buffer_offset_alignment,
+            readback: None,
+            mesh_types: Vec::new(),
+        }
+    }
+
+    /// Also reads the culling results back into `visible_instances`.
+    pub fn with_readback(mut self, visible_instances: VisibleInstances) -> Self {
+        self.readback = Some(VisibilityReadback {
+            visible_instances,
+            buffers: Vec::new(),
+            mapped: Arc::new(AtomicUsize::new(0)),
+            pending: 0,
+        });
+        self
+    }
+
+    fn create_pipelines(
+        device: &wgpu::Device,
+        label: &str,
+        pipeline_layout: &wgpu::PipelineLayout,
+        compiled: &CompiledShaders,
+    ) -> CullingPipelines {
+        let create_pipeline = |module| device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some(label),
+            layout: Some(pipeline_layout),
+            module,
+            entry_point: Some("main"),
+            compilation_options: Default::default(),
+            cache: compiled.cache,
+        });
+
+        CullingPipelines {
+            cull: create_pipeline(&compiled.modules[0]),
+            sort: create_pipeline(&compiled.modules[1]),
+            sort_local: create_pipeline(&compiled.modules[2]),
+            compact: create_pipeline(&compiled.modules[3]),
+        }
+    }
+
+    pub fn reload_shaders(&mut self, device: &wgpu::Device) {
+        let (label, pipeline_layout) = (&self.label, &self.pipeline_layout);
+        self.shaders.reload(device, &mut self.pipelines, |compiled| Self::create_pipelines(device, label, pipeline_layout, compiled));
+    }
+
+    fn create_culled_mesh_type(&self, device: &wgpu::Device, mesh_resources: &MeshResources, index: usize) -> CulledMeshType {
+        let mesh_type = &mesh_resources.mesh_types[index];
+
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(&self.label),
+            size: std::mem::size_of::<GpuCullUniforms>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let sort_size = (mesh_type.capacity() as u32).next_power_of_two().max(LOCAL_SORT_SIZE);
+
+        let sort_items = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(&self.label),
+            size: sort_size as u64 * std::mem::size_of::<[u32; 2]>() as u64,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        let visible_instances = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(&self.label),
+            size: (mesh_type.capacity().max(1) * std::mem::size_of::<u32>()) as u64,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        let draw_arguments = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&self.label),
+            contents: wgpu::util::DrawIndexedIndirectArgs {
+                index_count: mesh_type.gpu_geometry.index_count,
+                instance_count: 0,
+                first_index: 0,
+                base_vertex: 0,
+                first_instance: 0,
+            }
+            .as_bytes(),
+            usage: wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        // Every step at its own dynamic offset, so the whole sort is recorded into one pass:
+        let sort_steps = sort_steps(sort_size);
+        let mut sort_step_contents = vec![0; sort_steps.len() * self.sort_step_stride as usize];
+        for (step, entry) in sort_steps.iter().zip(sort_step_contents.chunks_mut(self.sort_step_stride as usize)) {
+            entry[..std::mem::size_of::<GpuSortStep>()].copy_from_slice(bytemuck::bytes_of(step));
+        }
+
+        let sort_step_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&self.label),
+            contents: &sort_step_contents,
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&self.label),
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: mesh_type.instance_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: sort_items.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: visible_instances.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: draw_arguments.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: &sort_step_buffer,
+                        offset: 0,
+                        size: wgpu::BufferSize::new(std::mem::size_of::<GpuSortStep>() as u64),
+                    }),
+                },
+            ],
+        });
+
+        CulledMeshType {
+            uniform_buffer,
+            sort_size,
+            sort_steps,
+            visible_instances,
+            draw_arguments,
+            bind_group,
+        }
+    }
+
+    /// Records the culling of all mesh types. Afterwards `mesh_types[i]` holds the draw
+    /// of `mesh_resources.mesh_types[i]`.
+    pub fn cull(
+        &mut self,
+        frame: &mut FrameContext,
+        mesh_resources: &MeshResources,
+        view_projection: cgmath::Matrix4<f32>,
+    ) {
+        while self.mesh_types.len() < mesh_resources.mesh_types.len() {
+            let culled_mesh_type = self.create_culled_mesh_type(frame.device, mesh_resources, self.mesh_types.len());
+            self.mesh_types.push(culled_mesh_type);
+        }
+
+        let culled_mesh_types = &self.mesh_types[..mesh_resources.mesh_types.len()];
+        let read_back = self.readback.as_mut().is_some_and(|readback| readback.collect(frame.device));
+
+        for (mesh_type, culled_mesh_type) in mesh_resources.mesh_types.iter().zip(culled_mesh_types) {
+            frame.queue.write_buffer(
+                &culled_mesh_type.uniform_buffer,
+                0,
+                bytemuck::cast_slice(&[GpuCullUniforms {
+                    view_projection,
+                    bounds_min: mesh_type.bounds.min.to_homogeneous(),
+                    bounds_max: mesh_type.bounds.max.to_homogeneous(),
+                    count: mesh_type.capacity() as u32,
+                    use_near_plane: self.use_near_plane as u32,
+                    sort_size: culled_mesh_type.sort_size,
+                    padding: 0,
+                }]),
+            );
+
+            // The culling pass counts the visible instances up from zero:
+            let instance_count_offset = std::mem::offset_of!(wgpu::util::DrawIndexedIndirectArgs, instance_count);
+            frame.queue.write_buffer(&culled_mesh_type.draw_arguments, instance_count_offset as u64, bytemuck::bytes_of(&0u32));
+        }
+
+        {
+            let mut compute_pass = frame.begin_compute_pass(&self.label);
+
+            for culled_mesh_type in culled_mesh_types {
+                compute_pass.set_pipeline(&self.pipelines.cull);
+                compute_pass.set_bind_group(0, &culled_mesh_type.bind_group, &[0]);
+                compute_pass.dispatch_workgroups(culled_mesh_type.sort_size / 64, 1, 1);
+
+                for (index, step) in culled_mesh_type.sort_steps.iter().enumerate() {
+                    compute_pass.set_bind_group(0, &culled_mesh_type.bind_group, &[index as u32 * self.sort_step_stride]);
+
+                    if step.j == 0 {
+                        compute_pass.set_pipeline(&self.pipelines.sort_local);
+                        compute_pass.dispatch_workgroups(culled_mesh_type.sort_size / LOCAL_SORT_SIZE, 1, 1);
+                    } else {
+                        compute_pass.set_pipeline(&self.pipelines.sort);
+                        compute_pass.dispatch_workgroups(culled_mesh_type.sort_size / 2 / SORT_WORKGROUP_SIZE, 1, 1);
+                    }
+                }
+
+                compute_pass.set_pipeline(&self.pipelines.compact);
+                compute_pass.set_bind_group(0, &culled_mesh_type.bind_group, &[0]);
+                compute_pass.dispatch_workgroups(culled_mesh_type.sort_size / 64, 1, 1);
+            }
+        }
+
+        if read_back && let Some(readback) = self.readback.as_mut() {
+            readback.record(frame, culled_mesh_types);
+        }
+    }
+}
@@ -0,0 +1,45 @@
+/*!
+    Sort keys for the order in which the visible instances of a mesh type are drawn.
+    Every view culls the active instances and sorts the visible ones by their key on the
+    GPU, see `InstanceCulling`, so opaque geometry is drawn front to back for early-z
+    rejection and transparent geometry back to front.
+*/
+
+/*
+    32bit, built in `cull.comp`:
+    layer (4bit) | depth (28bit) |
+
+    Keys are drawn in ascending order: layer 0 before layer 1 and so on, and within a
+    layer the smaller depth first. The depth is the clip space depth of the bounding box
+    center without the sign and the lowest 3 mantissa bits; positive floats order like
+    their bits. Transparent layers store it inverted, so far instances are drawn first.
+    Instances with equal keys are drawn in the order of their object index.
+*/
+
+pub const LAYER_OPAQUE: u32 = 0;
+pub const LAYER_TRANSPARENT: u32 = 1;
+
+/// The largest layer that fits into the sort key. Instances of larger layers are not drawn.
+pub const MAX_LAYER: u32 = 15;
+
+/// A layer does not fit into its part of the sort key. Sorting such an instance would
+/// mix up the draws of other objects, so it is not drawn instead.
+#[derive(Debug, Clone)]
+pub struct LayerOverflow {
+    pub layer: u32,
+}
+
+impl std::fmt::Display for LayerOverflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "layer {} does not fit into the draw order key (max {})", self.layer, MAX_LAYER)
+    }
+}
+
+impl std::error::Error for LayerOverflow {}
+
+pub fn check_layer(layer: u32) -> Result<(), LayerOverflow> {
+    if layer > MAX_LAYER {
+        return Err(LayerOverflow { layer });
+    }
+    Ok(())
+}
@@ -0,0 +1,233 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Everything the passes need to record one frame. All passes record into the same
+/// encoder, which is submitted once in [`FrameContext::submit`].
+pub struct FrameContext<'a> {
+    pub device: &'a wgpu::Device,
+    pub queue: &'a wgpu::Queue,
+    pub encoder: wgpu::CommandEncoder,
+    timer: Option<&'a mut GpuTimer>,
+    readbacks: Vec<(wgpu::Buffer, Arc<AtomicUsize>)>,
+}
+
+impl<'a> FrameContext<'a> {
+    pub fn new(device: &'a wgpu::Device, queue: &'a wgpu::Queue, timer: Option<&'a mut GpuTimer>) -> Self {
+        let encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Frame Encoder"),
+        });
+
+        let timer = timer.and_then(|timer| timer.begin_frame(device).then_some(timer));
+
+        FrameContext {
+            device,
+            queue,
+            encoder,
+            timer,
+            readbacks: Vec::new(),
+        }
+    }
+
+    /// Begins a render pass and measures its GPU time, if timestamp queries are available.
+    /// The label of the descriptor names the measurement.
+    pub fn begin_render_pass(&mut self, descriptor: wgpu::RenderPassDescriptor<'_>) -> wgpu::RenderPass<'_> {
+        let timestamp_writes = self.timer
+            .as_mut()
+            .and_then(|timer| timer.allocate(descriptor.label.unwrap_or("Unnamed Pass")));
+
+        let timer = self.timer.as_deref();
+        self.encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            timestamp_writes: timestamp_writes.zip(timer).map(|(index, timer)| wgpu::RenderPassTimestampWrites {
+                query_set: &timer.query_set,
+                beginning_of_pass_write_index: Some(index),
+                end_of_pass_write_index: Some(index + 1),
+            }),
+            ..descriptor
+        })
+    }
+
+    /// Like [`FrameContext::begin_render_pass`], for compute passes.
+    pub fn begin_compute_pass(&mut self, label: &str) -> wgpu::ComputePass<'_> {
+        let timestamp_writes = self.timer
+            .as_mut()
+            .and_then(|timer| timer.allocate(label));
+
+        let timer = self.timer.as_deref();
+        self.encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some(label),
+            timestamp_writes: timestamp_writes.zip(timer).map(|(index, timer)| wgpu::ComputePassTimestampWrites {
+                query_set: &timer.query_set,
+                beginning_of_pass_write_index: Some(index),
+                end_of_pass_write_index: Some(index + 1),
+            }),
+        })
+    }
+
+    /// Maps `buffer` for reading once the frame was submitted, since buffers used by the
+    /// frame can't be mapped before. `mapped` is incremented when the mapping succeeded.
+    pub fn read_back(&mut self, buffer: &wgpu::Buffer, mapped: Arc<AtomicUsize>) {
+        self.readbacks.push((buffer.clone(), mapped));
+    }
+
+    pub fn submit(mut self) {
+        if let Some(timer) = self.timer.as_mut() {
+            timer.resolve(&mut self.encoder);
+        }
+
+        self.queue.submit(std::iter::once(self.encoder.finish()));
+
+        if let Some(timer) = self.timer {
+            timer.request_readback();
+        }
+
+        for (buffer, mapped) in self.readbacks {
+            buffer.slice(..).map_async(wgpu::MapMode::Read, move |result| {
+                if result.is_ok() {
+                    mapped.fetch_add(1, Ordering::AcqRel);
+                }
+            });
+        }
+    }
+}
+
+const MAX_TIMED_PASSES: u32 = 64;
+
+/// Measures the GPU time of every pass recorded through a [`FrameContext`] with timestamp
+/// queries. Results arrive a frame or more later, without stalling the CPU; frames are not
+/// measured while the previous results are still being read back.
+pub struct GpuTimer {
+    query_set: wgpu::QuerySet,
+    resolve_buffer: wgpu::Buffer,
+    readback_buffer: wgpu::Buffer,
+    readback_ready: Arc<AtomicBool>,
+    readback_pending: bool,
+    period: f32,
+    labels: Vec<String>,
+    pending_labels: Vec<String>,
+    timings: Vec<(String, f64)>,
+}
+
+impl GpuTimer {
+    /// Returns `None` if the device was created without `Features::TIMESTAMP_QUERY`.
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Option<Self> {
+        if !device.features().contains(wgpu::Features::TIMESTAMP_QUERY) {
+            return None;
+        }
+
+        let query_count = MAX_TIMED_PASSES * 2;
+        let size = query_count as wgpu::BufferAddress * std::mem::size_of::<u64>() as wgpu::BufferAddress;
+
+        let query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
+            label: Some("Pass Timestamps"),
+            ty: wgpu::QueryType::Timestamp,
+            count: query_count,
+        });
+
+        let resolve_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Timestamp Resolve Buffer"),
+            size,
+            usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Timestamp Readback Buffer"),
+            size,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        Some(GpuTimer {
+            query_set,
+            resolve_buffer,
+            readback_buffer,
+            readback_ready: Arc::new(AtomicBool::new(false)),
+            readback_pending: false,
+            period: queue.get_timestamp_period(),
+            labels: Vec::new(),
+            pending_labels: Vec::new(),
+            timings: Vec::new(),
+        })
+    }
+
+    /// GPU time in milliseconds of every pass of the last measured frame, in recording order.
+    pub fn timings(&self) -> &[(String, f64)] {
+        &self.timings
+    }
+
+    /// Collects finished results. Returns whether this frame can be measured.
+    fn begin_frame(&mut self, device: &wgpu::Device) -> bool {
+        self.labels.clear();
+
+        if !self.readback_pending {
+            return true;
+        }
+
+        let _ = device.poll(wgpu::PollType::Poll);
+
+        if !self.readback_ready.swap(false, Ordering::AcqRel) {
+            return false;
+        }
+
+        {
+            let data = self.readback_buffer.slice(..).get_mapped_range();
+            let timestamps: &[u64] = bytemuck::cast_slice(&data);
+
+            self.timings = self.pending_labels
+                .drain(..)
+                .enumerate()
+                .map(|(i, label)| {
+                    let ticks = timestamps[2 * i + 1].saturating_sub(timestamps[2 * i]);
+                    (label, ticks as f64 * self.period as f64 / 1_000_000.0)
+                })
+                .collect();
+        }
+
+        self.readback_buffer.unmap();
+        self.readback_pending = false;
+
+        true
+    }
+
+    /// Reserves a begin and end query for a pass. Passes beyond the capacity are not measured.
+    fn allocate(&mut self, label: &str) -> Option<u32> {
+        if self.labels.len() as u32 >= MAX_TIMED_PASSES {
+            return None;
+        }
+
+        self.labels.push(label.to_string());
+        Some((self.labels.len() as u32 - 1) * 2)
+    }
+
+    fn resolve(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        if self.labels.is_empty() {
+            return;
+        }
+
+        let query_count = self.labels.len() as u32 * 2;
+        encoder.resolve_query_set(&self.query_set, 0..query_count, &self.resolve_buffer, 0);
+        encoder.copy_buffer_to_buffer(
+            &self.resolve_buffer,
+            0,
+            &self.readback_buffer,
+            0,
+            query_count as wgpu::BufferAddress * std::mem::size_of::<u64>() as wgpu::BufferAddress,
+        );
+    }
+
+    fn request_readback(&mut self) {
+        if self.labels.is_empty() {
+            return;
+        }
+
+        self.pending_labels = std::mem::take(&mut self.labels);
+        self.readback_pending = true;
+
+        let ready = self.readback_ready.clone();
+        self.readback_buffer.slice(..).map_async(wgpu::MapMode::Read, move |result| {
+            if result.is_ok() {
+                ready.store(true, Ordering::Release);
+            }
+        });
+    }
+}
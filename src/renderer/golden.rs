@@ -0,0 +1,145 @@
+//! Golden-image regression tests. A fixed scene is rendered headless and every intermediate
+//! render target (G-buffer, SSAO, shadow map) as well as the final frame is read back and
+//! compared against the reference PNGs in `tests/golden`.
+//!
+//! The scene is always rendered with the fallback adapter, so that the results do not depend
+//! on the GPU of the machine running the tests; the reference images were rendered with Mesa's
+//! llvmpipe through the GL backend. Without a fallback adapter the test fails. A missing
+//! reference image is an error as well; set `CELLS_UPDATE_GOLDEN=1` to write missing images
+//! or overwrite them after an intended change. Mismatches write the actual image and a diff
+//! image to `target/golden-failures`.
+
+use std::path::{Path, PathBuf};
+
+use super::offscreen::Image;
+
+const WIDTH: u32 = 320;
+const HEIGHT: u32 = 240;
+
+#[derive(Clone, Copy)]
+pub struct Tolerance {
+    /// Largest per-channel difference for which two pixels are still considered equal.
+    pub channel: u8,
+    /// Fraction of pixels that may differ before the comparison fails.
+    pub mismatched_pixels: f32,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Tolerance {
+            channel: 2,
+            mismatched_pixels: 0.001,
+        }
+    }
+}
+
+fn read_png(path: &Path) -> Image {
+    let decoder = png::Decoder::new(std::fs::File::open(path).unwrap());
+    let mut reader = decoder.read_info().unwrap();
+    let mut rgba = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut rgba).unwrap();
+    assert_eq!(info.color_type, png::ColorType::Rgba, "{:?} is not an RGBA image", path);
+    rgba.truncate(info.buffer_size());
+
+    Image {
+        width: info.width,
+        height: info.height,
+        rgba,
+    }
+}
+
+/// Compares two images of equal size. Returns the number of mismatched pixels and a diff
+/// image, which shows mismatches in red on top of a darkened copy of the expected image.
+pub fn diff_images(expected: &Image, actual: &Image, tolerance: Tolerance) -> (usize, Image) {
+    let mut mismatched = 0;
+    let mut rgba = Vec::with_capacity(expected.rgba.len());
+
+    for (e, a) in expected.rgba.chunks_exact(4).zip(actual.rgba.chunks_exact(4)) {
+        let difference = e.iter().zip(a).map(|(e, a)| e.abs_diff(*a)).max().unwrap();
+
+        if difference > tolerance.channel {
+            mismatched += 1;
+            rgba.extend_from_slice(&[difference.saturating_mul(4).max(64), 0, 0, 255]);
+        } else {
+            rgba.extend_from_slice(&[e[0] / 4, e[1] / 4, e[2] / 4, 255]);
+        }
+    }
+
+    (
+        mismatched,
+        Image {
+            width: expected.width,
+            height: expected.height,
+            rgba,
+        },
+    )
+}
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+}
+
+fn failures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("target").join("golden-failures")
+}
+
+/// Checks `actual` against `tests/golden/<name>.png`.
+pub fn check_golden(name: &str, actual: &Image, tolerance: Tolerance) -> Result<(), String> {
+    let golden_path = golden_dir().join(format!("{}.png", name));
+
+    if std::env::var_os("CELLS_UPDATE_GOLDEN").is_some() {
+        actual.write(&golden_path);
+        log::info!("Wrote golden image {:?}", golden_path);
+        return Ok(());
+    }
+
+    if !golden_path.exists() {
+        let actual_path = failures_dir().join(format!("{}.actual.png", name));
+        actual.write(&actual_path);
+        return Err(format!(
+            "{}: no golden image at {:?}, set CELLS_UPDATE_GOLDEN=1 to accept {:?}",
+            name, golden_path, actual_path
+        ));
+    }
+
+    let expected = read_png(&golden_path);
+    let actual_path = failures_dir().join(format!("{}.actual.png", name));
+
+    if (expected.width, expected.height) != (actual.width, actual.height) {
+        actual.write(&actual_path);
+        return Err(format!(
+            "{}: expected {}x{}, got {}x{} (written to {:?})",
+            name, expected.width, expected.height, actual.width, actual.height, actual_path
+        ));
+    }
+
+    let (mismatched, diff) = diff_images(&expected, actual, tolerance);
+    let allowed = (tolerance.mismatched_pixels * (actual.width * actual.height) as f32) as usize;
+
+    if mismatched > allowed {
+        let diff_path = failures_dir().join(format!("{}.diff.png", name));
+        actual.write(&actual_path);
+        diff.write(&diff_path);
+        return Err(format!(
+            "{}: {} pixels differ, {} allowed (see {:?} and {:?})",
+            name, mismatched, allowed, actual_path, diff_path
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn render_passes_match_golden_images() {
+    let _ = env_logger::builder().is_test(true).try_init();
+
+    let images = crate::headless::render_snapshots(WIDTH, HEIGHT)
+        .expect("No fallback adapter for the golden image test, install Mesa's llvmpipe");
+
+    let failures: Vec<String> = images
+        .iter()
+        .filter_map(|(name, image)| check_golden(name, image, Tolerance::default()).err())
+        .collect();
+
+    assert!(failures.is_empty(), "Golden image mismatches:\n{}", failures.join("\n"));
+}
@@ -0,0 +1,161 @@
+use super::frame::FrameContext;
+use super::lights::LightsResources;
+use super::render_graph::{BufferDesc, GraphResources, NodeBuilder, RenderInputs, RenderNode};
+use super::scene_base::SceneBaseResources;
+use super::shader_cache::ShaderCache;
+use super::shaders::{CompiledShaders, ShaderFile, ShaderStage, ShaderWatcher};
+use crate::renderer::deferred_pass::GBUFFER_POSITION;
+
+/// The lights reaching each screen tile, row by row: a light count followed by
+/// [`MAX_LIGHTS_PER_TILE`] light indices.
+pub const LIGHT_TILES: &str = "light_tiles";
+
+/// Width and height of the screen tiles, in pixels. The culling shader handles one tile
+/// per workgroup, with one invocation per pixel.
+pub const LIGHT_TILE_SIZE: u32 = 16;
+
+/// Lights beyond this many in a single tile are dropped.
+pub const MAX_LIGHTS_PER_TILE: u32 = 127;
+
+/// Number of tiles covering the screen horizontally and vertically.
+fn tile_count((width, height): (u32, u32)) -> (u32, u32) {
+    (width.div_ceil(LIGHT_TILE_SIZE), height.div_ceil(LIGHT_TILE_SIZE))
+}
+
+/// Bins the lights into screen tiles, testing their spheres against the frustum of each tile,
+/// narrowed to the depth range of the G-buffer within it. The composition then only shades
+/// the lights of the tile a pixel lies in.
+pub struct LightCullingPass {
+    shaders: ShaderWatcher,
+    pipeline_layout: wgpu::PipelineLayout,
+    pipeline: wgpu::ComputePipeline,
+    tiles_bind_group_layout: wgpu::BindGroupLayout,
+    /// Created once the graph allocated the G-buffer and the tiles.
+    tiles_bind_group: Option<wgpu::BindGroup>,
+}
+
+impl LightCullingPass {
+    pub fn new(
+        device: &wgpu::Device,
+        light_resources: &LightsResources,
+        scene_base_resources: &SceneBaseResources,
+        shader_cache: &ShaderCache,
+    ) -> Self {
+        let tiles_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Light Culling Tiles"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[
+                &scene_base_resources.bind_group_layout,
+                &light_resources.lights_bind_group_layout,
+                &tiles_bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+
+        let mut shaders = ShaderWatcher::new("Light Culling", vec![
+            ShaderFile::new("light_cull.comp", ShaderStage::Compute)
+                .define_u32("LIGHT_TILE_SIZE", LIGHT_TILE_SIZE)
+                .define_u32("MAX_LIGHTS_PER_TILE", MAX_LIGHTS_PER_TILE),
+        ], shader_cache);
+
+        let pipeline = shaders.build(device, |compiled| Self::create_pipeline(device, &pipeline_layout, compiled));
+
+        LightCullingPass {
+            shaders,
+            pipeline_layout,
+            pipeline,
+            tiles_bind_group_layout,
+            tiles_bind_group: None,
+        }
+    }
+
+    fn create_pipeline(
+        device: &wgpu::Device,
+        pipeline_layout: &wgpu::PipelineLayout,
+        compiled: &CompiledShaders,
+    ) -> wgpu::ComputePipeline {
+        device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Light Culling"),
+            layout: Some(pipeline_layout),
+            module: &compiled.modules[0],
+            entry_point: Some("main"),
+            compilation_options: Default::default(),
+            cache: compiled.cache,
+        })
+    }
+}
+
+impl RenderNode for LightCullingPass {
+    fn name(&self) -> &str {
+        "Light Culling"
+    }
+
+    fn declare(&self, builder: &mut NodeBuilder) {
+        let (tiles_x, tiles_y) = tile_count(builder.screen_size());
+
+        builder.read_texture(GBUFFER_POSITION);
+        builder.create_buffer(LIGHT_TILES, BufferDesc {
+            size: ((tiles_x * tiles_y).max(1) * (1 + MAX_LIGHTS_PER_TILE)) as u64 * std::mem::size_of::<u32>() as u64,
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+    }
+
+    fn prepare(&mut self, device: &wgpu::Device, resources: &GraphResources) {
+        self.tiles_bind_group = Some(device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Light Culling Tiles"),
+            layout: &self.tiles_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(resources.texture_view(GBUFFER_POSITION)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: resources.buffer(LIGHT_TILES).as_entire_binding(),
+                },
+            ],
+        }));
+    }
+
+    fn reload_shaders(&mut self, device: &wgpu::Device) {
+        let pipeline_layout = &self.pipeline_layout;
+        self.shaders.reload(device, &mut self.pipeline, |compiled| Self::create_pipeline(device, pipeline_layout, compiled));
+    }
+
+    fn run(&mut self, frame: &mut FrameContext, resources: &GraphResources, inputs: &RenderInputs) {
+        let (tiles_x, tiles_y) = tile_count(resources.screen_size());
+
+        let mut compute_pass = frame.begin_compute_pass("Light Culling");
+
+        compute_pass.set_pipeline(&self.pipeline);
+        compute_pass.set_bind_group(0, &inputs.scene_base.bind_group, &[]);
+        compute_pass.set_bind_group(1, &inputs.lights.lights_bind_group, &[]);
+        compute_pass.set_bind_group(2, self.tiles_bind_group.as_ref().unwrap(), &[]);
+        compute_pass.dispatch_workgroups(tiles_x, tiles_y, 1);
+    }
+}
@@ -1,53 +1,240 @@
-use wgpu::util::*;
-use bytemuck::__core::num::NonZeroU32;
+/// Number of lights the light buffer has room for before it first grows.
+const INITIAL_LIGHT_CAPACITY: usize = 32;
+
+/// Number of slices the camera's depth range is split into, each with its own layer in the
+/// shadow map.
+pub const SHADOW_CASCADES: usize = 4;
+
+/// Width and height of every layer of the shadow map.
+pub const SHADOW_MAP_SIZE: u32 = 2048;
+
+/// Each cascade also covers this fraction of the depth before its start, where the
+/// composition blends it with the previous cascade.
+pub const CASCADE_BLEND: f32 = 0.1;
+
+/// How many point lights can cast shadows at the same time. Each one owns a slot of six
+/// layers in the point shadow map, one per cube face.
+pub const MAX_SHADOWED_POINT_LIGHTS: usize = 6;
+
+/// How many spot lights can cast shadows at the same time. Their slots are a single layer
+/// each, following the ones of the point lights.
+pub const MAX_SHADOWED_SPOT_LIGHTS: usize = 4;
+
+/// The shadow slots of the point lights, followed by the ones of the spot lights.
+pub const SHADOW_SLOTS: usize = MAX_SHADOWED_POINT_LIGHTS + MAX_SHADOWED_SPOT_LIGHTS;
+
+/// Layers of the point shadow map. The GL backend takes textures with a multiple of six
+/// layers for cube arrays, so this must not be one.
+pub const POINT_SHADOW_LAYERS: usize = 6 * MAX_SHADOWED_POINT_LIGHTS + MAX_SHADOWED_SPOT_LIGHTS;
+
+/// Width and height of every cube face in the point shadow map.
+pub const POINT_SHADOW_MAP_SIZE: u32 = 512;
+
+/// How far surfaces are moved towards a point light before looking up its shadow, in world
+/// units, so they don't shadow themselves.
+pub const POINT_SHADOW_BIAS: f32 = 0.05;
+
+/// Stored in the w component of `GpuLight::direction_type`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LightType {
+    Point = 0,
+    Spot = 1,
+}
+
+/// How the intensity of a point or spot light decreases with the distance. The composition
+/// multiplies every curve with a window which smoothly reaches zero at the light's radius.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Falloff {
+    /// Physically based, 1 / distance².
+    InverseSquare = 0,
+    /// 1 - distance / radius.
+    Linear = 1,
+    /// Full intensity until the window sets in.
+    Constant = 2,
+}
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct GpuLight {
     pub position: [f32;4],
     pub color: [f32;4],
-    pub intensity_radius_enabled: [f32; 4] // gpu wants 16byte wide fields...
+    pub intensity_radius_enabled_shadow: [f32; 4], // gpu wants 16byte wide fields... shadow is the first layer of the slot or -1
+    pub direction_type: [f32; 4], // xyz: the direction of spot lights, w: the `LightType`
+    pub cone_falloff: [f32; 4] // x: cosine of the inner cone angle, y: of the outer one, z: the `Falloff`
 }
 
 unsafe impl bytemuck::Pod for GpuLight {}
 unsafe impl bytemuck::Zeroable for GpuLight {}
 
+/// Precedes the lights in the light buffer.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct GpuLightCount {
+    count: u32,
+    padding: [u32; 3],
+}
+
+unsafe impl bytemuck::Pod for GpuLightCount {}
+unsafe impl bytemuck::Zeroable for GpuLightCount {}
+
 impl Default for GpuLight {
     fn default() -> Self {
         GpuLight {
             position: [0.0, 0.0, 0.0, 1.0],
             color: [1.0, 1.0, 1.0, 1.0],
-            intensity_radius_enabled: [0.125, 10.0, 0.0, 1.0]
+            intensity_radius_enabled_shadow: [0.125, 10.0, 0.0, -1.0],
+            direction_type: [0.0, -1.0, 0.0, LightType::Point as u32 as f32],
+            cone_falloff: [-1.0, -1.0, Falloff::InverseSquare as u32 as f32, 0.0]
         }
     }
 }
 
+/// The part of the camera's view a layer of the shadow map covers.
+#[derive(Debug, Copy, Clone)]
+pub struct ShadowCascade {
+    /// Maps world space into the light's clip space.
+    pub view_projection: cgmath::Matrix4<f32>,
+    /// View space distance from the camera at which the next cascade takes over.
+    pub split_depth: f32,
+    /// The world space depth covered by the projection divided by its width, which turns
+    /// depth differences in light clip space into penumbra widths in shadow map uv.
+    pub penumbra_scale: f32,
+}
+
+/// How shadow map lookups are filtered.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ShadowFilter {
+    /// A single lookup, filtered over 2x2 texels by the comparison sampler.
+    Hardware,
+    /// Lookups on a Poisson disk rotated per pixel, which trades the blocky edges for noise.
+    Poisson,
+    /// Percentage-closer soft shadows: the Poisson disk is scaled by the penumbra estimated
+    /// from the average depth of the blockers, so shadows soften with the distance to their
+    /// casters.
+    Pcss,
+}
+
+impl ShadowFilter {
+    pub fn next(self) -> Self {
+        match self {
+            ShadowFilter::Hardware => ShadowFilter::Poisson,
+            ShadowFilter::Poisson => ShadowFilter::Pcss,
+            ShadowFilter::Pcss => ShadowFilter::Hardware,
+        }
+    }
+}
+
+/// Trades the smoothness of filtered shadows against their cost.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ShadowQuality {
+    Low,
+    Medium,
+    High,
+}
+
+impl ShadowQuality {
+    /// Lookups per pixel of the Poisson filter, and of the PCSS blocker search.
+    pub fn taps(self) -> u32 {
+        match self {
+            ShadowQuality::Low => 8,
+            ShadowQuality::Medium => 16,
+            ShadowQuality::High => 32,
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            ShadowQuality::Low => ShadowQuality::Medium,
+            ShadowQuality::Medium => ShadowQuality::High,
+            ShadowQuality::High => ShadowQuality::Low,
+        }
+    }
+}
+
+/// Filtering of the directional light's shadows. Can be changed at any time; point light
+/// shadows always use [`ShadowFilter::Hardware`].
+#[derive(Debug, Copy, Clone)]
+pub struct ShadowSettings {
+    pub filter: ShadowFilter,
+    pub quality: ShadowQuality,
+    /// Radius of the Poisson disk in shadow map texels.
+    pub filter_radius: f32,
+    /// Apparent size of the directional light for PCSS, as the tangent of its angular radius.
+    /// The sun's is about 0.005; larger values give softer shadows.
+    pub light_size: f32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        ShadowSettings {
+            filter: ShadowFilter::Poisson,
+            quality: ShadowQuality::Medium,
+            filter_radius: 1.5,
+            light_size: 0.05,
+        }
+    }
+}
+
+/// The shadow casting directional light, as set by `LightSystem`.
+#[derive(Debug, Copy, Clone)]
+pub struct DirectionalLightData {
+    /// The direction the light travels in, in world space.
+    pub direction: cgmath::Vector3<f32>,
+    pub color: cgmath::Vector3<f32>,
+    pub intensity: f32,
+    /// Ordered from the camera outwards.
+    pub cascades: [ShadowCascade; SHADOW_CASCADES],
+    pub depth_bias: f32,
+    pub slope_bias: f32,
+}
+
+/// A point or spot light casting shadows, as set by `LightSystem`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LightShadowData {
+    /// Map world space into the clip space of the faces. Point lights use all six cube faces,
+    /// in the order +x, -x, +y, -y, +z, -z; spot lights only the first, as their slots have a
+    /// single layer.
+    pub face_view_projections: [cgmath::Matrix4<f32>; 6],
+    pub faces: usize,
+    /// Encloses everything the light reaches, so the shadow casters are culled once for all
+    /// faces.
+    pub culling_view_projection: cgmath::Matrix4<f32>,
+}
+
 pub struct LightsResources {
     pub lights_bind_group_layout: wgpu::BindGroupLayout,
+    /// Replaced whenever the light buffer grows.
     pub lights_bind_group: wgpu::BindGroup,
+    /// A `GpuLightCount` followed by the lights, indexed by light index.
     pub lights_buffer: wgpu::Buffer,
-    free_light_indices: std::vec::Vec<u32>
+    /// Lights the scene with shadows. Without it, only the point lights and ambient light remain.
+    pub directional_light: Option<DirectionalLightData>,
+    pub shadow_settings: ShadowSettings,
+    /// Indexed by shadow slot.
+    pub light_shadows: [Option<LightShadowData>; SHADOW_SLOTS],
+    /// Every light index handed out so far; freed ones are disabled. Only the lights which
+    /// changed are uploaded, see `upload_lights`.
+    lights: Vec<GpuLight>,
+    dirty_lights: Option<std::ops::Range<usize>>,
+    capacity: usize,
+    free_light_indices: std::vec::Vec<u32>,
+    free_point_shadow_slots: std::vec::Vec<u32>,
+    free_spot_shadow_slots: std::vec::Vec<u32>
 }
 
 impl LightsResources {
     pub fn new(device: &wgpu::Device) -> Self {
-        let lights_data = vec![GpuLight::default(); 20];
-
-        let lights_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Point Lights Buffer"),
-            contents: bytemuck::cast_slice(&lights_data),
-            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM
-        });
-
         let lights_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: Some("Point Lights Bind Group Layout"),
+            label: Some("Lights Bind Group Layout"),
             entries: &[
                 wgpu::BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::VERTEX,
+                    visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
                     ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        min_binding_size: None,//wgpu::BufferSize::new((std::mem::size_of::<GpuLight>() * 20) as wgpu::BufferAddress)
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        min_binding_size: wgpu::BufferSize::new(
+                            (std::mem::size_of::<GpuLightCount>() + std::mem::size_of::<GpuLight>()) as u64
+                        ),
                         has_dynamic_offset: false
                     },
                     count: None
@@ -55,48 +242,128 @@ impl LightsResources {
             ]
         });
 
+        let (lights_buffer, lights_bind_group) = Self::create_lights_buffer(device, &lights_bind_group_layout, INITIAL_LIGHT_CAPACITY);
+
+        LightsResources {
+            lights_buffer,
+            lights_bind_group_layout,
+            lights_bind_group,
+            directional_light: None,
+            shadow_settings: ShadowSettings::default(),
+            light_shadows: [None; SHADOW_SLOTS],
+            lights: Vec::new(),
+            dirty_lights: None,
+            capacity: INITIAL_LIGHT_CAPACITY,
+            free_light_indices: Vec::new(),
+            free_point_shadow_slots: (0..MAX_SHADOWED_POINT_LIGHTS as u32).rev().collect(),
+            free_spot_shadow_slots: (MAX_SHADOWED_POINT_LIGHTS as u32..SHADOW_SLOTS as u32).rev().collect()
+        }
+    }
+
+    fn create_lights_buffer(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        capacity: usize
+    ) -> (wgpu::Buffer, wgpu::BindGroup) {
+        let lights_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Lights Buffer"),
+            size: (std::mem::size_of::<GpuLightCount>() + capacity * std::mem::size_of::<GpuLight>()) as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false
+        });
+
         let lights_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout: &lights_bind_group_layout,
+            label: Some("Lights Bind Group"),
+            layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: wgpu::BindingResource::Buffer(lights_buffer.as_entire_buffer_binding())
+                    resource: lights_buffer.as_entire_binding()
                 }
             ]
         });
 
-        LightsResources {
-            lights_buffer,
-            lights_bind_group_layout,
-            lights_bind_group,
-            free_light_indices: (0..20).collect()
+        (lights_buffer, lights_bind_group)
+    }
+
+    /// Hands out an unused light index, disabled until it's updated.
+    pub fn create_light(&mut self) -> u32 {
+        self.free_light_indices.pop().unwrap_or_else(|| {
+            self.lights.push(GpuLight::default());
+            (self.lights.len() - 1) as u32
+        })
+    }
+
+    /// Disables the light and makes its index available again.
+    pub fn free_light(&mut self, light_index: u32) {
+        self.update_light(light_index, GpuLight::default());
+        self.free_light_indices.push(light_index);
+    }
+
+    /// Reserves a slot in the point shadow map for a light of the given type. Returns `None`
+    /// when all slots of that type are taken.
+    pub fn create_shadow_slot(&mut self, light_type: LightType) -> Option<u32> {
+        match light_type {
+            LightType::Point => self.free_point_shadow_slots.pop(),
+            LightType::Spot => self.free_spot_shadow_slots.pop(),
         }
     }
 
-    pub fn create_new_light(&mut self) -> Option<u32> {
-        self.free_light_indices.pop()
+    pub fn free_shadow_slot(&mut self, slot: u32) {
+        self.light_shadows[slot as usize] = None;
+        if (slot as usize) < MAX_SHADOWED_POINT_LIGHTS {
+            self.free_point_shadow_slots.push(slot);
+        } else {
+            self.free_spot_shadow_slots.push(slot);
+        }
     }
 
-    pub fn update_light(&self, device: &wgpu::Device, queue: &wgpu::Queue, light_index: u32, light: GpuLight) {
-        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-            label: None
+    /// The first layer of a shadow slot in the point shadow map.
+    pub fn shadow_slot_layer(slot: u32) -> u32 {
+        let slot = slot as usize;
+        let layer = if slot < MAX_SHADOWED_POINT_LIGHTS {
+            6 * slot
+        } else {
+            6 * MAX_SHADOWED_POINT_LIGHTS + slot - MAX_SHADOWED_POINT_LIGHTS
+        };
+        layer as u32
+    }
+
+    pub fn update_light(&mut self, light_index: u32, light: GpuLight) {
+        let index = light_index as usize;
+        self.lights[index] = light;
+
+        self.dirty_lights = Some(match self.dirty_lights.take() {
+            Some(dirty) => dirty.start.min(index)..dirty.end.max(index + 1),
+            None => index..index + 1
         });
+    }
+
+    /// Writes the lights changed since the last upload to the GPU, together with the light
+    /// count. Grows the buffer when the lights don't fit anymore, which replaces the bind group.
+    pub fn upload_lights(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let dirty = match self.dirty_lights.take() {
+            Some(dirty) => dirty,
+            None => return
+        };
+
+        let dirty = if self.lights.len() > self.capacity {
+            self.capacity = self.lights.len().next_power_of_two();
+            let (lights_buffer, lights_bind_group) = Self::create_lights_buffer(device, &self.lights_bind_group_layout, self.capacity);
+            self.lights_buffer = lights_buffer;
+            self.lights_bind_group = lights_bind_group;
 
-        encoder.copy_buffer_to_buffer(
-            &device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Tmp copy buffer light"),
-                contents: bytemuck::cast_slice(&[
-                    light
-                ]),
-                usage: wgpu::BufferUsages::COPY_SRC
-            }),
-            0,
+            0..self.lights.len()
+        } else {
+            dirty
+        };
+
+        let count = GpuLightCount { count: self.lights.len() as u32, padding: [0; 3] };
+        queue.write_buffer(&self.lights_buffer, 0, bytemuck::cast_slice(&[count]));
+        queue.write_buffer(
             &self.lights_buffer,
-            (light_index as u64) * std::mem::size_of::<GpuLight>() as u64,
-            std::mem::size_of::<GpuLight>() as wgpu::BufferAddress
+            (std::mem::size_of::<GpuLightCount>() + dirty.start * std::mem::size_of::<GpuLight>()) as wgpu::BufferAddress,
+            bytemuck::cast_slice(&self.lights[dirty])
         );
-
-        queue.submit(std::iter::once(encoder.finish()));
     }
 }
@@ -1,80 +1,144 @@
-use wgpu::util::DeviceExt;
-
+/// The slots a [`Material`] is stored in on the GPU.
 #[repr(C, align(256))]
 #[derive(Debug, Clone, Copy)]
 pub struct GpuMaterial {
-    pub(crate) primary: cgmath::Vector4<f32>,    // 16 bytes
-    pub(crate) secondary: cgmath::Vector4<f32>,  // 16 bytes
-    pub(crate) tertiary: cgmath::Vector4<f32>,   // 16 bytes
-    pub(crate) quaternary: cgmath::Vector4<f32>, // 16 bytes
+    pub(crate) primary: cgmath::Vector4<f32>,    // 16 bytes, base color
+    pub(crate) secondary: cgmath::Vector4<f32>,  // 16 bytes, x: metallic, y: roughness
+    pub(crate) tertiary: cgmath::Vector4<f32>,   // 16 bytes, unused
+    pub(crate) quaternary: cgmath::Vector4<f32>, // 16 bytes, unused
 }
 
 unsafe impl bytemuck::Pod for GpuMaterial {}
 unsafe impl bytemuck::Zeroable for GpuMaterial {}
 
+/// A metallic/roughness material.
+#[derive(Debug, Clone, Copy)]
+pub struct Material {
+    /// The albedo of dielectrics, and the reflectance of metals.
+    pub base_color: cgmath::Vector4<f32>,
+    /// 0 for dielectrics, 1 for metals.
+    pub metallic: f32,
+    /// Perceptual roughness, from 0 for a mirror to 1.
+    pub roughness: f32,
+}
+
+impl From<Material> for GpuMaterial {
+    fn from(material: Material) -> Self {
+        GpuMaterial {
+            primary: material.base_color,
+            secondary: cgmath::Vector4::new(material.metallic, material.roughness, 0.0, 0.0),
+            tertiary: cgmath::Vector4::new(0.0, 0.0, 0.0, 0.0),
+            quaternary: cgmath::Vector4::new(0.0, 0.0, 0.0, 0.0),
+        }
+    }
+}
+
+/// How the composition lights the G-buffer. Stored as a `u32` in the composition uniforms.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ShadingModel {
+    /// Diffuse only, ignoring metallic and roughness.
+    Lambert = 0,
+    /// A Cook-Torrance BRDF with a GGX distribution.
+    Pbr = 1,
+}
+
+impl ShadingModel {
+    pub fn next(self) -> Self {
+        match self {
+            ShadingModel::Lambert => ShadingModel::Pbr,
+            ShadingModel::Pbr => ShadingModel::Lambert,
+        }
+    }
+}
+
+/// Number of materials the material buffer has room for before it first grows.
+const INITIAL_MATERIAL_CAPACITY: usize = 16;
+
 pub struct MaterialResources {
     pub materials: Vec<GpuMaterial>,
     buffer: wgpu::Buffer,
     pub bind_group: wgpu::BindGroup,
     pub bind_group_layout: wgpu::BindGroupLayout,
-    capacity: u64,
+    /// Applies to all materials; switchable at runtime to compare the models.
+    pub shading_model: ShadingModel,
+    capacity: usize,
 }
 
 impl MaterialResources {
-    pub fn new(device: &wgpu::Device, capacity: u64) -> MaterialResources {
-        let materials = Vec::with_capacity(capacity as usize);
-
-        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Material Buffer"),
-            size: capacity * std::mem::size_of::<GpuMaterial>() as u64,
-            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
-            mapped_at_creation: false,
-        });
-
+    pub fn new(device: &wgpu::Device) -> MaterialResources {
         let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: None,
             entries: &[wgpu::BindGroupLayoutEntry {
                 binding: 0,
                 visibility: wgpu::ShaderStages::FRAGMENT,
                 ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
-                    has_dynamic_offset: true,
-                    min_binding_size: wgpu::BufferSize::new(
-                        device.limits().min_uniform_buffer_offset_alignment as u64
-                    ),
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: wgpu::BufferSize::new(std::mem::size_of::<GpuMaterial>() as u64),
                 },
                 count: None,
             }],
         });
 
+        let (buffer, bind_group) = Self::create_buffer(device, &bind_group_layout, INITIAL_MATERIAL_CAPACITY);
+
+        MaterialResources {
+            materials: Vec::with_capacity(INITIAL_MATERIAL_CAPACITY),
+            buffer,
+            bind_group_layout,
+            bind_group,
+            shading_model: ShadingModel::Lambert,
+            capacity: INITIAL_MATERIAL_CAPACITY,
+        }
+    }
+
+    fn create_buffer(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        capacity: usize
+    ) -> (wgpu::Buffer, wgpu::BindGroup) {
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Material Buffer"),
+            size: (capacity * std::mem::size_of::<GpuMaterial>()) as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: None,
-            layout: &bind_group_layout,
+            layout,
             entries: &[wgpu::BindGroupEntry {
                 binding: 0,
                 resource: wgpu::BindingResource::Buffer(buffer.as_entire_buffer_binding()),
             }],
         });
 
-        MaterialResources {
-            materials,
-            buffer,
-            bind_group_layout,
-            bind_group,
-            capacity,
-        }
+        (buffer, bind_group)
     }
 
-    pub fn add_material(&mut self, queue: &wgpu::Queue, material: GpuMaterial) -> u64 {
-        let index = self.materials.len() as u64;
+    /// Stores the material on the GPU and returns its index. Grows the buffer when the
+    /// materials don't fit anymore, which replaces the bind group.
+    pub fn add_material(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, material: Material) -> u64 {
+        let index = self.materials.len();
+        self.materials.push(GpuMaterial::from(material));
+
+        let written = if self.materials.len() > self.capacity {
+            self.capacity = self.materials.len().next_power_of_two();
+            let (buffer, bind_group) = Self::create_buffer(device, &self.bind_group_layout, self.capacity);
+            self.buffer = buffer;
+            self.bind_group = bind_group;
+
+            0..self.materials.len()
+        } else {
+            index..index + 1
+        };
 
         queue.write_buffer(
             &self.buffer,
-            std::mem::size_of::<GpuMaterial>() as u64 * index,
-            bytemuck::cast_slice(&[material]),
+            (std::mem::size_of::<GpuMaterial>() * written.start) as wgpu::BufferAddress,
+            bytemuck::cast_slice(&self.materials[written]),
         );
-        self.materials.push(material);
 
-        index
+        index as u64
     }
 }
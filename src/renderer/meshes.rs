@@ -1,6 +1,7 @@
 use super::{geometry::Geometry};
 use wgpu::util::*;
-use crate::renderer::utils::{GpuMatrix4, GpuVector3};
+use crate::renderer::draw_order::{check_layer, LAYER_OPAQUE};
+use crate::renderer::utils::{GpuMatrix4, AABB};
 
 pub struct GpuGeometry {
     pub positions_buffer: wgpu::Buffer,
@@ -10,28 +11,97 @@ pub struct GpuGeometry {
     pub index_count: u32,
 }
 
+/// Per-instance data read by the culling compute shader and the vertex shaders.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct GpuInstance {
+    pub model_matrix: cgmath::Matrix4<f32>,
+    pub material: u32,
+    /// 0 for free slots, which are never drawn.
+    pub active: u32,
+    /// Part of the sort key built by the culling shader, see `draw_order`.
+    pub layer: u32,
+    pub padding: u32,
+}
+
+impl GpuInstance {
+    pub fn empty() -> Self {
+        GpuInstance {
+            model_matrix: GpuMatrix4::empty().matrix,
+            material: 0,
+            active: 0,
+            layer: LAYER_OPAQUE,
+            padding: 0,
+        }
+    }
+}
+
+unsafe impl bytemuck::Pod for GpuInstance {}
+unsafe impl bytemuck::Zeroable for GpuInstance {}
+
+/// All instances of a mesh type are in use. The instance buffer has a fixed capacity,
+/// given when the mesh type is created.
+#[derive(Debug, Clone)]
+pub struct InstancesFull {
+    pub mesh_type: String,
+    pub capacity: usize,
+}
+
+impl std::fmt::Display for InstancesFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "All {} instances of {} are in use", self.capacity, self.mesh_type)
+    }
+}
+
+impl std::error::Error for InstancesFull {}
+
 pub struct MeshType {
     name: String,
     pub gpu_geometry: GpuGeometry,
-    geometry: Geometry,
-    pub model_matrix_buffer: wgpu::Buffer,
-    pub model_matrices: Vec<GpuMatrix4>,
+    /// Bounds of the geometry in model space.
+    pub bounds: AABB,
+    /// Instances of all meshes of this type, indexed by object index. Only the
+    /// instances which changed are uploaded, see `upload_instances`.
+    pub instance_buffer: wgpu::Buffer,
+    pub instance_bind_group: wgpu::BindGroup,
+    pub instances: Vec<GpuInstance>,
+    dirty_instances: Option<std::ops::Range<usize>>,
     free_indices: Vec<usize>,
     capacity: usize
 }
 
 impl MeshType {
-    pub fn new(device: &wgpu::Device, name: &str, capacity: usize, geometry: Geometry) -> Self {
+    pub fn new(
+        device: &wgpu::Device,
+        instance_bind_group_layout: &wgpu::BindGroupLayout,
+        name: &str,
+        capacity: usize,
+        geometry: Geometry
+    ) -> Self {
 
-        let model_matrices = vec![GpuMatrix4::empty(); capacity];
+        let instances = vec![GpuInstance::empty(); capacity];
 
-        let model_matrix_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some(&format!("ModelMatrixBuffer: {}", name)),
-            size: (capacity * std::mem::size_of::<GpuMatrix4>()) as u64,
-            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false
+        let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("InstanceBuffer: {}", name)),
+            contents: bytemuck::cast_slice(&instances),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST
         });
 
+        let instance_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&format!("InstanceBindGroup: {}", name)),
+            layout: instance_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: instance_buffer.as_entire_binding()
+                }
+            ]
+        });
+
+        let bounds = AABB::from_points(
+            geometry.vertices.iter().map(|vertex| cgmath::Point3::new(vertex.vector.x, vertex.vector.y, vertex.vector.z))
+        );
+
         let gpu_geometry = {
 
             let positions_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -70,65 +140,143 @@ impl MeshType {
         MeshType {
             name: name.to_string(),
             free_indices: (0..capacity).rev().collect(),
-            model_matrices,
-            model_matrix_buffer,
-            geometry,
+            instances,
+            instance_buffer,
+            instance_bind_group,
+            dirty_instances: None,
+            bounds,
             gpu_geometry,
             capacity
         }
     }
 
-    pub fn create_mesh(&mut self) -> usize {
-        let index = self.free_indices.pop().unwrap(); // for now, we just panic if max capacity is reached
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn create_mesh(&mut self) -> Result<usize, InstancesFull> {
+        let index = self.free_indices.pop().ok_or_else(|| InstancesFull {
+            mesh_type: self.name.clone(),
+            capacity: self.capacity
+        })?;
 
-        if let Some(matrix) = self.model_matrices.get_mut(index) {
-            *matrix = GpuMatrix4::empty();
-        } else {
-            self.model_matrices.push(GpuMatrix4::empty());
+        self.update_instance(index as u32, |instance| {
+            *instance = GpuInstance::empty();
+            instance.active = 1;
+        });
+
+        Ok(index)
+    }
+
+    /// Stops drawing the instance and hands its index out again.
+    pub fn free_instance(&mut self, object_index: u32) {
+        if self.instances[object_index as usize].active == 0 {
+            log::warn!("Instance {} of {} is freed twice", object_index, self.name);
+            return;
         }
 
-        index
+        self.update_instance(object_index, |instance| *instance = GpuInstance::empty());
+        self.free_indices.push(object_index as usize);
     }
 
-    pub fn prepare_instances(&self, queue: &wgpu::Queue, instance_indices: &[u32]) {
-        let mut matrices_to_copy = Vec::with_capacity(instance_indices.len());
+    pub fn update_model_matrix(&mut self, object_index: u32, matrix: GpuMatrix4) {
+        self.update_instance(object_index, |instance| instance.model_matrix = matrix.matrix);
+    }
 
-        for index in instance_indices {
-            if let Some(matrix) = self.model_matrices.get(*index as usize) {
-                matrices_to_copy.push(*matrix);
-            }
+    pub fn update_material(&mut self, object_index: u32, material: u32) {
+        self.update_instance(object_index, |instance| instance.material = material);
+    }
+
+    pub fn update_layer(&mut self, object_index: u32, layer: u32) {
+        if let Err(error) = check_layer(layer) {
+            log::error!("Not drawing instance {} of {}: {}", object_index, self.name, error);
         }
+        self.update_instance(object_index, |instance| instance.layer = layer);
+    }
+
+    fn update_instance(&mut self, object_index: u32, update: impl FnOnce(&mut GpuInstance)) {
+        let index = object_index as usize;
+        update(self.instances.get_mut(index).unwrap());
 
-        let data = bytemuck::cast_slice(&matrices_to_copy);
-        queue.write_buffer(&self.model_matrix_buffer, 0, data);
+        self.dirty_instances = Some(match self.dirty_instances.take() {
+            Some(dirty) => dirty.start.min(index)..dirty.end.max(index + 1),
+            None => index..index + 1
+        });
     }
 
-    pub fn update_model_matrix(&mut self, object_index: u32, matrix: GpuMatrix4) {
-        *self.model_matrices.get_mut(object_index as usize).unwrap() = matrix;
+    /// Writes the range of instances changed since the last upload to the GPU. Returns whether
+    /// there were any.
+    pub fn upload_instances(&mut self, queue: &wgpu::Queue) -> bool {
+        match self.dirty_instances.take() {
+            Some(dirty) => {
+                queue.write_buffer(
+                    &self.instance_buffer,
+                    (dirty.start * std::mem::size_of::<GpuInstance>()) as wgpu::BufferAddress,
+                    bytemuck::cast_slice(&self.instances[dirty])
+                );
+                true
+            }
+            None => false
+        }
     }
 }
 
 
 pub struct MeshResources {
     pub mesh_types: Vec<MeshType>,
+    pub instance_bind_group_layout: wgpu::BindGroupLayout,
+    generation: u64,
 }
 
 impl MeshResources {
-    pub fn new() -> Self {
+    pub fn new(device: &wgpu::Device) -> Self {
+
+        let instance_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Instances"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(std::mem::size_of::<GpuInstance>() as u64)
+                    },
+                    count: None
+                }
+            ]
+        });
 
         MeshResources {
             mesh_types: Vec::new(),
+            instance_bind_group_layout,
+            generation: 0,
+        }
+    }
+
+    pub fn upload_instances(&mut self, queue: &wgpu::Queue) {
+        for mesh_type in self.mesh_types.iter_mut() {
+            if mesh_type.upload_instances(queue) {
+                self.generation += 1;
+            }
         }
     }
 
+    /// Changes whenever instances were added or changed on the GPU, so views rendered earlier
+    /// can be kept as long as it stays the same.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
     pub fn add_mesh_type(&mut self, mesh_type: MeshType) -> usize {
         self.mesh_types.push(mesh_type);
+        self.generation += 1;
 
         self.mesh_types.len() - 1
     }
 
-    pub fn create_mesh(&mut self, mesh_type_index: usize) -> usize {
-        let mut mesh_type = self.mesh_types.get_mut(mesh_type_index).unwrap();
+    pub fn create_mesh(&mut self, mesh_type_index: usize) -> Result<usize, InstancesFull> {
+        let mesh_type = self.mesh_types.get_mut(mesh_type_index).unwrap();
 
         mesh_type.create_mesh()
     }
@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod renderer;
 pub mod scene_base;
 pub mod geometry;
@@ -7,18 +8,30 @@ pub mod deferred_pass;
 pub mod shadow_passes;
 pub mod utils;
 pub mod composition_pass;
-pub mod command_queue;
+pub mod light_culling_pass;
+pub mod culling;
+pub mod draw_order;
+pub mod frame;
+pub mod render_graph;
+pub mod shaders;
+pub mod shader_cache;
 pub mod ssao_pass;
 pub mod material;
+#[cfg(any(test, feature = "headless"))]
+pub mod offscreen;
+#[cfg(test)]
+mod golden;
 
 use std::time::{Duration, Instant};
 use specs::prelude::*;
 
-use self::{command_queue::{CommandQueue, RenderMeshCommand}, composition_pass::CompositionPass, deferred_pass::DeferredPass, lights::LightsResources, meshes::MeshResources, renderer::{Renderer, RendererEvent}, scene_base::SceneBaseResources};
-use crate::renderer::shadow_passes::{ShadowPasses, RenderShadowMeshCommand, RenderShadowBatch};
+use self::{composition_pass::CompositionPass, deferred_pass::DeferredPass, lights::LightsResources, meshes::MeshResources, renderer::{Renderer, RendererEvent}, scene_base::SceneBaseResources};
+use crate::renderer::light_culling_pass::LightCullingPass;
+use crate::renderer::shadow_passes::ShadowPasses;
 use crate::renderer::ssao_pass::SSAOPass;
 use crate::renderer::material::MaterialResources;
-use crate::renderer::command_queue::RenderBatch;
+use crate::renderer::shader_cache::ShaderCache;
+use crate::renderer::culling::VisibleInstances;
 
 pub struct DeltaTimer {
     d: Duration,
@@ -45,19 +58,51 @@ impl DeltaTimer {
 
 pub fn setup_rendering(world: &mut World, window: std::sync::Arc<winit::window::Window>) -> Renderer {
 
-    let window_size = window.inner_size();
+    let (mut renderer, device, queue) = futures::executor::block_on(Renderer::new(window));
 
-    let (renderer, device, queue) = futures::executor::block_on(Renderer::new(window));
+    insert_rendering_resources(world, &mut renderer, device, queue);
 
-    let mesh_resources = MeshResources::new();
+    renderer
+}
+
+/// Like [`setup_rendering`], but renders into an offscreen texture instead of a window.
+#[cfg(any(test, feature = "headless"))]
+pub fn setup_headless_rendering(
+    world: &mut World,
+    width: u32,
+    height: u32,
+    force_fallback_adapter: bool,
+) -> Result<Renderer, wgpu::RequestAdapterError> {
+
+    let (mut renderer, device, queue) = futures::executor::block_on(Renderer::new_headless(width, height, force_fallback_adapter))?;
+
+    insert_rendering_resources(world, &mut renderer, device, queue);
+
+    Ok(renderer)
+}
+
+/// Creates the shared GPU resources and registers the passes in the renderer's render graph.
+fn insert_rendering_resources(world: &mut World, renderer: &mut Renderer, device: wgpu::Device, queue: wgpu::Queue) {
+
+    let mesh_resources = MeshResources::new(&device);
     let lights_resources = LightsResources::new(&device);
     let scene_base_resources = SceneBaseResources::new(&device);
-    let material_resources = MaterialResources::new(&device, 20);
+    let material_resources = MaterialResources::new(&device);
+    let visible_instances = VisibleInstances::default();
+
+    let pipelines_start = Instant::now();
+    let shader_cache = ShaderCache::new(&device, &renderer.adapter.get_info());
 
-    let deferred_pass = DeferredPass::new(&device, &material_resources, &scene_base_resources, window_size.width, window_size.height);
-    let shadow_passes = ShadowPasses::new(&device, &mesh_resources, window_size.width, window_size.height);
-    let ssao_pass = SSAOPass::new(&device, &queue, &deferred_pass, &scene_base_resources, window_size.width, window_size.height);
-    let composition_pass = CompositionPass::new(&device, &queue, &deferred_pass, &shadow_passes, &ssao_pass, &lights_resources, &scene_base_resources);
+    let output_format = renderer.output_format();
+    let render_graph = &mut renderer.render_graph;
+    render_graph.add_node(DeferredPass::new(&device, &mesh_resources, &material_resources, &scene_base_resources, &shader_cache, &visible_instances));
+    render_graph.add_node(SSAOPass::new(&device, &queue, &scene_base_resources, &shader_cache));
+    render_graph.add_node(ShadowPasses::new(&device, &mesh_resources, &shader_cache));
+    render_graph.add_node(LightCullingPass::new(&device, &lights_resources, &scene_base_resources, &shader_cache));
+    render_graph.add_node(CompositionPass::new(&device, &lights_resources, &scene_base_resources, output_format, &shader_cache));
+
+    shader_cache.save_pipeline_cache();
+    log::info!("Built all pipelines in {:?}", pipelines_start.elapsed());
 
     world.insert(device);
     world.insert(queue);
@@ -66,14 +111,7 @@ pub fn setup_rendering(world: &mut World, window: std::sync::Arc<winit::window::
     world.insert(lights_resources);
     world.insert(scene_base_resources);
     world.insert(material_resources);
-
-    world.insert(CommandQueue::<RenderMeshCommand, RenderBatch>::new());
-    world.insert(CommandQueue::<RenderShadowMeshCommand, RenderShadowBatch>::new());
-
-    world.insert(deferred_pass);
-    world.insert(composition_pass);
-    world.insert(shadow_passes);
-    world.insert(ssao_pass);
+    world.insert(visible_instances);
 
     world.insert(RendererEvent::None);
 
@@ -81,6 +119,4 @@ pub fn setup_rendering(world: &mut World, window: std::sync::Arc<winit::window::
         Duration::from_millis(0),
         Instant::now()
     ));
-
-    renderer
 }
\ No newline at end of file
@@ -0,0 +1,360 @@
+//! Rendering without a window: an offscreen render target and the read-back of textures
+//! into images. Compiled with the `headless` feature, which `cells --headless` and other
+//! harnesses on machines without a display use, and for the golden image tests.
+
+use std::path::Path;
+
+/// A render target that lives in a plain texture instead of a window surface.
+/// The composition pass renders into it when running headless and the result can be
+/// read back to the CPU.
+pub struct OffscreenTarget {
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    pub format: wgpu::TextureFormat,
+}
+
+impl OffscreenTarget {
+    pub fn new(device: &wgpu::Device, width: u32, height: u32, format: wgpu::TextureFormat) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Offscreen Target"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        OffscreenTarget {
+            texture,
+            view,
+            format,
+        }
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        *self = OffscreenTarget::new(device, width, height, self.format);
+    }
+
+    /// Copies the current contents back to the CPU. Rows are tightly packed.
+    pub fn read_pixels(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<u8> {
+        read_texture(device, queue, &self.texture, wgpu::TextureAspect::All)
+    }
+}
+
+/// Copies mip level 0 of a 2D texture into a mappable buffer and blocks until the data
+/// is available on the CPU. The texture needs `COPY_SRC` usage. Rows are returned tightly
+/// packed, i.e. without the padding wgpu requires for buffer copies.
+pub fn read_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+    aspect: wgpu::TextureAspect,
+) -> Vec<u8> {
+    let width = texture.width();
+    let height = texture.height();
+    let bytes_per_pixel = texture
+        .format()
+        .block_copy_size(Some(aspect))
+        .expect("Texture format cannot be copied to a buffer");
+
+    let unpadded_bytes_per_row = width * bytes_per_pixel;
+    let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    let padded_bytes_per_row = unpadded_bytes_per_row.div_ceil(align) * align;
+
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Texture Readback Buffer"),
+        size: (padded_bytes_per_row * height) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder =
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+
+    encoder.copy_texture_to_buffer(
+        wgpu::TexelCopyTextureInfo {
+            texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+            aspect,
+        },
+        wgpu::TexelCopyBufferInfo {
+            buffer: &buffer,
+            layout: wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(padded_bytes_per_row),
+                rows_per_image: Some(height),
+            },
+        },
+        wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+    );
+
+    queue.submit(std::iter::once(encoder.finish()));
+
+    let slice = buffer.slice(..);
+    slice.map_async(wgpu::MapMode::Read, |result| {
+        result.expect("Could not map readback buffer");
+    });
+    device
+        .poll(wgpu::PollType::Wait)
+        .expect("Device lost while reading back texture");
+
+    let mut pixels = Vec::with_capacity((unpadded_bytes_per_row * height) as usize);
+    {
+        let data = slice.get_mapped_range();
+        for row in data.chunks(padded_bytes_per_row as usize) {
+            pixels.extend_from_slice(&row[..unpadded_bytes_per_row as usize]);
+        }
+    }
+    buffer.unmap();
+
+    pixels
+}
+
+/// An 8-bit RGBA image read back from the GPU.
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub rgba: Vec<u8>,
+}
+
+impl Image {
+    /// Converts texels read back from the GPU into 8-bit RGBA. Float formats are mapped
+    /// from `range` to [0, 255], so e.g. view-space positions can be made visible.
+    pub fn from_texels(
+        width: u32,
+        height: u32,
+        format: wgpu::TextureFormat,
+        texels: &[u8],
+        range: (f32, f32),
+    ) -> Self {
+        let to_u8 = |value: f32| {
+            let t = (value - range.0) / (range.1 - range.0);
+            (t.clamp(0.0, 1.0) * 255.0).round() as u8
+        };
+
+        let rgba = match format {
+            wgpu::TextureFormat::Rgba8Unorm | wgpu::TextureFormat::Rgba8UnormSrgb => {
+                texels.to_vec()
+            }
+            wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb => texels
+                .chunks_exact(4)
+                .flat_map(|bgra| [bgra[2], bgra[1], bgra[0], bgra[3]])
+                .collect(),
+            wgpu::TextureFormat::Rgba16Float => texels
+                .chunks_exact(8)
+                .flat_map(|texel| {
+                    let channel =
+                        |i: usize| f16_to_f32(u16::from_le_bytes([texel[2 * i], texel[2 * i + 1]]));
+                    [to_u8(channel(0)), to_u8(channel(1)), to_u8(channel(2)), 255]
+                })
+                .collect(),
+            wgpu::TextureFormat::Rgba32Float => texels
+                .chunks_exact(16)
+                .flat_map(|texel| {
+                    let channel = |i: usize| {
+                        f32::from_le_bytes([texel[4 * i], texel[4 * i + 1], texel[4 * i + 2], texel[4 * i + 3]])
+                    };
+                    [to_u8(channel(0)), to_u8(channel(1)), to_u8(channel(2)), 255]
+                })
+                .collect(),
+            wgpu::TextureFormat::R16Float => texels
+                .chunks_exact(2)
+                .flat_map(|texel| {
+                    let value = to_u8(f16_to_f32(u16::from_le_bytes([texel[0], texel[1]])));
+                    [value, value, value, 255]
+                })
+                .collect(),
+            wgpu::TextureFormat::R32Float | wgpu::TextureFormat::Depth32Float => texels
+                .chunks_exact(4)
+                .flat_map(|texel| {
+                    let value = to_u8(f32::from_le_bytes([texel[0], texel[1], texel[2], texel[3]]));
+                    [value, value, value, 255]
+                })
+                .collect(),
+            other => panic!("No golden image conversion for {:?}", other),
+        };
+
+        Image {
+            width,
+            height,
+            rgba,
+        }
+    }
+
+    pub fn write(&self, path: &Path) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let file = std::io::BufWriter::new(std::fs::File::create(path).unwrap());
+        let mut encoder = png::Encoder::new(file, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&self.rgba).unwrap();
+    }
+}
+
+/// Reads a texture back and converts it, see [`Image::from_texels`].
+pub fn read_image(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+    range: (f32, f32),
+) -> Image {
+    let texels = if texture.format().is_depth_stencil_format() {
+        read_depth(device, queue, texture)
+    } else {
+        read_texture(device, queue, texture, wgpu::TextureAspect::All)
+    };
+
+    Image::from_texels(texture.width(), texture.height(), texture.format(), &texels, range)
+}
+
+const DEPTH_READBACK_SHADER: &str = r#"
+@group(0) @binding(0) var depth: texture_2d_array<f32>;
+@group(0) @binding(1) var<storage, read_write> texels: array<f32>;
+
+@compute @workgroup_size(8, 8)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = textureDimensions(depth);
+    if (any(id.xy >= size)) {
+        return;
+    }
+    texels[id.y * size.x + id.x] = textureLoad(depth, id.xy, 0, 0).r;
+}
+"#;
+
+/// Reads the first layer of a `Depth32Float` texture as tightly packed floats. Not every
+/// backend can copy depth textures to buffers, so a compute shader loads the depths into
+/// a storage buffer, which is then read back.
+fn read_depth(device: &wgpu::Device, queue: &wgpu::Queue, texture: &wgpu::Texture) -> Vec<u8> {
+    let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("Depth Readback"),
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2Array,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+    });
+
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Depth Readback"),
+        bind_group_layouts: &[&bind_group_layout],
+        push_constant_ranges: &[],
+    });
+
+    let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("Depth Readback"),
+        source: wgpu::ShaderSource::Wgsl(DEPTH_READBACK_SHADER.into()),
+    });
+
+    let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some("Depth Readback"),
+        layout: Some(&pipeline_layout),
+        module: &module,
+        entry_point: Some("main"),
+        compilation_options: Default::default(),
+        cache: None,
+    });
+
+    let size = (texture.width() * texture.height()) as u64 * std::mem::size_of::<f32>() as u64;
+
+    let texels = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Depth Readback"),
+        size,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    });
+
+    let readback = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Depth Readback"),
+        size,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let view = texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2Array),
+        aspect: wgpu::TextureAspect::DepthOnly,
+        ..Default::default()
+    });
+
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Depth Readback"),
+        layout: &bind_group_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: texels.as_entire_binding(),
+            },
+        ],
+    });
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    {
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor::default());
+        compute_pass.set_pipeline(&pipeline);
+        compute_pass.set_bind_group(0, &bind_group, &[]);
+        compute_pass.dispatch_workgroups(texture.width().div_ceil(8), texture.height().div_ceil(8), 1);
+    }
+    encoder.copy_buffer_to_buffer(&texels, 0, &readback, 0, size);
+    queue.submit(std::iter::once(encoder.finish()));
+
+    let slice = readback.slice(..);
+    slice.map_async(wgpu::MapMode::Read, |result| {
+        result.expect("Could not map readback buffer");
+    });
+    device
+        .poll(wgpu::PollType::Wait)
+        .expect("Device lost while reading back depth texture");
+
+    let data = slice.get_mapped_range().to_vec();
+    readback.unmap();
+
+    data
+}
+
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let mantissa = (bits & 0x3ff) as f32;
+
+    match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        31 if mantissa == 0.0 => sign * f32::INFINITY,
+        31 => f32::NAN,
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
@@ -0,0 +1,766 @@
+//! A small render graph. Nodes declare the named textures and buffers they create, read
+//! and write; the graph derives the execution order from that, drops nodes which don't
+//! contribute to the output, and allocates the textures, letting textures whose lifetimes
+//! don't overlap share the same allocation.
+//!
+//! wgpu inserts the actual barriers itself from the usages recorded in the command encoder.
+//! The graph only has to order the nodes so every read happens after the write it depends
+//! on; the resulting transitions are logged at debug level when the graph is compiled.
+
+use std::collections::{HashMap, HashSet};
+
+use super::frame::FrameContext;
+use super::lights::LightsResources;
+use super::material::MaterialResources;
+use super::meshes::MeshResources;
+use super::scene_base::SceneBaseResources;
+
+/// The texture the final frame is written to. It is not allocated by the graph, the
+/// renderer provides it every frame (the surface texture or the offscreen target).
+pub const OUTPUT: &str = "output";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextureSize {
+    /// Follows the size of the output.
+    Screen,
+    Fixed { width: u32, height: u32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TextureDesc {
+    pub format: wgpu::TextureFormat,
+    pub size: TextureSize,
+    /// More than one layer makes it a 2D array texture.
+    pub layers: u32,
+}
+
+impl TextureDesc {
+    pub fn screen(format: wgpu::TextureFormat) -> Self {
+        TextureDesc {
+            format,
+            size: TextureSize::Screen,
+            layers: 1,
+        }
+    }
+
+    /// A texture array of a fixed size, which does not follow resizes of the output.
+    pub fn array(format: wgpu::TextureFormat, width: u32, height: u32, layers: u32) -> Self {
+        TextureDesc {
+            format,
+            size: TextureSize::Fixed { width, height },
+            layers,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BufferDesc {
+    pub size: wgpu::BufferAddress,
+    pub usage: wgpu::BufferUsages,
+}
+
+/// The scene data every node can read. Fetched from the world by the renderer.
+pub struct RenderInputs<'a> {
+    pub scene_base: &'a SceneBaseResources,
+    pub meshes: &'a MeshResources,
+    pub materials: &'a MaterialResources,
+    pub lights: &'a LightsResources,
+}
+
+pub trait RenderNode {
+    /// Used in logs and panic messages.
+    fn name(&self) -> &str;
+
+    /// Declares every graph resource the node creates, reads or writes.
+    fn declare(&self, builder: &mut NodeBuilder);
+
+    /// Called whenever the graph (re)allocated its resources: before the first frame and
+    /// after resizes. Bind groups referencing graph resources are created here.
+    fn prepare(&mut self, _device: &wgpu::Device, _resources: &GraphResources) {}
+
+    /// Called before every frame. Nodes rebuild their pipelines here when shader files changed,
+    /// see [`super::shaders::ShaderWatcher`].
+    fn reload_shaders(&mut self, _device: &wgpu::Device) {}
+
+    fn run(&mut self, frame: &mut FrameContext, resources: &GraphResources, inputs: &RenderInputs);
+}
+
+struct Access {
+    name: String,
+    write: bool,
+    /// Empty for buffers, their usage is fixed by the [`BufferDesc`].
+    texture_usage: wgpu::TextureUsages,
+}
+
+/// Collects the declarations of one node.
+#[derive(Default)]
+pub struct NodeBuilder {
+    screen_size: (u32, u32),
+    textures: Vec<(String, TextureDesc)>,
+    persistent_textures: Vec<String>,
+    buffers: Vec<(String, BufferDesc)>,
+    accesses: Vec<Access>,
+}
+
+impl NodeBuilder {
+    /// The size of the output, for buffers which depend on it.
+    pub fn screen_size(&self) -> (u32, u32) {
+        self.screen_size
+    }
+
+    /// Creates a texture the node renders into. Its contents are undefined at the start
+    /// of the node, since the allocation may be shared with other textures; clear it.
+    pub fn create_texture(&mut self, name: &str, desc: TextureDesc) {
+        self.textures.push((name.to_string(), desc));
+        self.write_texture(name);
+    }
+
+    /// Creates a texture which keeps its contents from one frame to the next, as it never shares
+    /// its allocation. They are lost when the graph reallocates its resources, see
+    /// [`RenderNode::prepare`].
+    pub fn create_persistent_texture(&mut self, name: &str, desc: TextureDesc) {
+        self.persistent_textures.push(name.to_string());
+        self.create_texture(name, desc);
+    }
+
+    /// Renders into a texture created by another node (or into [`OUTPUT`]).
+    pub fn write_texture(&mut self, name: &str) {
+        self.accesses.push(Access {
+            name: name.to_string(),
+            write: true,
+            texture_usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        });
+    }
+
+    pub fn read_texture(&mut self, name: &str) {
+        self.accesses.push(Access {
+            name: name.to_string(),
+            write: false,
+            texture_usage: wgpu::TextureUsages::TEXTURE_BINDING,
+        });
+    }
+
+    /// Creates a buffer the node writes. Buffers are never shared, and survive resizes as long
+    /// as their description stays the same.
+    pub fn create_buffer(&mut self, name: &str, desc: BufferDesc) {
+        self.buffers.push((name.to_string(), desc));
+        self.accesses.push(Access {
+            name: name.to_string(),
+            write: true,
+            texture_usage: wgpu::TextureUsages::empty(),
+        });
+    }
+
+    pub fn read_buffer(&mut self, name: &str) {
+        self.accesses.push(Access {
+            name: name.to_string(),
+            write: false,
+            texture_usage: wgpu::TextureUsages::empty(),
+        });
+    }
+}
+
+struct GraphTexture {
+    /// Only read back when rendering headless, see [`RenderGraph::texture`].
+    #[cfg_attr(not(any(test, feature = "headless")), allow(dead_code))]
+    texture: wgpu::Texture,
+    /// Covers all layers.
+    view: wgpu::TextureView,
+    /// One view per layer, to render into the layers of arrays separately.
+    layer_views: Vec<wgpu::TextureView>,
+}
+
+/// The resources allocated by the graph, handed to the nodes.
+pub struct GraphResources {
+    textures: HashMap<String, GraphTexture>,
+    buffers: HashMap<String, (BufferDesc, wgpu::Buffer)>,
+    output: Option<wgpu::TextureView>,
+    screen_size: (u32, u32),
+}
+
+impl GraphResources {
+    /// The size of the output the resources were allocated for.
+    pub fn screen_size(&self) -> (u32, u32) {
+        self.screen_size
+    }
+
+    pub fn texture_view(&self, name: &str) -> &wgpu::TextureView {
+        if name == OUTPUT {
+            return self.output.as_ref().expect("The output is only available while rendering");
+        }
+
+        match self.textures.get(name) {
+            Some(texture) => &texture.view,
+            None => panic!("Unknown render graph texture: {}", name),
+        }
+    }
+
+    /// A single layer of a texture array, as a 2D view.
+    pub fn texture_layer_view(&self, name: &str, layer: u32) -> &wgpu::TextureView {
+        match self.textures.get(name).and_then(|texture| texture.layer_views.get(layer as usize)) {
+            Some(view) => view,
+            None => panic!("Unknown render graph texture layer: {} {}", name, layer),
+        }
+    }
+
+    pub fn buffer(&self, name: &str) -> &wgpu::Buffer {
+        match self.buffers.get(name) {
+            Some((_, buffer)) => buffer,
+            None => panic!("Unknown render graph buffer: {}", name),
+        }
+    }
+}
+
+/// A texture allocated by the graph, and the names of the textures sharing it.
+struct Allocation<'a> {
+    desc: TextureDesc,
+    usage: wgpu::TextureUsages,
+    last_use: usize,
+    names: Vec<&'a str>,
+}
+
+pub struct RenderGraph {
+    nodes: Vec<Box<dyn RenderNode>>,
+    width: u32,
+    height: u32,
+    retained: HashSet<String>,
+    /// The nodes in execution order. `None` until the graph is compiled, and again after
+    /// anything changes.
+    order: Option<Vec<usize>>,
+    resources: GraphResources,
+}
+
+impl RenderGraph {
+    pub fn new(width: u32, height: u32) -> Self {
+        RenderGraph {
+            nodes: Vec::new(),
+            width,
+            height,
+            retained: HashSet::new(),
+            order: None,
+            resources: GraphResources {
+                textures: HashMap::new(),
+                buffers: HashMap::new(),
+                output: None,
+                screen_size: (width, height),
+            },
+        }
+    }
+
+    /// Nodes may be added in any order; if two nodes don't depend on each other, the one
+    /// added first runs first.
+    pub fn add_node(&mut self, node: impl RenderNode + 'static) {
+        self.nodes.push(Box::new(node));
+        self.order = None;
+    }
+
+    /// Keeps a texture intact until the end of the frame and allows copying from it, so it
+    /// can be read back after rendering, e.g. for debugging or tests.
+    #[cfg(any(test, feature = "headless"))]
+    pub fn retain(&mut self, name: &str) {
+        self.retained.insert(name.to_string());
+        self.order = None;
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.order = None;
+    }
+
+    /// A texture of the last compiled graph, see [`RenderGraph::retain`].
+    #[cfg(any(test, feature = "headless"))]
+    pub fn texture(&self, name: &str) -> Option<&wgpu::Texture> {
+        self.resources.textures.get(name).map(|texture| &texture.texture)
+    }
+
+    pub fn execute(&mut self, frame: &mut FrameContext, output: &wgpu::TextureView, inputs: &RenderInputs) {
+        if self.order.is_none() {
+            self.compile(frame.device);
+        }
+
+        self.resources.output = Some(output.clone());
+
+        for &node in self.order.as_ref().unwrap() {
+            self.nodes[node].reload_shaders(frame.device);
+            self.nodes[node].run(frame, &self.resources, inputs);
+        }
+
+        self.resources.output = None;
+    }
+
+    fn compile(&mut self, device: &wgpu::Device) {
+        let declarations = self.declare();
+
+        let texture_descs = Self::collect_creators(&declarations, |declaration| &declaration.textures);
+        let buffer_descs = Self::collect_creators(&declarations, |declaration| &declaration.buffers);
+
+        let edges = self.dependencies(&declarations, &texture_descs, &buffer_descs);
+        let order = self.sort(&edges);
+        let order = self.cull(order, &edges, &declarations);
+
+        // Lifetimes of the textures, as positions in the execution order:
+        let mut lifetimes: HashMap<&str, (usize, usize, wgpu::TextureUsages)> = HashMap::new();
+        for (position, &node) in order.iter().enumerate() {
+            for access in declarations[node].accesses.iter() {
+                if texture_descs.contains_key(access.name.as_str()) {
+                    let lifetime = lifetimes
+                        .entry(access.name.as_str())
+                        .or_insert((position, position, wgpu::TextureUsages::empty()));
+                    lifetime.1 = position;
+                    lifetime.2 |= access.texture_usage;
+                }
+            }
+        }
+
+        let persistent: HashSet<&str> = declarations
+            .iter()
+            .flat_map(|declaration| declaration.persistent_textures.iter().map(String::as_str))
+            .collect();
+
+        for (name, lifetime) in lifetimes.iter_mut() {
+            if self.retained.contains(*name) {
+                lifetime.1 = order.len();
+                lifetime.2 |= wgpu::TextureUsages::COPY_SRC;
+            }
+
+            // Living through the whole frame, nothing can share the allocation:
+            if persistent.contains(*name) {
+                lifetime.0 = 0;
+                lifetime.1 = order.len();
+            }
+        }
+
+        let mut by_first_use: Vec<(&str, (usize, usize, wgpu::TextureUsages))> = lifetimes
+            .iter()
+            .map(|(name, lifetime)| (*name, *lifetime))
+            .collect();
+        by_first_use.sort_by_key(|(name, (first, _, _))| (*first, *name));
+
+        let allocations = Self::share_allocations(&by_first_use, &texture_descs);
+
+        let mut textures = HashMap::new();
+        for allocation in allocations.iter() {
+            let label = allocation.names.join(" / ");
+            let (width, height) = match allocation.desc.size {
+                TextureSize::Screen => (self.width, self.height),
+                TextureSize::Fixed { width, height } => (width, height),
+            };
+
+            let texture = device.create_texture(&wgpu::TextureDescriptor {
+                label: Some(&label),
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: allocation.desc.layers,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: allocation.desc.format,
+                usage: allocation.usage,
+                view_formats: &[],
+            });
+
+            let dimension = if allocation.desc.layers > 1 {
+                wgpu::TextureViewDimension::D2Array
+            } else {
+                wgpu::TextureViewDimension::D2
+            };
+
+            for name in allocation.names.iter() {
+                let view = texture.create_view(&wgpu::TextureViewDescriptor {
+                    label: Some(name),
+                    dimension: Some(dimension),
+                    ..Default::default()
+                });
+
+                let layer_views = (0..allocation.desc.layers)
+                    .map(|layer| texture.create_view(&wgpu::TextureViewDescriptor {
+                        label: Some(name),
+                        dimension: Some(wgpu::TextureViewDimension::D2),
+                        base_array_layer: layer,
+                        array_layer_count: Some(1),
+                        ..Default::default()
+                    }))
+                    .collect();
+
+                textures.insert(name.to_string(), GraphTexture {
+                    texture: texture.clone(),
+                    view,
+                    layer_views,
+                });
+            }
+        }
+
+        log::debug!(
+            "Render graph allocates {} textures for {} transient textures",
+            allocations.len(),
+            lifetimes.len()
+        );
+
+        // Buffers keep their contents as long as their description doesn't change:
+        let mut buffers = HashMap::new();
+        for (name, (_, desc)) in buffer_descs.iter() {
+            let buffer = match self.resources.buffers.remove(*name) {
+                Some((old_desc, buffer)) if old_desc == *desc => buffer,
+                _ => device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some(name),
+                    size: desc.size,
+                    usage: desc.usage,
+                    mapped_at_creation: false,
+                }),
+            };
+            buffers.insert(name.to_string(), (*desc, buffer));
+        }
+
+        // The usage transitions wgpu will perform between the nodes:
+        let mut last_usage: HashMap<&str, wgpu::TextureUsages> = HashMap::new();
+        for &node in order.iter() {
+            for access in declarations[node].accesses.iter().filter(|access| !access.texture_usage.is_empty()) {
+                if let Some(from) = last_usage.insert(access.name.as_str(), access.texture_usage)
+                    && from != access.texture_usage
+                {
+                    log::debug!(
+                        "Render graph: {} {:?} -> {:?} before {}",
+                        access.name,
+                        from,
+                        access.texture_usage,
+                        self.nodes[node].name()
+                    );
+                }
+            }
+        }
+
+        self.resources = GraphResources {
+            textures,
+            buffers,
+            output: None,
+            screen_size: (self.width, self.height),
+        };
+
+        for &node in order.iter() {
+            self.nodes[node].prepare(device, &self.resources);
+        }
+
+        self.order = Some(order);
+    }
+
+    fn declare(&self) -> Vec<NodeBuilder> {
+        self.nodes
+            .iter()
+            .map(|node| {
+                let mut builder = NodeBuilder {
+                    screen_size: (self.width, self.height),
+                    ..Default::default()
+                };
+                node.declare(&mut builder);
+                builder
+            })
+            .collect()
+    }
+
+    /// The edges between the nodes: every resource is created before it is used anywhere
+    /// else, and written before it is read. Nodes writing a resource they didn't create run in
+    /// the order they were added, so their writes don't depend on the rest of the graph.
+    fn dependencies(
+        &self,
+        declarations: &[NodeBuilder],
+        texture_descs: &HashMap<&str, (usize, TextureDesc)>,
+        buffer_descs: &HashMap<&str, (usize, BufferDesc)>,
+    ) -> Vec<HashSet<usize>> {
+        let mut edges: Vec<HashSet<usize>> = vec![HashSet::new(); self.nodes.len()];
+
+        let mut accessors: HashMap<&str, Vec<(usize, &Access)>> = HashMap::new();
+        for (node, declaration) in declarations.iter().enumerate() {
+            for access in declaration.accesses.iter() {
+                accessors.entry(access.name.as_str()).or_default().push((node, access));
+            }
+        }
+
+        for (name, accesses) in accessors.iter() {
+            let creator = texture_descs.get(*name)
+                .map(|(creator, _)| *creator)
+                .or_else(|| buffer_descs.get(*name).map(|(creator, _)| *creator));
+
+            if creator.is_none() && *name != OUTPUT {
+                panic!("Render graph resource {} is used by {} but never created", name, self.nodes[accesses[0].0].name());
+            }
+
+            let mut previous_writer: Option<usize> = None;
+
+            for &(node, access) in accesses.iter() {
+                if let Some(creator) = creator
+                    && node != creator
+                {
+                    edges[creator].insert(node);
+                }
+
+                if access.write && Some(node) != creator {
+                    for &(reader, other) in accesses.iter() {
+                        if !other.write && reader != node && Some(reader) != creator {
+                            edges[node].insert(reader);
+                        }
+                    }
+
+                    if let Some(previous_writer) = previous_writer.filter(|&previous| previous != node) {
+                        edges[previous_writer].insert(node);
+                    }
+                    previous_writer = Some(node);
+                }
+            }
+        }
+
+        edges
+    }
+
+    /// Textures with the same description share an allocation if one is no longer used
+    /// when the other one is created. `by_first_use` holds the lifetimes of the textures,
+    /// as positions in the execution order, sorted by their first use.
+    fn share_allocations<'a>(
+        by_first_use: &[(&'a str, (usize, usize, wgpu::TextureUsages))],
+        texture_descs: &HashMap<&str, (usize, TextureDesc)>,
+    ) -> Vec<Allocation<'a>> {
+        let mut allocations: Vec<Allocation> = Vec::new();
+
+        for &(name, (first, last, usage)) in by_first_use {
+            let desc = texture_descs[name].1;
+
+            match allocations.iter_mut().find(|allocation| allocation.desc == desc && allocation.last_use < first) {
+                Some(allocation) => {
+                    allocation.usage |= usage;
+                    allocation.last_use = last;
+                    allocation.names.push(name);
+                }
+                None => allocations.push(Allocation {
+                    desc,
+                    usage,
+                    last_use: last,
+                    names: vec![name],
+                }),
+            }
+        }
+
+        allocations
+    }
+
+    /// Maps every created resource to its creator and description.
+    fn collect_creators<'a, D: Copy + 'a>(
+        declarations: &'a [NodeBuilder],
+        created: impl Fn(&'a NodeBuilder) -> &'a Vec<(String, D)>,
+    ) -> HashMap<&'a str, (usize, D)> {
+        let mut creators = HashMap::new();
+
+        for (node, declaration) in declarations.iter().enumerate() {
+            for (name, desc) in created(declaration).iter() {
+                if creators.insert(name.as_str(), (node, *desc)).is_some() {
+                    panic!("Render graph resource {} is created twice", name);
+                }
+            }
+        }
+
+        creators
+    }
+
+    /// Topological order of the nodes. Nodes without dependencies between them keep the
+    /// order in which they were added.
+    fn sort(&self, edges: &[HashSet<usize>]) -> Vec<usize> {
+        let mut incoming = vec![0; self.nodes.len()];
+        for targets in edges.iter() {
+            for &target in targets.iter() {
+                incoming[target] += 1;
+            }
+        }
+
+        let mut order = Vec::with_capacity(self.nodes.len());
+        while let Some(node) = (0..self.nodes.len()).find(|&node| incoming[node] == 0) {
+            incoming[node] = usize::MAX;
+            order.push(node);
+
+            for &target in edges[node].iter() {
+                incoming[target] -= 1;
+            }
+        }
+
+        if order.len() < self.nodes.len() {
+            let cycle: Vec<&str> = (0..self.nodes.len())
+                .filter(|node| !order.contains(node))
+                .map(|node| self.nodes[node].name())
+                .collect();
+            panic!("Render graph has a cycle between {:?}", cycle);
+        }
+
+        order
+    }
+
+    /// Drops the nodes which neither write the output nor a retained texture, directly or
+    /// through the nodes reading their results.
+    fn cull(&self, order: Vec<usize>, edges: &[HashSet<usize>], declarations: &[NodeBuilder]) -> Vec<usize> {
+        let mut needed = vec![false; self.nodes.len()];
+
+        for &node in order.iter().rev() {
+            needed[node] = edges[node].iter().any(|&target| needed[target])
+                || declarations[node].accesses.iter().any(|access| {
+                    access.write && (access.name == OUTPUT || self.retained.contains(&access.name))
+                });
+
+            if !needed[node] {
+                log::debug!("Render graph: skipping {}, nothing uses its results", self.nodes[node].name());
+            }
+        }
+
+        order.into_iter().filter(|&node| needed[node]).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+
+    type Declare = fn(&mut NodeBuilder);
+
+    struct TestNode {
+        name: &'static str,
+        declare: Declare,
+    }
+
+    impl RenderNode for TestNode {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn declare(&self, builder: &mut NodeBuilder) {
+            (self.declare)(builder)
+        }
+
+        fn run(&mut self, _frame: &mut FrameContext, _resources: &GraphResources, _inputs: &RenderInputs) {
+            unreachable!("Test nodes are only compiled")
+        }
+    }
+
+    fn graph(nodes: &[(&'static str, Declare)]) -> RenderGraph {
+        let mut graph = RenderGraph::new(64, 64);
+        for &(name, declare) in nodes {
+            graph.add_node(TestNode { name, declare });
+        }
+        graph
+    }
+
+    /// The names of the nodes in execution order, without the culled ones.
+    fn order(graph: &RenderGraph) -> Vec<&str> {
+        let declarations = graph.declare();
+        let texture_descs = RenderGraph::collect_creators(&declarations, |declaration| &declaration.textures);
+        let buffer_descs = RenderGraph::collect_creators(&declarations, |declaration| &declaration.buffers);
+
+        let edges = graph.dependencies(&declarations, &texture_descs, &buffer_descs);
+        let order = graph.sort(&edges);
+
+        graph.cull(order, &edges, &declarations)
+            .into_iter()
+            .map(|node| graph.nodes[node].name())
+            .collect()
+    }
+
+    #[test]
+    fn nodes_run_after_the_nodes_they_depend_on() {
+        let graph = graph(&[
+            ("composition", |builder| {
+                builder.read_texture("gbuffer");
+                builder.read_texture("ssao");
+                builder.write_texture(OUTPUT);
+            }),
+            ("ssao", |builder| {
+                builder.read_texture("gbuffer");
+                builder.create_texture("ssao", TextureDesc::screen(FORMAT));
+            }),
+            ("gbuffer", |builder| builder.create_texture("gbuffer", TextureDesc::screen(FORMAT))),
+        ]);
+
+        assert_eq!(order(&graph), ["gbuffer", "ssao", "composition"]);
+    }
+
+    #[test]
+    fn writers_run_in_the_order_they_were_added() {
+        let graph = graph(&[
+            ("clear", |builder| builder.create_texture("color", TextureDesc::screen(FORMAT))),
+            ("first", |builder| {
+                builder.read_texture("mask");
+                builder.write_texture("color");
+            }),
+            ("second", |builder| builder.write_texture("color")),
+            ("mask", |builder| builder.create_texture("mask", TextureDesc::screen(FORMAT))),
+            ("present", |builder| {
+                builder.read_texture("color");
+                builder.write_texture(OUTPUT);
+            }),
+        ]);
+
+        assert_eq!(order(&graph), ["clear", "mask", "first", "second", "present"]);
+    }
+
+    #[test]
+    fn nodes_without_used_results_are_culled() {
+        let mut graph = graph(&[
+            ("unused", |builder| builder.create_texture("debug", TextureDesc::screen(FORMAT))),
+            ("input", |builder| builder.create_texture("input", TextureDesc::screen(FORMAT))),
+            ("present", |builder| {
+                builder.read_texture("input");
+                builder.write_texture(OUTPUT);
+            }),
+        ]);
+
+        assert_eq!(order(&graph), ["input", "present"]);
+
+        graph.retain("debug");
+        assert_eq!(order(&graph), ["unused", "input", "present"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Render graph has a cycle")]
+    fn cycles_are_rejected() {
+        let graph = graph(&[
+            ("a", |builder| {
+                builder.read_texture("b");
+                builder.create_texture("a", TextureDesc::screen(FORMAT));
+            }),
+            ("b", |builder| {
+                builder.read_texture("a");
+                builder.create_texture("b", TextureDesc::screen(FORMAT));
+                builder.write_texture(OUTPUT);
+            }),
+        ]);
+
+        order(&graph);
+    }
+
+    #[test]
+    fn textures_share_allocations_when_their_lifetimes_are_disjoint() {
+        let screen = TextureDesc::screen(FORMAT);
+        let texture_descs: HashMap<&str, (usize, TextureDesc)> = HashMap::from([
+            ("a", (0, screen)),
+            ("b", (1, screen)),
+            ("c", (2, screen)),
+            ("d", (3, TextureDesc::screen(wgpu::TextureFormat::R16Float))),
+            ("e", (4, screen)),
+        ]);
+
+        let usage = wgpu::TextureUsages::RENDER_ATTACHMENT;
+        let by_first_use = [
+            ("a", (0, 1, usage)),
+            // Created while a is still in use:
+            ("b", (1, 2, usage)),
+            ("c", (2, 4, usage)),
+            // Would fit after b, but has another format:
+            ("d", (3, 4, usage)),
+            ("e", (4, 4, wgpu::TextureUsages::TEXTURE_BINDING)),
+        ];
+
+        let allocations = RenderGraph::share_allocations(&by_first_use, &texture_descs);
+        let names: Vec<&[&str]> = allocations.iter().map(|allocation| allocation.names.as_slice()).collect();
+
+        assert_eq!(names, [&["a", "c"][..], &["b", "e"][..], &["d"][..]]);
+        assert_eq!(allocations[1].usage, usage | wgpu::TextureUsages::TEXTURE_BINDING);
+    }
+}
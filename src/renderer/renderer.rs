@@ -1,21 +1,17 @@
-use imgui::MouseCursor;
 use specs::prelude::*;
 
 use super::{
     DeltaTimer,
-    command_queue::{CommandQueue, RenderMeshCommand},
-    composition_pass::CompositionPass,
-    deferred_pass::DeferredPass,
+    frame::{FrameContext, GpuTimer},
     lights::LightsResources,
     meshes::MeshResources,
+    render_graph::{RenderGraph, RenderInputs},
     scene_base::SceneBaseResources,
 };
-use crate::renderer::command_queue::RenderBatch;
 use crate::renderer::material::MaterialResources;
-use crate::renderer::shadow_passes::{RenderShadowBatch, RenderShadowMeshCommand, ShadowPasses};
-use crate::renderer::ssao_pass::SSAOPass;
+#[cfg(any(test, feature = "headless"))]
+use crate::renderer::offscreen::OffscreenTarget;
 use std::time::Instant;
-use wgpu::naga::SwitchValue::Default;
 
 pub enum RendererEvent {
     Render,
@@ -23,14 +19,29 @@ pub enum RendererEvent {
     None,
 }
 
+/// Where the final composited frame ends up.
+pub enum RenderTarget {
+    Surface {
+        surface: wgpu::Surface<'static>,
+        config: wgpu::SurfaceConfiguration,
+    },
+    /// Used when rendering headless, see [`Renderer::new_headless`].
+    #[cfg(any(test, feature = "headless"))]
+    Offscreen(OffscreenTarget),
+}
+
+const GPU_TIMINGS_LOG_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
 pub struct Renderer {
-    pub instance: wgpu::Instance,
     pub size: winit::dpi::PhysicalSize<u32>,
-    pub surface: wgpu::Surface<'static>,
-    pub config: wgpu::SurfaceConfiguration,
+    pub target: RenderTarget,
     pub adapter: wgpu::Adapter,
-    pub last_cursor: Option<MouseCursor>,
+    /// All passes. They are registered by [`super::setup_rendering`].
+    pub render_graph: RenderGraph,
     is_surface_ready: bool,
+    /// Only available if the adapter supports timestamp queries.
+    gpu_timer: Option<GpuTimer>,
+    timings_logged: Instant,
 }
 
 impl Renderer {
@@ -53,33 +64,7 @@ impl Renderer {
             .await
             .unwrap();
 
-        log::info!("Limits: {:?}", adapter.limits());
-
-        // Todo: Specify required features
-        let adapter_features = adapter.features();
-        log::info!("Features: {:?}", adapter_features);
-
-        // Todo: Specify limits
-        let required_limits = wgpu::Limits {
-            max_bind_groups: 6,
-            ..wgpu::Limits::default()
-        };
-        log::info!("Limits: {:#?}", required_limits);
-
-        // todo: Add back tracing
-        let trace_dir = std::env::var("WGPU_TRACE");
-
-        let (device, queue) = adapter
-            .request_device(&wgpu::DeviceDescriptor {
-                label: None,
-                required_features: wgpu::Features::DEPTH_CLIP_CONTROL,
-                required_limits,
-                //trace: wgpu::Trace::Directory(trace_dir.ok().as_ref().map(std::path::Path::new)),
-                trace: wgpu::Trace::Off,
-                memory_hints: wgpu::MemoryHints::default(),
-            })
-            .await
-            .unwrap();
+        let (device, queue) = Self::request_device(&adapter).await;
 
         let surface_caps = surface.get_capabilities(&adapter);
         let surface_format = surface_caps.formats.iter()
@@ -100,27 +85,172 @@ impl Renderer {
 
         (
             Renderer {
-                instance,
                 size,
-                surface,
-                config,
+                target: RenderTarget::Surface { surface, config },
                 adapter,
-                last_cursor: None,
+                render_graph: RenderGraph::new(size.width, size.height),
                 is_surface_ready: false,
+                gpu_timer: GpuTimer::new(&device, &queue),
+                timings_logged: Instant::now(),
             },
             device,
             queue,
         )
     }
 
-    fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>, device: &wgpu::Device) {
+    /// Creates a renderer without a window. Frames are rendered into an [`OffscreenTarget`]
+    /// and can be read back with [`Renderer::read_frame`]. Backends can be overridden with
+    /// the usual `WGPU_BACKEND` environment variable; set `force_fallback_adapter` to get a
+    /// software adapter on machines without a GPU. The fallback includes the GL backend, since
+    /// that is where Mesa's llvmpipe is usually found.
+    #[cfg(any(test, feature = "headless"))]
+    pub async fn new_headless(
+        width: u32,
+        height: u32,
+        force_fallback_adapter: bool,
+    ) -> Result<(Self, wgpu::Device, wgpu::Queue), wgpu::RequestAdapterError> {
+        let backends = if force_fallback_adapter {
+            wgpu::Backends::PRIMARY | wgpu::Backends::GL
+        } else {
+            wgpu::Backends::PRIMARY
+        };
+
+        let instance = wgpu::Instance::new(
+            &wgpu::InstanceDescriptor {
+                backends,
+                ..std::default::Default::default()
+            }
+            .with_env(),
+        );
+
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                force_fallback_adapter,
+                compatible_surface: None,
+            })
+            .await?;
+
+        log::info!("Headless adapter: {:?}", adapter.get_info());
+
+        let (device, queue) = Self::request_device(&adapter).await;
+
+        let target = OffscreenTarget::new(&device, width, height, wgpu::TextureFormat::Bgra8UnormSrgb);
+
+        Ok((
+            Renderer {
+                size: winit::dpi::PhysicalSize::new(width, height),
+                target: RenderTarget::Offscreen(target),
+                adapter,
+                render_graph: RenderGraph::new(width, height),
+                is_surface_ready: true,
+                gpu_timer: GpuTimer::new(&device, &queue),
+                timings_logged: Instant::now(),
+            },
+            device,
+            queue,
+        ))
+    }
+
+    async fn request_device(adapter: &wgpu::Adapter) -> (wgpu::Device, wgpu::Queue) {
+        log::info!("Limits: {:?}", adapter.limits());
+
+        // Todo: Specify required features
+        let adapter_features = adapter.features();
+        log::info!("Features: {:?}", adapter_features);
+
+        // Todo: Specify limits
+        let required_limits = wgpu::Limits {
+            max_bind_groups: 6,
+            ..wgpu::Limits::default()
+        };
+        log::info!("Limits: {:#?}", required_limits);
+
+        // todo: Add back tracing, e.g. into the directory given by WGPU_TRACE
+
+        adapter
+            .request_device(&wgpu::DeviceDescriptor {
+                label: None,
+                // Software adapters don't always support unclipped depth or timestamp queries, and
+                // only Vulkan has pipeline caches. The passes, the GPU timer and the shader cache
+                // check for them.
+                required_features: adapter_features
+                    & (wgpu::Features::DEPTH_CLIP_CONTROL
+                        | wgpu::Features::TIMESTAMP_QUERY
+                        | wgpu::Features::PIPELINE_CACHE
+                        | wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES),
+                required_limits,
+                //trace: wgpu::Trace::Directory(trace_dir.ok().as_ref().map(std::path::Path::new)),
+                trace: wgpu::Trace::Off,
+                memory_hints: wgpu::MemoryHints::default(),
+            })
+            .await
+            .unwrap()
+    }
+
+    /// The texture format the final frame is written in.
+    pub fn output_format(&self) -> wgpu::TextureFormat {
+        match &self.target {
+            RenderTarget::Surface { config, .. } => config.format,
+            #[cfg(any(test, feature = "headless"))]
+            RenderTarget::Offscreen(target) => target.format,
+        }
+    }
+
+    /// Reads the last rendered frame back to the CPU. Only available for offscreen targets,
+    /// surfaces can't be read from.
+    #[cfg(any(test, feature = "headless"))]
+    pub fn read_frame(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Option<Vec<u8>> {
+        match &self.target {
+            RenderTarget::Offscreen(target) => Some(target.read_pixels(device, queue)),
+            RenderTarget::Surface { .. } => None,
+        }
+    }
+
+    /// GPU time in milliseconds of every pass of a recent frame. Empty if timestamp
+    /// queries are not supported.
+    pub fn gpu_timings(&self) -> &[(String, f64)] {
+        self.gpu_timer.as_ref().map_or(&[], |timer| timer.timings())
+    }
+
+    /// Logs the GPU timings at most once per second, every frame would flood the log.
+    fn log_gpu_timings(&mut self) {
+        if self.timings_logged.elapsed() < GPU_TIMINGS_LOG_INTERVAL || self.gpu_timings().is_empty() {
+            return;
+        }
+
+        let total: f64 = self.gpu_timings().iter().map(|(_, time)| time).sum();
+        let passes: Vec<String> = self.gpu_timings()
+            .iter()
+            .map(|(label, time)| format!("{} {:.3}", label, time))
+            .collect();
+        log::debug!("GPU time {:.3} ms: {}", total, passes.join(", "));
+
+        self.timings_logged = Instant::now();
+    }
+
+    fn resize(
+        &mut self,
+        new_size: winit::dpi::PhysicalSize<u32>,
+        device: &wgpu::Device,
+    ) {
         if new_size.width > 0 && new_size.height > 0 {
-            self.config.width = new_size.width;
-            self.config.height = new_size.height;
-            self.surface.configure(&device, &self.config);
+            self.size = new_size;
+            match &mut self.target {
+                RenderTarget::Surface { surface, config } => {
+                    config.width = new_size.width;
+                    config.height = new_size.height;
+                    surface.configure(device, config);
+                }
+                #[cfg(any(test, feature = "headless"))]
+                RenderTarget::Offscreen(target) => {
+                    target.resize(device, new_size.width, new_size.height);
+                }
+            }
             self.is_surface_ready = true;
+
+            self.render_graph.resize(new_size.width, new_size.height);
         }
-        // todo: Call resize on passes
     }
 }
 
@@ -132,15 +262,9 @@ impl<'a> System<'a> for Renderer {
         WriteExpect<'a, DeltaTimer>,
         ReadExpect<'a, wgpu::Device>,
         ReadExpect<'a, wgpu::Queue>,
-        ReadExpect<'a, DeferredPass>,
         ReadExpect<'a, SceneBaseResources>,
         ReadExpect<'a, MeshResources>,
         ReadExpect<'a, MaterialResources>,
-        WriteExpect<'a, CommandQueue<RenderMeshCommand, RenderBatch>>,
-        ReadExpect<'a, ShadowPasses>,
-        WriteExpect<'a, CommandQueue<RenderShadowMeshCommand, RenderShadowBatch>>,
-        ReadExpect<'a, SSAOPass>,
-        ReadExpect<'a, CompositionPass>,
         ReadExpect<'a, LightsResources>,
     );
 
@@ -150,45 +274,48 @@ impl<'a> System<'a> for Renderer {
             mut d_t,
             device,
             queue,
-            deferred_pass,
             scene_base_resources,
             mesh_resources,
             material_resources,
-            mut mesh_commands,
-            shadow_passes,
-            mut shadow_mesh_commands,
-            ssao_pass,
-            composition_pass,
             lights_resources,
         ) = data;
 
         match *event {
-            RendererEvent::Render => {
-                if self.is_surface_ready {
-                    deferred_pass.render(
-                        &device,
-                        &queue,
-                        &scene_base_resources,
-                        &mesh_resources,
-                        &material_resources,
-                        &mut mesh_commands,
-                    );
-                    ssao_pass.render(&device, &queue, &scene_base_resources, &deferred_pass);
-                    shadow_passes.render(&device, &queue, &mesh_resources, &mut shadow_mesh_commands);
-                    composition_pass.render(
-                        &device,
-                        &queue,
-                        &mut self.surface,
-                        &scene_base_resources,
-                        &lights_resources,
-                        &deferred_pass,
-                        &shadow_passes,
-                        &ssao_pass,
-                    );
-
-                    *event = RendererEvent::None;
-                    *d_t = DeltaTimer::new(Instant::now() - d_t.get_last_render(), Instant::now());
+            RendererEvent::Render if self.is_surface_ready => {
+                let inputs = RenderInputs {
+                    scene_base: &scene_base_resources,
+                    meshes: &mesh_resources,
+                    materials: &material_resources,
+                    lights: &lights_resources,
+                };
+
+                let mut frame = FrameContext::new(&device, &queue, self.gpu_timer.as_mut());
+
+                let (screen_frame, output) = match &self.target {
+                    RenderTarget::Surface { surface, .. } => {
+                        let screen_frame = surface
+                            .get_current_texture()
+                            .expect("Could not acquire texture for rendering");
+                        let output = screen_frame
+                            .texture
+                            .create_view(&wgpu::TextureViewDescriptor::default());
+                        (Some(screen_frame), output)
+                    }
+                    #[cfg(any(test, feature = "headless"))]
+                    RenderTarget::Offscreen(target) => (None, target.view.clone()),
+                };
+
+                self.render_graph.execute(&mut frame, &output, &inputs);
+
+                frame.submit();
+                self.log_gpu_timings();
+
+                if let Some(screen_frame) = screen_frame {
+                    screen_frame.present();
                 }
+
+                *event = RendererEvent::None;
+                *d_t = DeltaTimer::new(Instant::now() - d_t.get_last_render(), Instant::now());
             }
             RendererEvent::Resize(size) => {
                 self.resize(size, &device);
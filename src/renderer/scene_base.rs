@@ -8,25 +8,17 @@ pub struct GpuSceneBase {
     pub view_matrix: cgmath::Matrix4<f32>,
     pub projection_matrix: cgmath::Matrix4<f32>,
     pub window_size: cgmath::Vector2<f32>,
-    pub padding: cgmath::Vector2<f32>,
+    /// Distance of the camera's near and far plane.
+    pub z_range: cgmath::Vector2<f32>,
 }
 
 impl GpuSceneBase {
-    pub fn new(view_matrix: cgmath::Matrix4<f32>, projection_matrix: cgmath::Matrix4<f32>, window_size: cgmath::Vector2<f32>) -> Self {
-        GpuSceneBase {
-            view_matrix,
-            projection_matrix,
-            window_size,
-            padding: cgmath::Vector2::new(0.0, 0.0)
-        }
-    }
-
     pub fn empty() -> Self {
         GpuSceneBase {
             view_matrix: cgmath::Matrix4::zero(),
             projection_matrix: cgmath::Matrix4::zero(),
             window_size: cgmath::Vector2::new(0.0, 0.0),
-            padding: cgmath::Vector2::new(0.0, 0.0)
+            z_range: cgmath::Vector2::new(0.0, 0.0)
         }
     }
 }
@@ -37,7 +29,9 @@ unsafe impl bytemuck::Zeroable for GpuSceneBase {}
 pub struct SceneBaseResources {
     pub bind_group: wgpu::BindGroup,
     pub bind_group_layout: wgpu::BindGroupLayout,
-    pub buffer: wgpu::Buffer
+    pub buffer: wgpu::Buffer,
+    /// The values last written to `buffer`.
+    pub scene_base: GpuSceneBase
 }
 
 impl SceneBaseResources {
@@ -50,7 +44,7 @@ impl SceneBaseResources {
             entries: &[
                 wgpu::BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Uniform,
                         has_dynamic_offset: false,
@@ -82,10 +76,12 @@ impl SceneBaseResources {
             bind_group_layout,
             bind_group,
             buffer,
+            scene_base: GpuSceneBase::empty(),
         }
     }
 
-    pub fn update_scene_base(&self, queue: &wgpu::Queue, scene_base: GpuSceneBase) {
+    pub fn update_scene_base(&mut self, queue: &wgpu::Queue, scene_base: GpuSceneBase) {
         queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[scene_base]));
+        self.scene_base = scene_base;
     }
 }
@@ -0,0 +1,140 @@
+//! Caches that shorten startup across runs: the SPIR-V compiled from the GLSL shaders, keyed
+//! by a hash of the preprocessed source, and the driver's pipeline cache on backends that
+//! support one. Both are stored in `target/cache`, or in `CELLS_CACHE_DIR` if it is set.
+
+use std::path::{Path, PathBuf};
+
+pub fn cache_dir() -> PathBuf {
+    match std::env::var_os("CELLS_CACHE_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(env!("CARGO_MANIFEST_DIR")).join("target/cache"),
+    }
+}
+
+/// 64-bit FNV-1a. Unlike `DefaultHasher` it is stable across Rust versions, so cache entries
+/// survive toolchain updates.
+#[cfg(feature = "glsl")]
+pub fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Writes to a temporary file first, so an interrupted run never leaves a truncated entry.
+fn write_file(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, data)?;
+    std::fs::rename(&temporary, path)
+}
+
+/// Shared by all [`super::shaders::ShaderWatcher`]s. Cloning is cheap.
+#[derive(Clone)]
+pub struct ShaderCache {
+    #[cfg(feature = "glsl")]
+    spirv_dir: PathBuf,
+    pipeline_cache: Option<(wgpu::PipelineCache, PathBuf)>,
+}
+
+impl ShaderCache {
+    /// Loads the pipeline cache written by a previous run, if the device supports
+    /// `Features::PIPELINE_CACHE`.
+    pub fn new(device: &wgpu::Device, adapter_info: &wgpu::AdapterInfo) -> Self {
+        let dir = cache_dir();
+
+        let key = wgpu::util::pipeline_cache_key(adapter_info)
+            .filter(|_| device.features().contains(wgpu::Features::PIPELINE_CACHE));
+
+        let pipeline_cache = key.map(|key| {
+            let path = dir.join("pipelines").join(key);
+
+            let data = std::fs::read(&path).ok();
+            if data.is_none() {
+                log::info!("Pipeline cache miss, no cache at {}", path.display());
+            }
+
+            // Safety: the data was written by `save_pipeline_cache` for an adapter with the same
+            // cache key. With `fallback`, data the driver does not accept is ignored.
+            let cache = unsafe {
+                device.create_pipeline_cache(&wgpu::PipelineCacheDescriptor {
+                    label: Some("Pipeline Cache"),
+                    data: data.as_deref(),
+                    fallback: true,
+                })
+            };
+
+            (cache, path)
+        });
+
+        ShaderCache {
+            #[cfg(feature = "glsl")]
+            spirv_dir: dir.join("spirv"),
+            pipeline_cache,
+        }
+    }
+
+    /// Used as the `cache` of every pipeline descriptor.
+    pub fn pipeline_cache(&self) -> Option<&wgpu::PipelineCache> {
+        self.pipeline_cache.as_ref().map(|(cache, _)| cache)
+    }
+
+    /// Persists the pipeline cache. Called whenever pipelines were built.
+    pub fn save_pipeline_cache(&self) {
+        let Some((cache, path)) = self.pipeline_cache.as_ref() else {
+            return;
+        };
+
+        if let Some(data) = cache.get_data()
+            && let Err(error) = write_file(path, &data)
+        {
+            log::warn!("Could not write the pipeline cache to {}: {}", path.display(), error);
+        }
+    }
+
+    #[cfg(feature = "glsl")]
+    fn spirv_path(&self, name: &str, key: u64) -> PathBuf {
+        self.spirv_dir.join(format!("{}-{:016x}.spv", name, key))
+    }
+
+    /// Returns the SPIR-V cached for the shader file `name` under `key`.
+    #[cfg(feature = "glsl")]
+    pub fn load_spirv(&self, name: &str, key: u64) -> Option<Vec<u32>> {
+        match std::fs::read(self.spirv_path(name, key)) {
+            Ok(bytes) if bytes.len() % 4 == 0 => {
+                log::debug!("Shader cache hit for {}", name);
+                Some(bytes.chunks_exact(4).map(|word| u32::from_ne_bytes([word[0], word[1], word[2], word[3]])).collect())
+            }
+            _ => {
+                log::info!("Shader cache miss for {}", name);
+                None
+            }
+        }
+    }
+
+    /// Caches the SPIR-V of the shader file `name`, replacing entries of its previous versions.
+    #[cfg(feature = "glsl")]
+    pub fn store_spirv(&self, name: &str, key: u64, spirv: &[u32]) {
+        let path = self.spirv_path(name, key);
+
+        let prefix = format!("{}-", name);
+        let stale = std::fs::read_dir(&self.spirv_dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|entry| *entry != path)
+            .filter(|entry| entry.file_name().and_then(|file_name| file_name.to_str()).is_some_and(|file_name| file_name.starts_with(&prefix)));
+
+        for entry in stale {
+            log::info!("Invalidated cached {}", entry.display());
+            let _ = std::fs::remove_file(entry);
+        }
+
+        if let Err(error) = write_file(&path, bytemuck::cast_slice(spirv)) {
+            log::warn!("Could not cache {}: {}", path.display(), error);
+        }
+    }
+}
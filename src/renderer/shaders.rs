@@ -0,0 +1,297 @@
+//! Shaders are loaded from `src/assets` at runtime. By default the WGSL versions are used and
+//! validated with naga; with the `glsl` feature the GLSL versions are compiled with shaderc
+//! instead. The files are watched while the renderer runs: when one of them or a file it
+//! includes changes, the pipelines using it are rebuilt. If compiling or validating the new version fails, the error is logged
+//! and the last good pipeline stays in use.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::shader_cache::ShaderCache;
+
+/// How often the shader files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The directory shaders are loaded from. Can be overridden with `CELLS_ASSET_DIR`.
+pub fn asset_dir() -> PathBuf {
+    match std::env::var_os("CELLS_ASSET_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(env!("CARGO_MANIFEST_DIR")).join("src/assets"),
+    }
+}
+
+#[cfg(feature = "glsl")]
+const EXTENSION: &str = "glsl";
+#[cfg(not(feature = "glsl"))]
+const EXTENSION: &str = "wgsl";
+
+#[derive(Clone, Copy)]
+pub enum ShaderStage {
+    Vertex,
+    Fragment,
+    Compute,
+}
+
+/// Files a shader was built from, with their modification times at that point.
+type Dependencies = Vec<(PathBuf, Option<SystemTime>)>;
+
+fn modified_on_disk(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Reads a file in the asset directory and records it as a dependency, also if reading fails,
+/// so creating the file later triggers a reload.
+fn read_asset(name: &str, dependencies: &mut Dependencies) -> Result<String, String> {
+    let path = asset_dir().join(name);
+    dependencies.push((path.clone(), modified_on_disk(&path)));
+
+    std::fs::read_to_string(&path)
+        .map_err(|error| format!("Could not read {}: {}", path.display(), error))
+}
+
+/// Reads a WGSL file and replaces its `#include "file"` lines with the contents of that file,
+/// relative to the asset directory. Each file is included once, like with `#pragma once`.
+#[cfg(not(feature = "glsl"))]
+fn read_with_includes(name: &str, dependencies: &mut Dependencies) -> Result<String, String> {
+    let source = read_asset(name, dependencies)?;
+
+    let mut expanded = String::with_capacity(source.len());
+    for line in source.lines() {
+        match line.trim().strip_prefix("#include") {
+            Some(include) => {
+                let include = include.trim().trim_matches('"');
+                let path = asset_dir().join(include);
+                if !dependencies.iter().any(|(dependency, _)| *dependency == path) {
+                    expanded.push_str(&read_with_includes(include, dependencies)?);
+                }
+            }
+            None => {
+                expanded.push_str(line);
+                expanded.push('\n');
+            }
+        }
+    }
+
+    Ok(expanded)
+}
+
+/// A shader file in the asset directory. The name excludes the language extension, e.g.
+/// `deferred.vert` is loaded from `deferred.vert.wgsl`, or `deferred.vert.glsl` with the
+/// `glsl` feature. Shared snippets are pulled in with `#include "scene_base.wgsl"`.
+pub struct ShaderFile {
+    name: String,
+    stage: ShaderStage,
+    defines: Vec<(String, String)>,
+    dependencies: Dependencies,
+}
+
+impl ShaderFile {
+    pub fn new(name: &str, stage: ShaderStage) -> Self {
+        ShaderFile {
+            name: format!("{}.{}", name, EXTENSION),
+            stage,
+            defines: Vec::new(),
+            dependencies: Vec::new(),
+        }
+    }
+
+    /// Defines an integer constant shared with Rust, so it has a single source. In GLSL it
+    /// is a preprocessor macro; in WGSL a `const` declaration prepended to the source.
+    pub fn define_u32(mut self, name: &str, value: u32) -> Self {
+        self.defines.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Like [`ShaderFile::define_u32`] for floats. They are written with a decimal point
+    /// even if whole, so the shader doesn't take them for integers.
+    pub fn define_f32(mut self, name: &str, value: f32) -> Self {
+        self.defines.push((name.to_string(), format!("{:?}", value)));
+        self
+    }
+
+    /// Whether the file or one of its includes changed since the last compilation.
+    fn changed(&self) -> bool {
+        self.dependencies.iter().any(|(path, modified)| modified_on_disk(path) != *modified)
+    }
+
+    /// Compiled SPIR-V is cached on disk, keyed by a hash of the preprocessed source, which
+    /// covers includes and defines.
+    #[cfg(feature = "glsl")]
+    fn compile(&mut self, cache: &ShaderCache) -> Result<wgpu::ShaderSource<'static>, String> {
+        self.dependencies.clear();
+        let source = read_asset(&self.name, &mut self.dependencies)?;
+
+        let kind = match self.stage {
+            ShaderStage::Vertex => shaderc::ShaderKind::Vertex,
+            ShaderStage::Fragment => shaderc::ShaderKind::Fragment,
+            ShaderStage::Compute => shaderc::ShaderKind::Compute,
+        };
+
+        let includes = std::cell::RefCell::new(std::mem::take(&mut self.dependencies));
+
+        let mut options = shaderc::CompileOptions::new().unwrap();
+        for (name, value) in self.defines.iter() {
+            options.add_macro_definition(name, Some(value));
+        }
+        options.set_include_callback(|name, _, _, _| {
+            read_asset(name, &mut includes.borrow_mut()).map(|content| shaderc::ResolvedInclude {
+                resolved_name: name.to_string(),
+                content,
+            })
+        });
+
+        let compiler = shaderc::Compiler::new().unwrap();
+        let preprocessed = compiler.preprocess(&source, &self.name, "main", Some(&options));
+
+        drop(options);
+        self.dependencies = includes.into_inner();
+
+        let preprocessed = preprocessed.map_err(|error| error.to_string())?.as_text();
+
+        let key = super::shader_cache::hash(&[&[self.stage as u8], preprocessed.as_bytes()].concat());
+        if let Some(spirv) = cache.load_spirv(&self.name, key) {
+            return Ok(wgpu::ShaderSource::SpirV(std::borrow::Cow::Owned(spirv)));
+        }
+
+        let spirv = compiler
+            .compile_into_spirv(&preprocessed, kind, &self.name, "main", None)
+            .map_err(|error| error.to_string())?
+            .as_binary()
+            .to_vec();
+
+        cache.store_spirv(&self.name, key, &spirv);
+
+        Ok(wgpu::ShaderSource::SpirV(std::borrow::Cow::Owned(spirv)))
+    }
+
+    /// Parses and validates the WGSL source with naga before handing it to wgpu, so errors
+    /// name the file on disk. Line numbers refer to the source with defines and includes
+    /// expanded.
+    #[cfg(not(feature = "glsl"))]
+    fn compile(&mut self, _cache: &ShaderCache) -> Result<wgpu::ShaderSource<'static>, String> {
+        use wgpu::naga;
+
+        self.dependencies.clear();
+
+        let mut source: String = self.defines
+            .iter()
+            .map(|(name, value)| format!("const {} = {};\n", name, value))
+            .collect();
+        source.push_str(&read_with_includes(&self.name, &mut self.dependencies)?);
+
+        let path = asset_dir().join(&self.name);
+        let module = naga::front::wgsl::parse_str(&source)
+            .map_err(|error| error.emit_to_string_with_path(&source, &path))?;
+
+        naga::valid::Validator::new(naga::valid::ValidationFlags::all(), naga::valid::Capabilities::all())
+            .validate(&module)
+            .map_err(|error| error.emit_to_string_with_path(&source, &path))?;
+
+        let stage = match self.stage {
+            ShaderStage::Vertex => naga::ShaderStage::Vertex,
+            ShaderStage::Fragment => naga::ShaderStage::Fragment,
+            ShaderStage::Compute => naga::ShaderStage::Compute,
+        };
+        if !module.entry_points.iter().any(|entry_point| entry_point.name == "main" && entry_point.stage == stage) {
+            return Err(format!("{} has no {:?} entry point named `main`", path.display(), stage));
+        }
+
+        Ok(wgpu::ShaderSource::Wgsl(std::borrow::Cow::Owned(source)))
+    }
+}
+
+/// What a pipeline is created from: one module per [`ShaderFile`], in the same order, and the
+/// pipeline cache to pass in the pipeline descriptor.
+pub struct CompiledShaders<'a> {
+    pub modules: &'a [wgpu::ShaderModule],
+    pub cache: Option<&'a wgpu::PipelineCache>,
+}
+
+/// The shader files of one pipeline.
+pub struct ShaderWatcher {
+    label: String,
+    files: Vec<ShaderFile>,
+    cache: ShaderCache,
+    last_poll: Instant,
+}
+
+impl ShaderWatcher {
+    pub fn new(label: &str, files: Vec<ShaderFile>, cache: &ShaderCache) -> Self {
+        ShaderWatcher {
+            label: label.to_string(),
+            files,
+            cache: cache.clone(),
+            last_poll: Instant::now(),
+        }
+    }
+
+    /// Compiles the shaders and creates the pipeline at startup. Panics on errors, since
+    /// there is no previous pipeline to fall back to.
+    pub fn build<P>(&mut self, device: &wgpu::Device, create: impl FnOnce(&CompiledShaders) -> P) -> P {
+        match self.try_build(device, create) {
+            Ok(pipeline) => pipeline,
+            Err(error) => panic!("Could not build {}:\n{}", self.label, error),
+        }
+    }
+
+    /// Rebuilds the pipeline if one of the shader files changed. On errors the previous
+    /// pipeline is kept; the file is compiled again on its next change.
+    pub fn reload<P>(&mut self, device: &wgpu::Device, pipeline: &mut P, create: impl FnOnce(&CompiledShaders) -> P) {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return;
+        }
+        self.last_poll = Instant::now();
+
+        if !self.files.iter().any(|file| file.changed()) {
+            return;
+        }
+
+        match self.try_build(device, create) {
+            Ok(new_pipeline) => {
+                *pipeline = new_pipeline;
+                self.cache.save_pipeline_cache();
+                log::info!("Reloaded shaders of {}", self.label);
+            }
+            Err(error) => log::error!("Could not reload shaders of {}, keeping the last good version:\n{}", self.label, error),
+        }
+    }
+
+    fn try_build<P>(&mut self, device: &wgpu::Device, create: impl FnOnce(&CompiledShaders) -> P) -> Result<P, String> {
+        let mut sources = Vec::with_capacity(self.files.len());
+        let mut errors = Vec::new();
+        for file in self.files.iter_mut() {
+            match file.compile(&self.cache) {
+                Ok(source) => sources.push(source),
+                Err(error) => errors.push(error),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
+
+        // Without an error scope, wgpu would treat an invalid shader as a fatal error:
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+
+        let modules: Vec<wgpu::ShaderModule> = self.files
+            .iter()
+            .zip(sources)
+            .map(|(file, source)| {
+                device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                    label: Some(&file.name),
+                    source,
+                })
+            })
+            .collect();
+
+        let pipeline = create(&CompiledShaders {
+            modules: &modules,
+            cache: self.cache.pipeline_cache(),
+        });
+
+        match futures::executor::block_on(device.pop_error_scope()) {
+            Some(error) => Err(error.to_string()),
+            None => Ok(pipeline),
+        }
+    }
+}
@@ -1,250 +1,169 @@
-use super::{lights::LightsResources, meshes::MeshResources, utils::GpuVector3};
-use crate::renderer::command_queue::{CommandQueue, Batch, Command};
-use crate::renderer::utils::{GpuMatrix4BGA, GpuVector3BGA};
-use std::ops::Not;
-
-use wgpu::util::*;
-
-#[derive(Clone)]
-pub struct RenderShadowMeshCommand {
-    pub mesh_type: u8,
-    pub object_index: u16,
-    pub order: u16,
-}
+use super::{culling::InstanceCulling, frame::FrameContext, lights::{DirectionalLightData, LightShadowData, LightsResources, ShadowFilter, ShadowSettings, MAX_SHADOWED_POINT_LIGHTS, POINT_SHADOW_LAYERS, POINT_SHADOW_MAP_SIZE, SHADOW_SLOTS, SHADOW_CASCADES, SHADOW_MAP_SIZE}, meshes::MeshResources, utils::GpuVector3};
+use super::render_graph::{BufferDesc, GraphResources, NodeBuilder, RenderInputs, RenderNode, TextureDesc};
+use super::shader_cache::ShaderCache;
+use super::shaders::{CompiledShaders, ShaderFile, ShaderStage, ShaderWatcher};
 
-impl Command for RenderShadowMeshCommand {
-    fn is_compatible(&self, other: &Self) -> bool {
-        self.mesh_type == other.mesh_type
-    }
-}
-
-impl From<u32> for RenderShadowMeshCommand {
-    fn from(other: u32) -> Self {
-        RenderShadowMeshCommand {
-            mesh_type: ((0b1111_1110_0000_0000_0000_0000_0000_0000 & other) >> 25) as u8,
-            object_index: ((0b0000_0000_0000_1111_1111_1100_0000_0000 & other) >> 10) as u16,
-            order: (0b0000_0000_0000_0000_0000_0011_1111_1111 & other) as u16,
-        }
-    }
-}
-
-impl Into<u32> for RenderShadowMeshCommand {
-    fn into(self) -> u32 {
-        (self.mesh_type as u32) << 25 |
-        (self.object_index as u32) << 10 |
-        (self.order as u32)
-    }
+use cgmath::SquareMatrix;
+use wgpu::util::DeviceExt;
+
+/// A depth texture array with one layer per cascade.
+pub const SHADOW_MAP: &str = "shadow_map";
+/// Holds a [`GpuLightView`].
+pub const SHADOW_LIGHT: &str = "shadow_light";
+/// A depth texture array with six layers, one per cube face, for every shadow slot of
+/// the point lights, followed by a layer for every shadow slot of the spot lights.
+pub const POINT_SHADOW_MAP: &str = "point_shadow_map";
+/// Holds a [`GpuPointShadows`].
+pub const POINT_SHADOW_VIEWS: &str = "point_shadow_views";
+
+/// The views rendered per frame: the cascades, followed by the layers of the point shadow map.
+const SHADOW_VIEWS: usize = SHADOW_CASCADES + POINT_SHADOW_LAYERS;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct GpuCascade {
+    pub view_matrix: cgmath::Matrix4<f32>,
+    /// x is the view space depth at which the next cascade takes over, y the penumbra scale.
+    pub split: [f32; 4],
 }
 
-pub struct RenderShadowBatch {
-    pub object_indices: Vec<u32>,
-    pub mesh_type: u16,
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct GpuLightView {
+    pub cascades: [GpuCascade; SHADOW_CASCADES],
+    /// World space direction the light travels in. w is 1 if there is a directional light.
+    pub direction: [f32; 4],
+    /// rgb is the color, a the intensity.
+    pub color: [f32; 4],
+    /// x is the constant depth bias, y the slope scaled one.
+    pub bias: [f32; 4],
+    /// x is the [`ShadowFilter`], y the number of taps, z the filter radius in texels and w
+    /// the light size.
+    pub filtering: [f32; 4],
 }
 
-impl Batch<RenderShadowMeshCommand> for RenderShadowBatch {
-    fn new(first_command: RenderShadowMeshCommand) -> Self {
-        RenderShadowBatch {
-            object_indices: Vec::new(),
-            mesh_type: first_command.mesh_type as u16,
-        }
-    }
-
-    fn add_command(&mut self, command: &RenderShadowMeshCommand) -> bool {
-        if command.mesh_type == self.mesh_type as u8 {
-            if !self.object_indices.contains(&(command.object_index as u32)) {
-                self.object_indices.push(command.object_index as u32);
-            }
-            true
-        } else {
-            false
+impl GpuLightView {
+    pub fn new(light: Option<&DirectionalLightData>, settings: &ShadowSettings) -> Self {
+        let filtering = [
+            match settings.filter {
+                ShadowFilter::Hardware => 0.0,
+                ShadowFilter::Poisson => 1.0,
+                ShadowFilter::Pcss => 2.0,
+            },
+            settings.quality.taps() as f32,
+            settings.filter_radius,
+            settings.light_size,
+        ];
+
+        match light {
+            Some(light) => GpuLightView {
+                cascades: light.cascades.map(|cascade| GpuCascade {
+                    view_matrix: cascade.view_projection,
+                    split: [cascade.split_depth, cascade.penumbra_scale, 0.0, 0.0],
+                }),
+                direction: [light.direction.x, light.direction.y, light.direction.z, 1.0],
+                color: [light.color.x, light.color.y, light.color.z, light.intensity],
+                bias: [light.depth_bias, light.slope_bias, 0.0, 0.0],
+                filtering,
+            },
+            None => GpuLightView {
+                cascades: [GpuCascade {
+                    view_matrix: cgmath::Matrix4::identity(),
+                    split: [f32::MAX, 0.0, 0.0, 0.0],
+                }; SHADOW_CASCADES],
+                direction: [0.0, -1.0, 0.0, 0.0],
+                color: [0.0; 4],
+                bias: [0.0; 4],
+                filtering,
+            },
         }
     }
 }
 
-use cgmath::SquareMatrix;
-use crate::renderer::material::MaterialResources;
+unsafe impl bytemuck::Pod for GpuCascade {}
+unsafe impl bytemuck::Zeroable for GpuCascade {}
+unsafe impl bytemuck::Pod for GpuLightView {}
+unsafe impl bytemuck::Zeroable for GpuLightView {}
 
-#[repr(C, align(256))]
+/// The views of the layers of the point shadow map: the cube faces of the point light shadows
+/// and the spot light shadows, see [`LightsResources::shadow_slot_layer`]. Unused slots hold
+/// identity matrices.
+#[repr(C)]
 #[derive(Debug, Copy, Clone)]
-pub struct GpuLightView {
-    pub view_matrix: cgmath::Matrix4<f32>,
+pub struct GpuPointShadows {
+    pub face_view_matrices: [cgmath::Matrix4<f32>; POINT_SHADOW_LAYERS],
 }
 
-impl Default for GpuLightView {
-    fn default() -> Self {
-        let view_matrix = //cgmath::perspective(cgmath::Deg(45.0), 1.333334, 10.0, 20.0)//cgmath::ortho(-10.0, 10.0, -10.0, 10.0, 1.0, 100.0)
-            cgmath::ortho(-10.0, 10.0, -10.0, 10.0, 0.0, 25.0)
-            * cgmath::Matrix4::look_at(
-                cgmath::Point3::new(5.0, 15.0, -5.0),
-                cgmath::Point3::new(0.0, 0.0, 0.0),
-                cgmath::Vector3::unit_z()
-            );
+unsafe impl bytemuck::Pod for GpuPointShadows {}
+unsafe impl bytemuck::Zeroable for GpuPointShadows {}
 
-        GpuLightView {
-            view_matrix,
-        }
-    }
+/// The matrix the shadow vertex shader renders one layer with. Aligned to be bound with a
+/// dynamic offset.
+#[repr(C, align(256))]
+#[derive(Debug, Copy, Clone)]
+struct GpuShadowView {
+    view_matrix: cgmath::Matrix4<f32>,
 }
 
-unsafe impl bytemuck::Pod for GpuLightView {}
-unsafe impl bytemuck::Zeroable for GpuLightView {}
+unsafe impl bytemuck::Pod for GpuShadowView {}
+unsafe impl bytemuck::Zeroable for GpuShadowView {}
 
+/// Renders the shadow casters into one layer of the shadow map per cascade, and into the
+/// cube faces of the point lights casting shadows.
 pub struct ShadowPasses {
-    shadow_texture: wgpu::Texture,
-    pub shadow_texture_view: wgpu::TextureView,
-    pub shadow_sampler: wgpu::Sampler,
-    shadow_light_buffer: wgpu::Buffer,
-    pub shadow_light_bind_group: wgpu::BindGroup,
-    pub shadow_light_bind_group_layout: wgpu::BindGroupLayout,
-    pub shadow_result_bind_group: wgpu::BindGroup,
-    pub shadow_result_bind_group_layout: wgpu::BindGroupLayout,
+    shaders: ShaderWatcher,
+    pipeline_layout: wgpu::PipelineLayout,
+    views_buffer: wgpu::Buffer,
+    views_bind_group: wgpu::BindGroup,
     pipeline: wgpu::RenderPipeline,
+    /// One per cascade, since every cascade culls with its own matrix in the same frame.
+    culling: Vec<InstanceCulling>,
+    /// One per shadow slot, shared by its faces.
+    point_culling: Vec<InstanceCulling>,
+    /// What the point shadow map holds for every slot: the shadow and the
+    /// [`MeshResources::generation`] of the casters it was rendered with. Slots are only
+    /// rendered again when either changes.
+    rendered_point_shadows: [Option<(LightShadowData, u64)>; SHADOW_SLOTS],
 }
 
 impl ShadowPasses {
-    pub fn new(device: &wgpu::Device, mesh_resources: &MeshResources, window_width: u32, window_height: u32) -> Self {
-
-        let shadow_texture = device.create_texture(&wgpu::TextureDescriptor {
-            label: Some("Shadows Texture"),
-            format: wgpu::TextureFormat::Depth32Float,
-            dimension: wgpu::TextureDimension::D2,
-            sample_count: 1,
-            mip_level_count: 1,
-            size: wgpu::Extent3d {
-                width: window_width,
-                height: window_height,
-                depth_or_array_layers: 1
-            },
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
-            view_formats: &[],
-        });
-
-        let shadow_texture_view = shadow_texture.create_view(& wgpu::TextureViewDescriptor::default());
+    pub fn new(device: &wgpu::Device, mesh_resources: &MeshResources, shader_cache: &ShaderCache) -> Self {
 
-        let shadow_light_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Shadow Light Buffer"),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            contents: bytemuck::cast_slice(&[ GpuLightView::default() ])
-        });
-
-        let shadow_light_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        let views_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: None,
             entries: &[
                 wgpu::BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    visibility: wgpu::ShaderStages::VERTEX,
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None
+                        has_dynamic_offset: true,
+                        min_binding_size: wgpu::BufferSize::new(std::mem::size_of::<cgmath::Matrix4<f32>>() as u64)
                     },
                     count: None
                 },
             ]
         });
 
-        let shadow_light_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::Buffer(shadow_light_buffer.as_entire_buffer_binding())
-                },
-            ],
-            layout: &shadow_light_bind_group_layout
-        });
-
-        let shadow_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            label: Some("shadow"),
-            address_mode_u: wgpu::AddressMode::ClampToEdge,
-            address_mode_v: wgpu::AddressMode::ClampToEdge,
-            address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Linear,
-            min_filter: wgpu::FilterMode::Linear,
-            mipmap_filter: wgpu::FilterMode::Nearest,
-            compare: Some(wgpu::CompareFunction::Less),
-            ..Default::default()
-        });
-
-        let shadow_result_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: None,
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
-                    count: None
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Texture {
-                        sample_type: wgpu::TextureSampleType::Depth,
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                        multisampled: false,
-                    },
-                    count: None
-                },
-            ]
+        let views_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Shadow Views"),
+            contents: bytemuck::cast_slice(&[GpuShadowView { view_matrix: cgmath::Matrix4::identity() }; SHADOW_VIEWS]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
-        let shadow_result_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        let views_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: None,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: wgpu::BindingResource::Sampler(&shadow_sampler)
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: &views_buffer,
+                        offset: 0,
+                        size: wgpu::BufferSize::new(std::mem::size_of::<cgmath::Matrix4<f32>>() as u64),
+                    })
                 },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::TextureView(&shadow_texture_view)
-                }
             ],
-            layout: &shadow_result_bind_group_layout
-        });
-
-
-        // create vertex shader:
-
-        let mut compiler = shaderc::Compiler::new().unwrap();
-
-        let vs_code = "
-            #version 450
-
-            layout(location=0) in vec3 a_position;
-            layout(location=1) in vec4 a_model_matrix_1;
-            layout(location=2) in vec4 a_model_matrix_2;
-            layout(location=3) in vec4 a_model_matrix_3;
-            layout(location=4) in vec4 a_model_matrix_4;
-
-            layout(set=0, binding=0)
-            uniform SceneUniforms {
-                mat4 light_view_matrix;
-            };
-
-            layout(set=1, binding=0)
-            uniform ModelUniforms {
-                mat4 u_transform;
-            };
-
-            void main() {
-                mat4 a_model_matrix = mat4(a_model_matrix_1, a_model_matrix_2, a_model_matrix_3, a_model_matrix_4);
-                gl_Position = light_view_matrix * a_model_matrix * vec4(a_position, 1.0);
-            }
-        ".to_string();
-
-        let vs_spirv = compiler.compile_into_spirv(
-                &vs_code,
-                shaderc::ShaderKind::Vertex,
-                "shadow_pass.vert",
-                "main",
-                None,
-            )
-            .unwrap();
-
-        let vertex_shader_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-            label: Some("Shadow Pass Vertex Shader"),
-            source: wgpu::ShaderSource::SpirV(std::borrow::Cow::Borrowed(vs_spirv.as_binary())),
+            layout: &views_bind_group_layout
         });
 
         // Create the render pipeline
@@ -252,16 +171,51 @@ impl ShadowPasses {
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: None,
             bind_group_layouts: &[
-                &shadow_light_bind_group_layout,
+                &views_bind_group_layout,
+                &mesh_resources.instance_bind_group_layout,
             ],
             push_constant_ranges: &[],
         });
 
-        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        let mut shaders = ShaderWatcher::new("Shadow Pass", vec![
+            ShaderFile::new("shadow.vert", ShaderStage::Vertex),
+        ], shader_cache);
+
+        let pipeline = shaders.build(device, |compiled| Self::create_pipeline(device, &pipeline_layout, compiled));
+
+        ShadowPasses {
+            shaders,
+            pipeline_layout,
+            views_buffer,
+            views_bind_group,
+            pipeline,
+            culling: (0..SHADOW_CASCADES)
+                .map(|cascade| InstanceCulling::new(device, &format!("Shadow Culling {}", cascade), false, shader_cache))
+                .collect(),
+            point_culling: (0..SHADOW_SLOTS)
+                .map(|slot| {
+                    let label = if slot < MAX_SHADOWED_POINT_LIGHTS {
+                        format!("Point Shadow Culling {}", slot)
+                    } else {
+                        format!("Spot Shadow Culling {}", slot - MAX_SHADOWED_POINT_LIGHTS)
+                    };
+                    InstanceCulling::new(device, &label, false, shader_cache)
+                })
+                .collect(),
+            rendered_point_shadows: [None; SHADOW_SLOTS],
+        }
+    }
+
+    fn create_pipeline(
+        device: &wgpu::Device,
+        pipeline_layout: &wgpu::PipelineLayout,
+        compiled: &CompiledShaders,
+    ) -> wgpu::RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
             label: Some("Shadow Pipeline"),
-            layout: Some(&pipeline_layout),
+            layout: Some(pipeline_layout),
             vertex: wgpu::VertexState {
-                module: &vertex_shader_module,
+                module: &compiled.modules[0],
                 entry_point: Some("main"),
                 compilation_options: Default::default(),
                 buffers: &[
@@ -281,26 +235,11 @@ impl ShadowPasses {
                             wgpu::VertexAttribute {
                                 offset: 0,
                                 shader_location: 1,
-                                format: wgpu::VertexFormat::Float32x4
-                            },
-                            wgpu::VertexAttribute {
-                                offset: 16,
-                                shader_location: 2,
-                                format: wgpu::VertexFormat::Float32x4
-                            },
-                            wgpu::VertexAttribute {
-                                offset: 32,
-                                shader_location: 3,
-                                format: wgpu::VertexFormat::Float32x4
-                            },
-                            wgpu::VertexAttribute {
-                                offset: 48,
-                                shader_location: 4,
-                                format: wgpu::VertexFormat::Float32x4
+                                format: wgpu::VertexFormat::Uint32
                             },
                         ],
                         step_mode: wgpu::VertexStepMode::Instance,
-                        array_stride: 64,
+                        array_stride: (std::mem::size_of::<u32>()) as wgpu::BufferAddress,
                     },
                 ]
             },
@@ -318,11 +257,9 @@ impl ShadowPasses {
                 depth_write_enabled: true,
                 depth_compare: wgpu::CompareFunction::LessEqual,
                 stencil: Default::default(),
-                bias: wgpu::DepthBiasState {
-                    constant: 2,
-                    slope_scale: 2.0,
-                    clamp: 0.0
-                },
+                // No rasterizer bias: the composition pass applies the light's depth_bias and
+                // slope_bias when sampling, and offsets point shadow lookups towards the light.
+                bias: wgpu::DepthBiasState::default(),
             }),
             multisample: wgpu::MultisampleState {
                 count: 1,
@@ -331,73 +268,184 @@ impl ShadowPasses {
             },
             fragment: None,
             multiview: None,
-            cache: None,
-        });
-
-        ShadowPasses {
-            shadow_texture,
-            shadow_texture_view,
-            shadow_sampler,
-            shadow_light_buffer,
-            shadow_light_bind_group_layout,
-            shadow_light_bind_group,
-            shadow_result_bind_group_layout,
-            shadow_result_bind_group,
-            pipeline
-        }
+            cache: compiled.cache,
+        })
     }
 
-    pub fn render(
+    /// Clears one layer of a shadow map and draws the instances `casters` culled into it,
+    /// with the matrix at `view` in the views buffer.
+    fn render_view(
         &self,
-        device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        mesh_resources: &MeshResources,
-        shadow_mesh_commands: &mut CommandQueue<RenderShadowMeshCommand, RenderShadowBatch>
+        frame: &mut FrameContext,
+        label: &str,
+        target: &wgpu::TextureView,
+        view: usize,
+        casters: Option<(&MeshResources, &InstanceCulling)>,
     ) {
-
-        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-            label: None
+        let mut render_pass = frame.begin_render_pass(wgpu::RenderPassDescriptor {
+            label: Some(label),
+            color_attachments: &[ ],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: target,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None
+            }),
+            timestamp_writes: None,
+            occlusion_query_set: None,
         });
 
-        {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Shadow Pass"),
-                color_attachments: &[ ],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: &self.shadow_texture_view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: wgpu::StoreOp::Store,
-                    }),
-                    stencil_ops: None
-                }),
-                timestamp_writes: None,
-                occlusion_query_set: None,
-            });
+        let Some((meshes, culling)) = casters else {
+            return;
+        };
 
-            render_pass.push_debug_group("Begin Shadow Pass");
+        render_pass.push_debug_group("Begin Shadow Pass");
 
-            render_pass.set_pipeline(&self.pipeline);
-            render_pass.set_bind_group(0, &self.shadow_light_bind_group, &[]);
+        render_pass.set_pipeline(&self.pipeline);
+        let offset = (view * std::mem::size_of::<GpuShadowView>()) as wgpu::DynamicOffset;
+        render_pass.set_bind_group(0, &self.views_bind_group, &[offset]);
 
-            while let Some(batch) = shadow_mesh_commands.pop_next_batch() {
+        for (mesh_type, culled_mesh_type) in meshes.mesh_types.iter().zip(culling.mesh_types.iter()) {
 
-                let mesh_type = mesh_resources.mesh_types.get(batch.mesh_type as usize).unwrap();
+            render_pass.set_bind_group(1, &mesh_type.instance_bind_group, &[]);
+            render_pass.set_vertex_buffer(0, mesh_type.gpu_geometry.positions_buffer.slice(..));
+            render_pass.set_vertex_buffer(1, culled_mesh_type.visible_instances.slice(..));
 
-                render_pass.set_vertex_buffer(0, mesh_type.gpu_geometry.positions_buffer.slice(..));
-                render_pass.set_vertex_buffer(1, mesh_type.model_matrix_buffer.slice(..));
+            render_pass.set_index_buffer(mesh_type.gpu_geometry.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
+            render_pass.draw_indexed_indirect(&culled_mesh_type.draw_arguments, 0);
+        }
 
-                let instances = batch.object_indices.len() as u32;
+        render_pass.pop_debug_group();
+    }
+}
 
-                mesh_type.prepare_instances(&queue, &batch.object_indices);
+impl RenderNode for ShadowPasses {
+    fn name(&self) -> &str {
+        "Shadow Pass"
+    }
 
-                render_pass.set_index_buffer(mesh_type.gpu_geometry.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
-                render_pass.draw_indexed(0..mesh_type.gpu_geometry.index_count, 0, 0..instances);
+    fn declare(&self, builder: &mut NodeBuilder) {
+        builder.create_texture(SHADOW_MAP, TextureDesc::array(
+            wgpu::TextureFormat::Depth32Float,
+            SHADOW_MAP_SIZE,
+            SHADOW_MAP_SIZE,
+            SHADOW_CASCADES as u32,
+        ));
+        builder.create_persistent_texture(POINT_SHADOW_MAP, TextureDesc::array(
+            wgpu::TextureFormat::Depth32Float,
+            POINT_SHADOW_MAP_SIZE,
+            POINT_SHADOW_MAP_SIZE,
+            POINT_SHADOW_LAYERS as u32,
+        ));
+        builder.create_buffer(SHADOW_LIGHT, BufferDesc {
+            size: std::mem::size_of::<GpuLightView>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        builder.create_buffer(POINT_SHADOW_VIEWS, BufferDesc {
+            size: std::mem::size_of::<GpuPointShadows>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+    }
+
+    /// The point shadow map was reallocated, so every slot is rendered again.
+    fn prepare(&mut self, _device: &wgpu::Device, _resources: &GraphResources) {
+        self.rendered_point_shadows = [None; SHADOW_SLOTS];
+    }
+
+    fn reload_shaders(&mut self, device: &wgpu::Device) {
+        let pipeline_layout = &self.pipeline_layout;
+        let mut rebuilt = false;
+        self.shaders.reload(device, &mut self.pipeline, |compiled| {
+            rebuilt = true;
+            Self::create_pipeline(device, pipeline_layout, compiled)
+        });
+        if rebuilt {
+            self.rendered_point_shadows = [None; SHADOW_SLOTS];
+        }
+        for culling in self.culling.iter_mut().chain(self.point_culling.iter_mut()) {
+            culling.reload_shaders(device);
+        }
+    }
+
+    fn run(&mut self, frame: &mut FrameContext, resources: &GraphResources, inputs: &RenderInputs) {
+
+        let light = inputs.lights.directional_light;
+        let light_shadows = &inputs.lights.light_shadows;
+
+        frame.queue.write_buffer(
+            resources.buffer(SHADOW_LIGHT),
+            0,
+            bytemuck::cast_slice(&[GpuLightView::new(light.as_ref(), &inputs.lights.shadow_settings)])
+        );
+
+        let mut views = [GpuShadowView { view_matrix: cgmath::Matrix4::identity() }; SHADOW_VIEWS];
+        if let Some(light) = light.as_ref() {
+            for (view, cascade) in views.iter_mut().zip(light.cascades.iter()) {
+                view.view_matrix = cascade.view_projection;
+            }
+        }
+        for (slot, shadow) in light_shadows.iter().enumerate() {
+            if let Some(shadow) = shadow {
+                let first_layer = LightsResources::shadow_slot_layer(slot as u32) as usize;
+                for (face, view_projection) in shadow.face_view_projections.iter().enumerate().take(shadow.faces) {
+                    views[SHADOW_CASCADES + first_layer + face].view_matrix = *view_projection;
+                }
+            }
+        }
+
+        frame.queue.write_buffer(&self.views_buffer, 0, bytemuck::cast_slice(&views));
+        frame.queue.write_buffer(
+            resources.buffer(POINT_SHADOW_VIEWS),
+            0,
+            bytemuck::cast_slice(&[GpuPointShadows {
+                face_view_matrices: std::array::from_fn(|i| views[SHADOW_CASCADES + i].view_matrix),
+            }])
+        );
+
+        // Without a directional light, the cascades are only cleared:
+        for cascade in 0..SHADOW_CASCADES {
+            if let Some(light) = light.as_ref() {
+                self.culling[cascade].cull(frame, inputs.meshes, light.cascades[cascade].view_projection);
             }
 
-            render_pass.pop_debug_group();
+            self.render_view(
+                frame,
+                &format!("Shadow Pass {}", cascade),
+                resources.texture_layer_view(SHADOW_MAP, cascade as u32),
+                cascade,
+                light.map(|_| (inputs.meshes, &self.culling[cascade])),
+            );
         }
 
-        queue.submit(std::iter::once(encoder.finish()));
+        // Unused slots are never sampled, and unchanged ones still hold their shadow, so both
+        // are left alone:
+        for (slot, shadow) in light_shadows.iter().enumerate() {
+            let Some(shadow) = shadow else {
+                self.rendered_point_shadows[slot] = None;
+                continue;
+            };
+
+            let rendered = Some((*shadow, inputs.meshes.generation()));
+            if self.rendered_point_shadows[slot] == rendered {
+                continue;
+            }
+            self.rendered_point_shadows[slot] = rendered;
+
+            self.point_culling[slot].cull(frame, inputs.meshes, shadow.culling_view_projection);
+
+            let first_layer = LightsResources::shadow_slot_layer(slot as u32) as usize;
+            for face in 0..shadow.faces {
+                let layer = first_layer + face;
+                self.render_view(
+                    frame,
+                    &format!("Point Shadow Pass {} {}", slot, face),
+                    resources.texture_layer_view(POINT_SHADOW_MAP, layer as u32),
+                    SHADOW_CASCADES + layer,
+                    Some((inputs.meshes, &self.point_culling[slot])),
+                );
+            }
+        }
     }
 }
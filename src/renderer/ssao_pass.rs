@@ -1,13 +1,23 @@
-use crate::renderer::deferred_pass::DeferredPass;
+use crate::renderer::deferred_pass::{GBUFFER_NORMAL, GBUFFER_POSITION};
+use crate::renderer::frame::FrameContext;
+use crate::renderer::render_graph::{GraphResources, NodeBuilder, RenderInputs, RenderNode, TextureDesc};
 use crate::renderer::scene_base::SceneBaseResources;
+use crate::renderer::shader_cache::ShaderCache;
+use crate::renderer::shaders::{CompiledShaders, ShaderFile, ShaderStage, ShaderWatcher};
 use crate::renderer::utils::GpuVector3;
-use rand::Rng;
-use shaderc::ShaderKind::DefaultAnyHit;
-use std::ops::Not;
+use rand::{Rng, SeedableRng};
 use wgpu::util::*;
 
+pub const SSAO_OUTPUT: &str = "ssao";
+
 const SAMPLE_COUNT: usize = 256;
 
+/// Width and height of the random rotation texture, which is tiled over the screen.
+const NOISE_SIZE: u32 = 4;
+
+// Fixed seed for the sample kernel and noise, so that frames are reproducible:
+const RANDOM_SEED: u64 = 0x5EED_55A0;
+
 #[repr(C, align(256))]
 #[derive(Clone, Copy, Debug)]
 struct HemisphereSamples {
@@ -22,10 +32,13 @@ pub fn lerp(a: f32, b: f32, t: f32) -> f32 {
 }
 
 pub struct SSAOPass {
-    pub bind_group_layout: wgpu::BindGroupLayout,
-    pub bind_group: wgpu::BindGroup,
+    shaders: ShaderWatcher,
+    render_pipeline_layout: wgpu::PipelineLayout,
     pipeline: wgpu::RenderPipeline,
-    ssao_output_view: wgpu::TextureView,
+    gbuffer_bind_group_layout: wgpu::BindGroupLayout,
+    gbuffer_sampler: wgpu::Sampler,
+    /// Created once the graph allocated the G-buffer.
+    gbuffer_bind_group: Option<wgpu::BindGroup>,
     ssao_bind_group: wgpu::BindGroup,
     vertices: wgpu::Buffer,
     indices: wgpu::Buffer,
@@ -35,113 +48,9 @@ impl SSAOPass {
     pub fn new(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-        deferred_pass: &DeferredPass,
         scene_base_resources: &SceneBaseResources,
-        screen_width: u32,
-        screen_height: u32,
+        shader_cache: &ShaderCache,
     ) -> Self {
-        let mut compiler = shaderc::Compiler::new().unwrap();
-
-        let vs_code = "
-            #version 450
-
-            layout(location=0) in vec3 a_position;
-            layout(location=0) out vec2 tex_coord;
-
-            void main() {
-                tex_coord = (a_position.xy + vec2(1.0,1.0)) * vec2(0.5, 0.5);
-                gl_Position = vec4(a_position, 1.0);
-            }
-        "
-        .to_string();
-
-        let fs_code = format!("
-            #version 450
-
-            layout(location=0) in vec2 tex_coord;
-            layout(location=0) out float f_occlusion;
-
-            layout(set=0, binding=0) uniform SceneBase {{
-                mat4 view_mat;
-                mat4 projection_mat;
-                vec2 window_size;
-            }};
-
-            layout(set=1, binding=0) uniform sampler layer_sampler;
-            layout(set=1, binding=2) uniform texture2D gPosition;
-            layout(set=1, binding=3) uniform texture2D gNormal;
-
-            layout(set = 2, binding = 0) uniform Hemisphere {{ vec3 sample_points[{}]; }};
-            layout(set = 2, binding = 1) uniform sampler random_vec_sampler;
-            layout(set = 2, binding = 2) uniform texture2D random_vec_texture;
-
-            void main() {{
-                vec3 f_position = texture(sampler2D(gPosition, layer_sampler), tex_coord).xyz;
-                vec3 f_normal = normalize(texture(sampler2D(gNormal, layer_sampler), tex_coord).rgb * 2.0 - 1.0);
-
-                const vec2 noise_scale = vec2( 1024.0 / 4.0, 768.0 / 4.0 ); // scale to cover whole screen
-
-                vec3 random_vector = normalize(texture(sampler2D(random_vec_texture, random_vec_sampler), tex_coord * noise_scale).xyz);
-                vec3 tangent = normalize( random_vector - f_normal * dot(random_vector, f_normal) );
-                vec3 bitangent = cross(f_normal, tangent);
-                mat3 tbn = mat3(tangent, bitangent, f_normal);
-
-                float radius = 0.40;
-                float ssao_bias = 0.01;
-                float occ = 0.0;
-                vec3 debug = vec3(0.0, 0.0, 0.0);
-
-                for(int i=0; i < {}; ++i) {{
-                    vec3 point = tbn * sample_points[i];
-                    point = f_position + point * radius;
-
-                    vec4 offset = vec4(point, 1.0);
-                    offset = projection_mat * offset;
-                    offset.xyz /= offset.w;
-                    offset.xy = offset.xy * vec2(0.5, -0.5) + 0.5;
-
-                    vec3 occluder_position = texture(sampler2D(gPosition, layer_sampler), offset.xy).xyz;
-
-                    if(i==32) {{ debug = occluder_position; }}
-
-                    float rangeCheck = smoothstep(0.0, 1.0, radius / abs(point.z - occluder_position.z));
-                    occ += (occluder_position.z >= (point.z + ssao_bias) ? 1.0 : 0.0) * rangeCheck;
-                }}
-
-                // We want shadows only, so we clamp everything above 0.7:
-                f_occlusion = smoothstep(0.0, 0.7, 1.0 - occ/{}.0);
-            }}
-        ", SAMPLE_COUNT, SAMPLE_COUNT, SAMPLE_COUNT);
-
-        let vs_spirv = compiler
-            .compile_into_spirv(
-                &vs_code,
-                shaderc::ShaderKind::Vertex,
-                "composition.vert",
-                "main",
-                None,
-            )
-            .unwrap();
-
-        let fs_spirv = compiler
-            .compile_into_spirv(
-                &fs_code,
-                shaderc::ShaderKind::Fragment,
-                "composition.frag",
-                "main",
-                None,
-            )
-            .unwrap();
-
-        let vertex_shader_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-            label: Some("SSAO Pass Vertex Shader"),
-            source: wgpu::ShaderSource::SpirV(std::borrow::Cow::Borrowed(vs_spirv.as_binary())),
-        });
-        let fragment_shader_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-            label: Some("SSAO Pass Fragment Shader"),
-            source: wgpu::ShaderSource::SpirV(std::borrow::Cow::Borrowed(fs_spirv.as_binary())),
-        });
-
         let vertices = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("SSAO Pass Vertex Buffer"),
             contents: bytemuck::cast_slice(&[
@@ -159,54 +68,60 @@ impl SSAOPass {
             usage: wgpu::BufferUsages::INDEX,
         });
 
-        // Render Target Texture
+        // G-buffer inputs:
 
-        let ssao_output_texture = device.create_texture(&wgpu::TextureDescriptor {
-            label: None,
-            size: wgpu::Extent3d {
-                width: screen_width,
-                height: screen_height,
-                depth_or_array_layers: 1,
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::R32Float,
-            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
-            view_formats: &[],
-        });
-
-        let ssao_output_view =
-            ssao_output_texture.create_view(&wgpu::TextureViewDescriptor::default());
-
-        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: None,
-            entries: &[wgpu::BindGroupLayoutEntry {
-                binding: 0,
-                visibility: wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Texture {
-                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
-                    multisampled: false,
-                    view_dimension: wgpu::TextureViewDimension::D2,
+        let gbuffer_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("SSAO G-Buffer"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::NonFiltering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
                 },
-                count: None,
-            }],
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+            ],
         });
 
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        let gbuffer_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             label: None,
-            layout: &bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: wgpu::BindingResource::TextureView(&ssao_output_view),
-            }],
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: 0.0,
+            lod_max_clamp: 0.0,
+            compare: None,
+            anisotropy_clamp: 1,
+            border_color: None,
         });
 
         // Generate Hemisphere Sample Points:
 
-        let mut rng = rand::rng();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(RANDOM_SEED);
         let mut samples = [[1.0, 1.0, 1.0, 0.0]; SAMPLE_COUNT];
-        for i in 0..SAMPLE_COUNT {
+        for (i, sample) in samples.iter_mut().enumerate() {
             let x = rng.random_range(0.0..2.0) - 1.0;
             let y = rng.random_range(0.0..2.0) - 1.0;
             let z = rng.random_range(0.0..1.0);
@@ -214,7 +129,7 @@ impl SSAOPass {
             let scale: f32 = i as f32 / SAMPLE_COUNT as f32;
             let lerp = lerp(0.1, 1.0, scale * scale);
 
-            samples[i] = [x * lerp, y * lerp, z * lerp, 0.0];
+            *sample = [x * lerp, y * lerp, z * lerp, 0.0];
         }
 
         let hemisphere = HemisphereSamples { points: samples };
@@ -222,8 +137,8 @@ impl SSAOPass {
         let random_vector_texture = device.create_texture(&wgpu::TextureDescriptor {
             label: None,
             size: wgpu::Extent3d {
-                width: 4,
-                height: 4,
+                width: NOISE_SIZE,
+                height: NOISE_SIZE,
                 depth_or_array_layers: 1,
             },
             mip_level_count: 1,
@@ -235,10 +150,10 @@ impl SSAOPass {
         });
 
         {
-            let mut data = [[0.0; 4]; 16];
+            let mut data = [[0.0; 4]; (NOISE_SIZE * NOISE_SIZE) as usize];
 
-            for i in 0..16 {
-                data[i] = [
+            for texel in data.iter_mut() {
+                *texel = [
                     rng.random_range(-1.0..1.0),
                     rng.random_range(-1.0..1.0),
                     0.0,
@@ -256,12 +171,12 @@ impl SSAOPass {
                 bytemuck::cast_slice(&data),
                 wgpu::TexelCopyBufferLayout {
                     offset: 0,
-                    bytes_per_row: Some(4 * 4 * 4),
-                    rows_per_image: Some(4),
+                    bytes_per_row: Some(NOISE_SIZE * 4 * 4),
+                    rows_per_image: Some(NOISE_SIZE),
                 },
                 wgpu::Extent3d {
-                    width: 4,
-                    height: 4,
+                    width: NOISE_SIZE,
+                    height: NOISE_SIZE,
                     depth_or_array_layers: 1,
                 },
             );
@@ -353,18 +268,46 @@ impl SSAOPass {
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 bind_group_layouts: &[
                     &scene_base_resources.bind_group_layout,
-                    &deferred_pass.gbuffer_bind_group_layout,
+                    &gbuffer_bind_group_layout,
                     &ssao_bind_group_layout,
                 ],
                 push_constant_ranges: &[],
                 label: None,
             });
 
-        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        let mut shaders = ShaderWatcher::new("SSAO Pass", vec![
+            ShaderFile::new("ssao.vert", ShaderStage::Vertex),
+            ShaderFile::new("ssao.frag", ShaderStage::Fragment)
+                .define_u32("SAMPLE_COUNT", SAMPLE_COUNT as u32)
+                .define_u32("NOISE_SIZE", NOISE_SIZE),
+        ], shader_cache);
+
+        let pipeline = shaders.build(device, |compiled| Self::create_pipeline(device, &render_pipeline_layout, compiled));
+
+        SSAOPass {
+            shaders,
+            render_pipeline_layout,
+            pipeline,
+            gbuffer_bind_group_layout,
+            gbuffer_sampler,
+            gbuffer_bind_group: None,
+            ssao_bind_group,
+            vertices,
+            indices,
+        }
+    }
+
+
+    fn create_pipeline(
+        device: &wgpu::Device,
+        render_pipeline_layout: &wgpu::PipelineLayout,
+        compiled: &CompiledShaders,
+    ) -> wgpu::RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
             label: None,
-            layout: Some(&render_pipeline_layout),
+            layout: Some(render_pipeline_layout),
             vertex: wgpu::VertexState {
-                module: &vertex_shader_module,
+                module: &compiled.modules[0],
                 entry_point: Some("main"),
                 compilation_options: Default::default(),
                 buffers: &[wgpu::VertexBufferLayout {
@@ -382,52 +325,71 @@ impl SSAOPass {
                 strip_index_format: None,
                 front_face: wgpu::FrontFace::Ccw,
                 cull_mode: Some(wgpu::Face::Back),
-                unclipped_depth: true,
+                unclipped_depth: device.features().contains(wgpu::Features::DEPTH_CLIP_CONTROL),
                 polygon_mode: Default::default(),
                 conservative: false,
             },
             depth_stencil: None,
             multisample: Default::default(),
             fragment: Some(wgpu::FragmentState {
-                module: &fragment_shader_module,
+                module: &compiled.modules[1],
                 entry_point: Some("main"),
                 compilation_options: Default::default(),
                 targets: &[Some(wgpu::ColorTargetState {
-                    format: wgpu::TextureFormat::R32Float,
+                    format: wgpu::TextureFormat::R16Float,
                     write_mask: wgpu::ColorWrites::ALL,
                     blend: None,
                 })],
             }),
             multiview: None,
-            cache: None,
-        });
+            cache: compiled.cache,
+        })
+    }
+}
 
-        SSAOPass {
-            bind_group_layout,
-            bind_group,
-            pipeline,
-            ssao_bind_group,
-            ssao_output_view,
-            vertices,
-            indices,
-        }
+impl RenderNode for SSAOPass {
+    fn name(&self) -> &str {
+        "SSAO Pass"
     }
 
-    pub fn render(
-        &self,
-        device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        scene_base: &SceneBaseResources,
-        deferred_pass: &DeferredPass,
-    ) {
-        let mut encoder =
-            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    fn declare(&self, builder: &mut NodeBuilder) {
+        builder.read_texture(GBUFFER_POSITION);
+        builder.read_texture(GBUFFER_NORMAL);
+        builder.create_texture(SSAO_OUTPUT, TextureDesc::screen(wgpu::TextureFormat::R16Float));
+    }
 
+    fn prepare(&mut self, device: &wgpu::Device, resources: &GraphResources) {
+        self.gbuffer_bind_group = Some(device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("SSAO G-Buffer"),
+            layout: &self.gbuffer_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Sampler(&self.gbuffer_sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(resources.texture_view(GBUFFER_POSITION)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(resources.texture_view(GBUFFER_NORMAL)),
+                },
+            ],
+        }));
+    }
+
+    fn reload_shaders(&mut self, device: &wgpu::Device) {
+        let render_pipeline_layout = &self.render_pipeline_layout;
+        self.shaders.reload(device, &mut self.pipeline, |compiled| Self::create_pipeline(device, render_pipeline_layout, compiled));
+    }
+
+    fn run(&mut self, frame: &mut FrameContext, resources: &GraphResources, inputs: &RenderInputs) {
         {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            let mut render_pass = frame.begin_render_pass(wgpu::RenderPassDescriptor {
                 label: Some("SSAO Pass"),
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &self.ssao_output_view,
+                    view: resources.texture_view(SSAO_OUTPUT),
                     depth_slice: None,
                     resolve_target: None,
                     ops: wgpu::Operations {
@@ -441,14 +403,12 @@ impl SSAOPass {
             });
 
             render_pass.set_pipeline(&self.pipeline);
-            render_pass.set_bind_group(0, &scene_base.bind_group, &[]);
-            render_pass.set_bind_group(1, &deferred_pass.gbuffer_bind_group, &[]);
+            render_pass.set_bind_group(0, &inputs.scene_base.bind_group, &[]);
+            render_pass.set_bind_group(1, self.gbuffer_bind_group.as_ref().unwrap(), &[]);
             render_pass.set_bind_group(2, &self.ssao_bind_group, &[]);
             render_pass.set_vertex_buffer(0, self.vertices.slice(..));
             render_pass.set_index_buffer(self.indices.slice(..), wgpu::IndexFormat::Uint16);
             render_pass.draw_indexed(0..6, 0, 0..1)
         }
-
-        queue.submit(std::iter::once(encoder.finish()));
     }
 }
@@ -1,12 +1,4 @@
-use cgmath::{Zero, ElementWise};
-
-#[cfg_attr(rustfmt, rustfmt_skip)]
-pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
-    1.0, 0.0, 0.0, 0.0,
-    0.0, 1.0, 0.0, 0.0,
-    0.0, 0.0, 0.5, 0.0,
-    0.0, 0.0, 0.5, 1.0,
-);
+use cgmath::{Zero, Transform};
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -31,29 +23,6 @@ impl GpuMatrix4 {
 unsafe impl bytemuck::Pod for GpuMatrix4 {}
 unsafe impl bytemuck::Zeroable for GpuMatrix4 {}
 
-#[repr(C, align(256))]
-#[derive(Debug, Copy, Clone)]
-pub struct GpuMatrix4BGA {
-    pub matrix: cgmath::Matrix4<f32>
-}
-
-impl GpuMatrix4BGA {
-    pub fn new(matrix: cgmath::Matrix4<f32>) -> Self {
-        GpuMatrix4BGA {
-            matrix
-        }
-    }
-
-    pub fn empty() -> Self {
-        GpuMatrix4BGA {
-            matrix: cgmath::Matrix4::zero()
-        }
-    }
-}
-
-unsafe impl bytemuck::Pod for GpuMatrix4BGA {}
-unsafe impl bytemuck::Zeroable for GpuMatrix4BGA {}
-
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct GpuVector3 {
@@ -61,12 +30,6 @@ pub struct GpuVector3 {
 }
 
 impl GpuVector3 {
-    pub fn empty() -> Self {
-        GpuVector3 {
-            vector: cgmath::Vector3::new(0.0, 0.0, 0.0)
-        }
-    }
-
     pub fn new(x: f32, y: f32, z: f32) -> Self {
         GpuVector3 {
             vector: cgmath::Vector3::new(x, y, z)
@@ -75,31 +38,7 @@ impl GpuVector3 {
 }
 
 unsafe impl bytemuck::Pod for GpuVector3 {}
-unsafe impl bytemuck::Zeroable for GpuVector3 {} 
-
-
-#[repr(C, align(256))]
-#[derive(Debug, Copy, Clone)]
-pub struct GpuVector3BGA {
-    pub vector: cgmath::Vector3<f32>
-}
-
-impl GpuVector3BGA {
-    pub fn empty() -> Self {
-        GpuVector3BGA {
-            vector: cgmath::Vector3::new(0.0, 0.0, 0.0)
-        }
-    }
-
-    pub fn new(x: f32, y: f32, z: f32) -> Self {
-        GpuVector3BGA {
-            vector: cgmath::Vector3::new(x, y, z)
-        }
-    }
-}
-
-unsafe impl bytemuck::Pod for GpuVector3BGA {}
-unsafe impl bytemuck::Zeroable for GpuVector3BGA {}
+unsafe impl bytemuck::Zeroable for GpuVector3 {}
 
 fn max3(a: f32, b: f32, c: f32) -> f32 {
     if a > b {
@@ -133,6 +72,8 @@ fn min3(a: f32, b: f32, c: f32) -> f32 {
     }
 }
 
+#[derive(Debug, Copy, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub struct AABB {
     pub min: cgmath::Point3<f32>,
     pub max: cgmath::Point3<f32>,
@@ -146,6 +87,42 @@ impl AABB {
         }
     }
 
+    /// The smallest box containing all points. Empty input yields an inverted box,
+    /// which no frustum intersects.
+    pub fn from_points(points: impl IntoIterator<Item = cgmath::Point3<f32>>) -> Self {
+        let mut min = cgmath::Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+        let mut max = cgmath::Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
+
+        for point in points {
+            min = cgmath::Point3::new(min.x.min(point.x), min.y.min(point.y), min.z.min(point.z));
+            max = cgmath::Point3::new(max.x.max(point.x), max.y.max(point.y), max.z.max(point.z));
+        }
+
+        AABB {
+            min,
+            max
+        }
+    }
+
+    pub fn corners(&self) -> [cgmath::Point3<f32>; 8] {
+        [
+            cgmath::Point3::new(self.min.x, self.min.y, self.min.z),
+            cgmath::Point3::new(self.max.x, self.min.y, self.min.z),
+            cgmath::Point3::new(self.min.x, self.max.y, self.min.z),
+            cgmath::Point3::new(self.max.x, self.max.y, self.min.z),
+            cgmath::Point3::new(self.min.x, self.min.y, self.max.z),
+            cgmath::Point3::new(self.max.x, self.min.y, self.max.z),
+            cgmath::Point3::new(self.min.x, self.max.y, self.max.z),
+            cgmath::Point3::new(self.max.x, self.max.y, self.max.z),
+        ]
+    }
+
+    /// The axis aligned box enclosing this box after transforming it with `matrix`.
+    pub fn transformed(&self, matrix: &cgmath::Matrix4<f32>) -> AABB {
+        let corners = self.corners().map(|corner| matrix.transform_point(corner));
+        AABB::from_points(corners)
+    }
+
     pub fn shortest_distance(&self, point: cgmath::Point3<f32>) -> f32 {
         let dx = max3(self.min.x - point.x, 0.0, point.x - self.max.x);
         let dy = max3(self.min.y - point.y, 0.0, point.y - self.max.y);
@@ -161,4 +138,4 @@ impl AABB {
 
         (dx*dx + dy*dy + dz*dz).sqrt()
     }
-}
\ No newline at end of file
+}
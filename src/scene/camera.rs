@@ -1,4 +1,4 @@
-use crate::renderer::{DeltaTimer, utils::GpuMatrix4, scene_base::SceneBaseResources};
+use crate::renderer::{DeltaTimer, scene_base::SceneBaseResources};
 use crate::input::{ KeyState, InputMap };
 use cgmath::prelude::*;
 use specs::prelude::*;
@@ -7,7 +7,7 @@ use winit::dpi::PhysicalSize;
 use crate::renderer::scene_base::GpuSceneBase;
 use crate::scene::scene_graph::SceneResources;
 
-#[cfg_attr(rustfmt, rustfmt_skip)]
+#[rustfmt::skip]
 pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
     1.0, 0.0, 0.0, 0.0,
     0.0, 1.0, 0.0, 0.0,
@@ -22,6 +22,7 @@ pub struct Camera {
     target: cgmath::Point3<f32>,
     up: cgmath::Vector3<f32>,
     aspect: f32,
+    viewport: PhysicalSize<u32>,
     fovy: f32,
     znear: f32,
     zfar: f32,
@@ -29,17 +30,18 @@ pub struct Camera {
 
 impl Default for Camera {
     fn default() -> Self {
-        Self::new(1.0)
+        Self::new(PhysicalSize::new(1024, 768))
     }
 }
 
 impl Camera {
-    pub fn new(aspect_ratio: f32) -> Self {
+    pub fn new(viewport: PhysicalSize<u32>) -> Self {
         Camera {
             position: (-8.0, 10.0, 8.0).into(),
             target: (0.0, 0.0, 0.0).into(),
             up: cgmath::Vector3::unit_y(),
-            aspect: aspect_ratio,
+            aspect: viewport.width as f32 / viewport.height as f32,
+            viewport,
             fovy: 45.0,
             znear: 0.1,
             zfar: 100.0,
@@ -47,7 +49,10 @@ impl Camera {
     }
 
     pub fn resize(&mut self, size: PhysicalSize<u32>) {
-        self.aspect = size.width as f32 / size.height as f32;
+        if size.width > 0 && size.height > 0 {
+            self.aspect = size.width as f32 / size.height as f32;
+            self.viewport = size;
+        }
     }
 
     pub fn build_projection_matrix(&self) -> cgmath::Matrix4<f32> {
@@ -57,9 +62,7 @@ impl Camera {
     }
 
     pub fn build_view_matrix(&self) -> cgmath::Matrix4<f32> {
-        let view = cgmath::Matrix4::look_at(self.position, self.target, self.up);
-
-        view
+        cgmath::Matrix4::look_at_rh(self.position, self.target, self.up)
     }
 }
 
@@ -71,7 +74,7 @@ impl<'a> System<'a> for CameraSystem {
         ReadExpect<'a, ActiveCamera>,
         ReadExpect<'a, DeltaTimer>,
         ReadExpect<'a, InputMap>,
-        ReadExpect<'a, SceneBaseResources>,
+        WriteExpect<'a, SceneBaseResources>,
         ReadExpect<'a, SceneResources>,
         ReadExpect<'a, wgpu::Queue>
     );
@@ -83,7 +86,7 @@ impl<'a> System<'a> for CameraSystem {
             active_camera,
             delta_timer,
             input_map,
-            scene_base_resources,
+            mut scene_base_resources,
             scene_resources,
             queue
         ) = data;
@@ -92,7 +95,7 @@ impl<'a> System<'a> for CameraSystem {
         let speed = 4.5;
         let zoom_speed = 10.0;
 
-        if let Some(camera) = cameras.get_mut((*active_camera).0) {
+        if let Some(camera) = cameras.get_mut(active_camera.0) {
 
             let d_left = match input_map.key_d { KeyState::Pressed => -1.0, _ => 0.0 } +
                 speed * d * match input_map.key_a { KeyState::Pressed => 1.0, _ => 0.0 };
@@ -134,8 +137,8 @@ impl<'a> System<'a> for CameraSystem {
                 scene_base_resources.update_scene_base(&queue, GpuSceneBase {
                     view_matrix: updated_view_matrix,
                     projection_matrix: updated_projection_matrix,
-                    window_size: cgmath::Vector2::new(1024.0, 768.0),
-                    padding: cgmath::Vector2::new(0.0, 0.0)
+                    window_size: cgmath::Vector2::new(camera.viewport.width as f32, camera.viewport.height as f32),
+                    z_range: cgmath::Vector2::new(camera.znear, camera.zfar)
                 });
         }
     }
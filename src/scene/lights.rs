@@ -1,7 +1,56 @@
+use std::collections::HashMap;
+
+use cgmath::{EuclideanSpace, InnerSpace, SquareMatrix, Transform};
 use specs::prelude::*;
 use specs::Component;
 
-use crate::renderer::lights::{GpuLight, LightsResources};
+use crate::renderer::lights::{DirectionalLightData, Falloff, GpuLight, LightType, LightsResources, LightShadowData, ShadowCascade, CASCADE_BLEND, MAX_SHADOWED_POINT_LIGHTS, MAX_SHADOWED_SPOT_LIGHTS, SHADOW_CASCADES, SHADOW_MAP_SIZE};
+use crate::renderer::scene_base::{GpuSceneBase, SceneBaseResources};
+use crate::renderer::utils::AABB;
+use crate::scene::camera::OPENGL_TO_WGPU_MATRIX;
+use crate::scene::scene_graph::SceneResources;
+
+/// How far the shadow map reaches beyond the scene bounds towards the light, so that objects
+/// sticking out of the bounds still cast shadows.
+const SHADOW_CASTER_MARGIN: f32 = 10.0;
+
+/// Blends between uniform (0) and logarithmic (1) cascade splits. Logarithmic splits match the
+/// perspective best, but leave the far cascades very little resolution.
+const CASCADE_SPLIT_LAMBDA: f32 = 0.75;
+
+/// Lower limit of the near plane used for the splits, the camera's near plane can be zero
+/// inside the scene bounds.
+const CASCADE_MIN_NEAR: f32 = 0.1;
+
+/// Near plane of the shadow projections of point and spot lights.
+const SHADOW_NEAR: f32 = 0.1;
+
+/// An up vector for views looking into `direction`, which must not be parallel to it.
+fn up_vector(direction: cgmath::Vector3<f32>) -> cgmath::Vector3<f32> {
+    if direction.normalize().cross(cgmath::Vector3::unit_y()).magnitude2() > 1e-6 {
+        cgmath::Vector3::unit_y()
+    } else {
+        cgmath::Vector3::unit_z()
+    }
+}
+
+/// What `LightSystem` needs to know about the lights stored in the light buffer.
+trait LocalLight: Component {
+    const NAME: &'static str;
+    const TYPE: LightType;
+
+    fn cast_shadows(&self) -> bool;
+
+    /// Whether a shadow map can cover the light, logged if it asks for shadows anyway.
+    fn can_cast_shadows(&self) -> bool {
+        true
+    }
+
+    fn shadow(&self) -> LightShadowData;
+
+    /// `shadow_layer` is the first layer of the light's shadow slot.
+    fn gpu_light(&self, shadow_layer: Option<u32>) -> GpuLight;
+}
 
 #[derive(Component)]
 #[storage(FlaggedStorage)]
@@ -9,45 +58,256 @@ pub struct PointLight {
     pub position: cgmath::Vector3<f32>,
     pub color: cgmath::Vector3<f32>,
     pub intensity: f32,
+    /// The light has no effect beyond this distance.
     pub radius: f32,
-    pub light_index: u32,
+    pub falloff: Falloff,
+    /// Renders a cube shadow map for the light, if one of the
+    /// [`MAX_SHADOWED_POINT_LIGHTS`] slots is free when the light is added or modified.
+    pub cast_shadows: bool,
 }
 
-pub struct LightSystem {
-    point_lights_reader: Option<ReaderId<ComponentEvent>>,
+impl PointLight {
+    /// Perspective projections covering the light's radius in all six directions.
+    pub fn shadow(&self) -> LightShadowData {
+        let position = cgmath::Point3::from_vec(self.position);
+        let projection = OPENGL_TO_WGPU_MATRIX * cgmath::perspective(cgmath::Deg(90.0), 1.0, SHADOW_NEAR, self.radius);
+
+        let faces = [
+            (cgmath::Vector3::unit_x(), -cgmath::Vector3::unit_y()),
+            (-cgmath::Vector3::unit_x(), -cgmath::Vector3::unit_y()),
+            (cgmath::Vector3::unit_y(), cgmath::Vector3::unit_z()),
+            (-cgmath::Vector3::unit_y(), -cgmath::Vector3::unit_z()),
+            (cgmath::Vector3::unit_z(), -cgmath::Vector3::unit_y()),
+            (-cgmath::Vector3::unit_z(), -cgmath::Vector3::unit_y()),
+        ];
+
+        let radius = self.radius;
+
+        LightShadowData {
+            face_view_projections: faces.map(|(direction, up)| projection * cgmath::Matrix4::look_to_rh(position, direction, up)),
+            faces: 6,
+            culling_view_projection: OPENGL_TO_WGPU_MATRIX
+                * cgmath::ortho(-radius, radius, -radius, radius, -radius, radius)
+                * cgmath::Matrix4::from_translation(-self.position),
+        }
+    }
 }
 
-impl Default for LightSystem {
-    fn default() -> Self {
-        LightSystem {
-            point_lights_reader: None
+impl LocalLight for PointLight {
+    const NAME: &'static str = "point light";
+    const TYPE: LightType = LightType::Point;
+
+    fn cast_shadows(&self) -> bool {
+        self.cast_shadows
+    }
+
+    fn shadow(&self) -> LightShadowData {
+        PointLight::shadow(self)
+    }
+
+    fn gpu_light(&self, shadow_layer: Option<u32>) -> GpuLight {
+        GpuLight {
+            position: [self.position.x, self.position.y, self.position.z, 1.0],
+            color: [self.color.x, self.color.y, self.color.z, 1.0],
+            intensity_radius_enabled_shadow: [
+                self.intensity,
+                self.radius,
+                1.0,
+                shadow_layer.map_or(-1.0, |layer| layer as f32)
+            ],
+            cone_falloff: [-1.0, -1.0, self.falloff as u32 as f32, 0.0],
+            ..GpuLight::default()
         }
     }
 }
 
-impl<'a> System<'a> for LightSystem {
-    type SystemData = (
-        Entities<'a>,
-        ReadExpect<'a, wgpu::Device>,
-        ReadExpect<'a, wgpu::Queue>,
-        ReadExpect<'a, LightsResources>,
-        ReadStorage<'a, PointLight>
-    );
+/// A point light limited to a cone.
+#[derive(Component)]
+#[storage(FlaggedStorage)]
+pub struct SpotLight {
+    pub position: cgmath::Vector3<f32>,
+    /// The direction the cone opens into, in world space.
+    pub direction: cgmath::Vector3<f32>,
+    pub color: cgmath::Vector3<f32>,
+    pub intensity: f32,
+    /// The light has no effect beyond this distance.
+    pub radius: f32,
+    pub falloff: Falloff,
+    /// Within this angle from the direction, the light has its full intensity.
+    pub inner_angle: cgmath::Deg<f32>,
+    /// Beyond this angle from the direction, the light has no effect. Must be larger than the
+    /// inner angle, and below 90 degrees to cast shadows.
+    pub outer_angle: cgmath::Deg<f32>,
+    /// Renders a shadow map for the light, if one of the [`MAX_SHADOWED_SPOT_LIGHTS`] slots
+    /// is free when the light is added or modified.
+    pub cast_shadows: bool,
+}
 
-    fn run(&mut self, data: Self::SystemData) {
-        let (
-            entities,
-            device,
-            queue,
-            resources,
-            point_lights
-        ) = data;
+impl SpotLight {
+    /// A perspective projection covering the outer cone, in the first face.
+    pub fn shadow(&self) -> LightShadowData {
+        let view = cgmath::Matrix4::look_to_rh(cgmath::Point3::from_vec(self.position), self.direction.normalize(), up_vector(self.direction));
+        let projection = cgmath::perspective(self.outer_angle * 2.0, 1.0, SHADOW_NEAR, self.radius);
+        let view_projection = OPENGL_TO_WGPU_MATRIX * projection * view;
+
+        LightShadowData {
+            face_view_projections: [view_projection; 6],
+            faces: 1,
+            culling_view_projection: view_projection,
+        }
+    }
+}
+
+impl LocalLight for SpotLight {
+    const NAME: &'static str = "spot light";
+    const TYPE: LightType = LightType::Spot;
+
+    fn cast_shadows(&self) -> bool {
+        self.cast_shadows
+    }
+
+    /// The shadow projection spans twice the outer angle, which has to stay below 180 degrees.
+    fn can_cast_shadows(&self) -> bool {
+        self.outer_angle < cgmath::Deg(90.0)
+    }
+
+    fn shadow(&self) -> LightShadowData {
+        SpotLight::shadow(self)
+    }
 
-        let events = point_lights
+    fn gpu_light(&self, shadow_layer: Option<u32>) -> GpuLight {
+        let direction = self.direction.normalize();
+
+        GpuLight {
+            position: [self.position.x, self.position.y, self.position.z, 1.0],
+            color: [self.color.x, self.color.y, self.color.z, 1.0],
+            intensity_radius_enabled_shadow: [
+                self.intensity,
+                self.radius,
+                1.0,
+                shadow_layer.map_or(-1.0, |layer| layer as f32)
+            ],
+            direction_type: [direction.x, direction.y, direction.z, LightType::Spot as u32 as f32],
+            cone_falloff: [
+                cgmath::Angle::cos(self.inner_angle),
+                cgmath::Angle::cos(self.outer_angle),
+                self.falloff as u32 as f32,
+                0.0
+            ],
+        }
+    }
+}
+
+/// A light infinitely far away, like the sun. Casts shadows; if there are several, only the
+/// first one is used.
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct DirectionalLight {
+    /// The direction the light travels in, in world space.
+    pub direction: cgmath::Vector3<f32>,
+    pub color: cgmath::Vector3<f32>,
+    pub intensity: f32,
+    /// Subtracted from the depth of every shadow map lookup to avoid shadow acne, in light
+    /// clip space depth.
+    pub depth_bias: f32,
+    /// Added to the depth bias in proportion to how steeply the light hits the surface.
+    pub slope_bias: f32,
+}
+
+impl DirectionalLight {
+    /// Splits the camera's depth range into [`SHADOW_CASCADES`] slices and fits an
+    /// orthographic projection along the light direction around each of them. Returns `None`
+    /// as long as there is no camera.
+    pub fn cascades(&self, bounds: &AABB, camera: &GpuSceneBase) -> Option<[ShadowCascade; SHADOW_CASCADES]> {
+        let camera_to_world = camera.view_matrix.invert()?;
+
+        // Only rotates, the projections are placed in light space. The light looks along -z:
+        let light_view = cgmath::Matrix4::look_to_rh(cgmath::Point3::origin(), self.direction.normalize(), up_vector(self.direction));
+        let scene = bounds.transformed(&light_view);
+
+        let near = camera.z_range.x.max(CASCADE_MIN_NEAR);
+        let far = camera.z_range.y.max(near * 2.0);
+        let split = |i: usize| {
+            let t = i as f32 / SHADOW_CASCADES as f32;
+            let logarithmic = near * (far / near).powf(t);
+            let uniform = near + (far - near) * t;
+            CASCADE_SPLIT_LAMBDA * logarithmic + (1.0 - CASCADE_SPLIT_LAMBDA) * uniform
+        };
+
+        // The corners of the view at a distance from the camera, in world space:
+        let tan_x = 1.0 / camera.projection_matrix.x.x;
+        let tan_y = 1.0 / camera.projection_matrix.y.y;
+        let corners = |depth: f32| {
+            [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)].map(|(x, y)| {
+                camera_to_world.transform_point(cgmath::Point3::new(x * depth * tan_x, y * depth * tan_y, -depth))
+            })
+        };
+
+        Some(std::array::from_fn(|i| {
+            let start = if i == 0 { near } else { split(i) * (1.0 - CASCADE_BLEND) };
+            let end = split(i + 1);
+
+            let slice: Vec<cgmath::Point3<f32>> = corners(start).into_iter().chain(corners(end)).collect();
+            let center = cgmath::Point3::centroid(&slice);
+
+            // A sphere around the slice keeps the size of the projection constant while the
+            // camera rotates, and snapping it to whole texels keeps the shadow edges still while
+            // it moves:
+            let radius = slice.iter().map(|corner| (corner - center).magnitude()).fold(0.0, f32::max);
+            let radius = (radius * 16.0).ceil() / 16.0;
+            let texel_size = 2.0 * radius / SHADOW_MAP_SIZE as f32;
+
+            let center = light_view.transform_point(center);
+            let x = (center.x / texel_size).floor() * texel_size;
+            let y = (center.y / texel_size).floor() * texel_size;
+
+            let near = -scene.max.z.max(center.z + radius) - SHADOW_CASTER_MARGIN;
+            let far = -(center.z - radius);
+            let projection = cgmath::ortho(x - radius, x + radius, y - radius, y + radius, near, far);
+
+            ShadowCascade {
+                view_projection: OPENGL_TO_WGPU_MATRIX * projection * light_view,
+                split_depth: end,
+                penumbra_scale: (far - near) / (2.0 * radius),
+            }
+        }))
+    }
+}
+
+/// What `LightSystem` allocated for a light.
+struct AllocatedLight {
+    light_index: u32,
+    shadow_slot: Option<u32>,
+}
+
+#[derive(Default)]
+pub struct LightSystem {
+    point_lights_reader: Option<ReaderId<ComponentEvent>>,
+    spot_lights_reader: Option<ReaderId<ComponentEvent>>,
+    /// By entity id.
+    point_lights: HashMap<u32, AllocatedLight>,
+    /// Like `point_lights`, for spot lights.
+    spot_lights: HashMap<u32, AllocatedLight>,
+}
+
+impl LightSystem {
+    /// Hands out and frees the light indices and shadow slots of added and removed lights,
+    /// and writes added and modified lights into the light buffer.
+    fn update_lights<L>(
+        reader: &mut ReaderId<ComponentEvent>,
+        allocated: &mut HashMap<u32, AllocatedLight>,
+        entities: &Entities,
+        lights: &ReadStorage<L>,
+        resources: &mut LightsResources,
+    ) where
+        L: LocalLight,
+        L::Storage: Tracked,
+    {
+        let events = lights
             .channel()
-            .read(self.point_lights_reader.as_mut().unwrap());
+            .read(reader);
 
-        // Process parenting updates:
+        // Sort the events on the light storage into added, modified and removed lights:
 
         let mut inserted : BitSet = BitSet::new();
         let mut updated : BitSet = BitSet::new();
@@ -67,22 +327,133 @@ impl<'a> System<'a> for LightSystem {
             }
         }
 
-        for (_, point_light) in (&inserted, &point_lights).join() {
-            // update buffer...
-            log::info!("Adding Point Light!");
-            resources.update_light(&device, &queue, point_light.light_index, GpuLight {
-                position: [point_light.position.x, point_light.position.y, point_light.position.z, 1.0],
-                color: [point_light.color.x, point_light.color.y, point_light.color.z, 1.0],
-                intensity_radius_enabled: [ point_light.intensity, point_light.radius, 1.0, 1.0 ]
-            });
+        for id in (&removed).join() {
+            if let Some(light) = allocated.remove(&id) {
+                resources.free_light(light.light_index);
+                if let Some(slot) = light.shadow_slot {
+                    resources.free_shadow_slot(slot);
+                }
+            }
+        }
+
+        for (entity, _, light) in (entities, &inserted, lights).join() {
+            let mut allocated_light = AllocatedLight {
+                light_index: resources.create_light(),
+                shadow_slot: None,
+            };
+
+            log::info!("Adding {}!", L::NAME);
+            Self::write_light(light, &mut allocated_light, resources);
+            allocated.insert(entity.id(), allocated_light);
+        }
+
+        // Lights inserted in this frame were written above:
+        for (entity, _, _, light) in (entities, &updated, !&inserted, lights).join() {
+            if let Some(allocated_light) = allocated.get_mut(&entity.id()) {
+                Self::write_light(light, allocated_light, resources);
+            }
+        }
+    }
+
+    /// Updates the light in the light buffer and its shadow, handing out or freeing the shadow
+    /// slot when `cast_shadows` changed.
+    fn write_light<L: LocalLight>(light: &L, allocated: &mut AllocatedLight, resources: &mut LightsResources) {
+        if light.cast_shadows() && !light.can_cast_shadows() {
+            log::warn!("The {} is too wide for a shadow map and casts no shadows, its outer angle must be below 90 degrees", L::NAME);
+        }
+
+        match (light.cast_shadows() && light.can_cast_shadows(), allocated.shadow_slot) {
+            (true, None) => {
+                allocated.shadow_slot = resources.create_shadow_slot(L::TYPE);
+                if allocated.shadow_slot.is_none() {
+                    let slots = match L::TYPE {
+                        LightType::Point => MAX_SHADOWED_POINT_LIGHTS,
+                        LightType::Spot => MAX_SHADOWED_SPOT_LIGHTS,
+                    };
+                    log::warn!("All {} shadow slots are taken, the {} casts no shadows", slots, L::NAME);
+                }
+            }
+            (false, Some(slot)) => {
+                resources.free_shadow_slot(slot);
+                allocated.shadow_slot = None;
+            }
+            _ => {}
+        }
+
+        if let Some(slot) = allocated.shadow_slot {
+            resources.light_shadows[slot as usize] = Some(light.shadow());
         }
+
+        let shadow_layer = allocated.shadow_slot.map(LightsResources::shadow_slot_layer);
+        resources.update_light(allocated.light_index, light.gpu_light(shadow_layer));
+    }
+}
+
+impl<'a> System<'a> for LightSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, wgpu::Device>,
+        ReadExpect<'a, wgpu::Queue>,
+        WriteExpect<'a, LightsResources>,
+        ReadExpect<'a, SceneResources>,
+        ReadExpect<'a, SceneBaseResources>,
+        ReadStorage<'a, PointLight>,
+        ReadStorage<'a, SpotLight>,
+        ReadStorage<'a, DirectionalLight>
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            device,
+            queue,
+            mut resources,
+            scene_resources,
+            scene_base_resources,
+            point_lights,
+            spot_lights,
+            directional_lights
+        ) = data;
+
+        resources.directional_light = directional_lights.join().next().and_then(|light| {
+            let cascades = light.cascades(&scene_resources.extend, &scene_base_resources.scene_base)?;
+
+            Some(DirectionalLightData {
+                direction: light.direction.normalize(),
+                color: light.color,
+                intensity: light.intensity,
+                cascades,
+                depth_bias: light.depth_bias,
+                slope_bias: light.slope_bias,
+            })
+        });
+
+        Self::update_lights(
+            self.point_lights_reader.as_mut().unwrap(),
+            &mut self.point_lights,
+            &entities,
+            &point_lights,
+            &mut resources,
+        );
+        Self::update_lights(
+            self.spot_lights_reader.as_mut().unwrap(),
+            &mut self.spot_lights,
+            &entities,
+            &spot_lights,
+            &mut resources,
+        );
+
+        resources.upload_lights(&device, &queue);
     }
 
     fn setup(&mut self, world: &mut World) {
         Self::SystemData::setup(world);
         self.point_lights_reader = Some(
-            WriteStorage::<PointLight>::fetch(&world).register_reader()
+            WriteStorage::<PointLight>::fetch(world).register_reader()
+        );
+        self.spot_lights_reader = Some(
+            WriteStorage::<SpotLight>::fetch(world).register_reader()
         );
     }
 
-}
\ No newline at end of file
+}
@@ -1,6 +1,5 @@
 pub mod scene_graph;
 pub mod camera;
-pub mod spawning;
 pub mod lights;
 pub mod solid_object;
 pub mod playing_field;
@@ -9,7 +8,7 @@ use specs::prelude::*;
 
 use self::{camera::Camera, scene_graph::Parent};
 use crate::scene::solid_object::SolidObject;
-use crate::scene::scene_graph::{Transformation, SceneResources};
+use crate::scene::scene_graph::{Transformation, ModelToWorld, SceneResources};
 use crate::renderer::utils::AABB;
 
 pub fn setup_scene(world: &mut specs::World) {
@@ -22,4 +21,5 @@ pub fn setup_scene(world: &mut specs::World) {
     world.register::<Camera>();
     world.register::<SolidObject>();
     world.register::<Transformation>();
+    world.register::<ModelToWorld>();
 }
\ No newline at end of file
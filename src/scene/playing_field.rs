@@ -3,7 +3,8 @@ use crate::scene::scene_graph::Transformation;
 use crate::renderer::meshes::{MeshResources, MeshType};
 use crate::renderer::geometry::create_cube_geometry;
 use crate::scene::solid_object::SolidObject;
-use crate::renderer::material::{MaterialResources, GpuMaterial};
+use crate::renderer::material::{MaterialResources, Material};
+use crate::renderer::draw_order::LAYER_OPAQUE;
 
 pub struct PlayingField {
     cells_horizontal: u32,
@@ -24,7 +25,7 @@ impl<'a> System<'a> for PlayingField {
 
     );
 
-    fn run(&mut self, data: Self::SystemData) {
+    fn run(&mut self, _data: Self::SystemData) {
 
     }
 
@@ -38,18 +39,19 @@ impl<'a> System<'a> for PlayingField {
             let mut mesh_resources = world.write_resource::<MeshResources>();
             let mut material_resources = world.write_resource::<MaterialResources>();
 
-            let cell_mesh_type = mesh_resources.add_mesh_type(MeshType::new(
+            let cell_mesh_type = MeshType::new(
                 &device,
+                &mesh_resources.instance_bind_group_layout,
                 "Cell",
                 (self.cells_vertical * self.cells_horizontal) as usize,
                 create_cube_geometry()
-            ));
+            );
+            let cell_mesh_type = mesh_resources.add_mesh_type(cell_mesh_type);
 
-            let cell_material = material_resources.add_material(&queue, GpuMaterial {
-                primary: cgmath::Vector4::new(0.5, 0.5, 0.5, 1.0),
-                secondary: cgmath::Vector4::new(1.0, 0.0, 0.0, 1.0),
-                tertiary: cgmath::Vector4::new(0.0, 0.0, 0.0, 1.0),
-                quaternary: cgmath::Vector4::new(0.0, 0.0, 0.0, 1.0)
+            let cell_material = material_resources.add_material(&device, &queue, Material {
+                base_color: cgmath::Vector4::new(0.5, 0.5, 0.5, 1.0),
+                metallic: 0.0,
+                roughness: 0.4
             });
 
             (cell_mesh_type, cell_material)
@@ -65,12 +67,19 @@ impl<'a> System<'a> for PlayingField {
 
             for x in 0..self.cells_horizontal {
                 for z in 0..self.cells_vertical {
-                    let object_index = mesh_resources.create_mesh(cell_mesh_type);
+                    let object_index = match mesh_resources.create_mesh(cell_mesh_type) {
+                        Ok(object_index) => object_index,
+                        Err(error) => {
+                            log::error!("Could not create cell: {}", error);
+                            continue;
+                        }
+                    };
 
                     meshes.push(SolidObject {
                         mesh_type: cell_mesh_type as u32,
                         object_index: object_index as u32,
-                        material: cell_material as u32
+                        material: cell_material as u32,
+                        layer: LAYER_OPAQUE
                     });
 
                     transforms.push(
@@ -84,7 +93,7 @@ impl<'a> System<'a> for PlayingField {
             }
         }
 
-        for (solid_object, transformation) in meshes.into_iter().zip(transforms.into_iter()) {
+        for (solid_object, transformation) in meshes.into_iter().zip(transforms) {
             world.create_entity()
                 .with(solid_object)
                 .with(transformation)
@@ -1,79 +1,159 @@
+use std::collections::HashMap;
+
 use specs::prelude::*;
+use specs::world::Index;
 use specs::Component;
 
-use crate::renderer::command_queue::{CommandQueue, RenderMeshCommand, RenderBatch};
-
-use crate::renderer::shadow_passes::{RenderShadowMeshCommand, RenderShadowBatch};
-use crate::scene::solid_object::SolidObject;
 use crate::renderer::utils::AABB;
 
-#[derive(Component)]
+/// Marks solid objects inside the active camera's frustum. Written by the `SolidObjectSystem`
+/// from the culling results read back from the GPU, so it lags a frame or more behind.
+#[derive(Component, Default)]
+#[storage(NullStorage)]
 pub struct Visible;
 
+/// The world matrix of an entity, i.e. its own `Transformation` combined with the ones of
+/// all its ancestors. Written by the `SceneGraph`.
 #[derive(Component)]
+#[storage(FlaggedStorage)]
 pub struct ModelToWorld {
-    transform: cgmath::Matrix4<f32>
+    pub transform: cgmath::Matrix4<f32>
 }
 
+/// The local transformation of an entity, relative to its parent.
 #[derive(Component)]
+#[storage(FlaggedStorage)]
 pub struct Transformation {
     pub position: cgmath::Point3<f32>,
     pub rotation: cgmath::Euler<cgmath::Deg<f32>>,
     pub scale: cgmath::Point3<f32>
 }
 
+impl Transformation {
+    pub fn to_matrix(&self) -> cgmath::Matrix4<f32> {
+        cgmath::Matrix4::from_translation(cgmath::Vector3::new(self.position.x, self.position.y, self.position.z)) *
+            cgmath::Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z) *
+            cgmath::Matrix4::from_angle_x(self.rotation.x) *
+            cgmath::Matrix4::from_angle_y(self.rotation.y) *
+            cgmath::Matrix4::from_angle_z(self.rotation.z)
+    }
+}
+
 #[derive(Component)]
 #[storage(FlaggedStorage)]
-pub struct Parent(Option<Entity>);
-
-struct TeeNode {
-    id: u32,
-    parent: u32
-}
+pub struct Parent(pub Option<Entity>);
 
 pub struct SceneResources {
     pub extend: AABB
 }
 
 /// The Scene Graph represents the hierarchical structure of the scene objects.
-/// Each entity can be parented to another one.
+/// Each entity can be parented to another one. Entities without a parent are roots
+/// of their own tree.
+#[derive(Default)]
 pub struct SceneGraph {
     parents_reader: Option<ReaderId<ComponentEvent>>,
-    root_node: Option<Entity>,
-    node_to_children: std::collections::HashMap<Entity, std::vec::Vec<Entity>>
+    transformations_reader: Option<ReaderId<ComponentEvent>>,
+    node_to_children: HashMap<Entity, Vec<Entity>>,
+    // Keyed by index, since removal events don't tell the generation of the entity.
+    node_to_parent: HashMap<Index, Entity>
 }
 
-impl Default for SceneGraph {
-    fn default() -> Self {
-        SceneGraph {
-            parents_reader: None,
-            root_node: None,
-            node_to_children: std::collections::HashMap::new()
+impl SceneGraph {
+
+    /// Moves `entity` below `parent`. Parenting an entity to one of its own descendants is
+    /// rejected and keeps the previous parent, until the `Parent` is set again.
+    fn add_node(&mut self, entity: Entity, parent: Entity) {
+        if self.is_ancestor(entity, parent) {
+            log::error!("Cannot parent {:?} to its own descendant {:?}", entity, parent);
+            return;
         }
+
+        self.remove_node(entity.id());
+        self.node_to_children
+            .entry(parent)
+            .or_default()
+            .push(entity);
+        self.node_to_parent.insert(entity.id(), parent);
     }
-}
 
-impl SceneGraph {
+    fn remove_node(&mut self, id: Index) {
+        if let Some(parent) = self.node_to_parent.remove(&id)
+            && let Some(children) = self.node_to_children.get_mut(&parent)
+        {
+            children.retain(|child| child.id() != id);
+            if children.is_empty() {
+                self.node_to_children.remove(&parent);
+            }
+        }
+    }
 
-    fn add_node(&mut self, entity: Entity, parent: Option<Entity>) {
+    fn is_ancestor(&self, ancestor: Entity, entity: Entity) -> bool {
+        let mut current = Some(entity);
+        while let Some(node) = current {
+            if node == ancestor {
+                return true;
+            }
+            current = self.node_to_parent.get(&node.id()).copied();
+        }
+        false
+    }
+
+    fn depth(&self, entity: Entity) -> usize {
+        let mut depth = 0;
+        let mut current = entity;
+        while let Some(parent) = self.node_to_parent.get(&current.id()) {
+            depth += 1;
+            current = *parent;
+        }
+        depth
+    }
+
+    /// Recomputes the world matrices of `entity` and all of its descendants.
+    fn update_subtree(
+        &self,
+        entity: Entity,
+        entities: &Entities,
+        transformations: &ReadStorage<Transformation>,
+        model_to_world: &mut WriteStorage<ModelToWorld>,
+        updated: &mut BitSet
+    ) {
+        let parent_transform = self.node_to_parent
+            .get(&entity.id())
+            .filter(|parent| entities.is_alive(**parent))
+            .and_then(|parent| model_to_world.get(*parent))
+            .map(|parent| parent.transform);
+
+        let mut stack = vec![ (entity, parent_transform) ];
+
+        while let Some((node, parent_transform)) = stack.pop() {
+            updated.add(node.id());
+
+            // Nodes without a transformation have no world matrix, so their
+            // children are placed as if they were roots.
+            let transform = transformations.get(node).map(|transformation| match parent_transform {
+                Some(parent_transform) => parent_transform * transformation.to_matrix(),
+                None => transformation.to_matrix()
+            });
 
-        if let Some(parent) = parent {
-            match self.node_to_children.get_mut(&parent) {
-                Some(children) => {
-                    children.push(entity);
+            match transform {
+                Some(transform) => {
+                    model_to_world.insert(node, ModelToWorld { transform }).unwrap();
                 }
                 None => {
-                    self.node_to_children.insert( parent, vec![ entity ]);
+                    model_to_world.remove(node);
                 }
             }
-        } else {
-            if self.root_node.is_some() {
-                panic!("Scene Graph already has a root node!");
+
+            if let Some(children) = self.node_to_children.get(&node) {
+                for child in children {
+                    if entities.is_alive(*child) {
+                        stack.push((*child, transform));
+                    }
+                }
             }
-            self.root_node = Some(entity);
         }
     }
-
 }
 
 impl<'a> System<'a> for SceneGraph {
@@ -82,14 +162,12 @@ impl<'a> System<'a> for SceneGraph {
         Entities<'a>,
         ReadStorage<'a, Parent>,
         ReadStorage<'a, Transformation>,
-        ReadStorage<'a, SolidObject>,
-        WriteExpect<'a, CommandQueue<RenderMeshCommand, RenderBatch>>,
-        WriteExpect<'a, CommandQueue<RenderShadowMeshCommand, RenderShadowBatch>>
+        WriteStorage<'a, ModelToWorld>
     );
 
     fn run(&mut self, data: Self::SystemData) {
 
-        if self.parents_reader.is_none() {
+        if self.parents_reader.is_none() || self.transformations_reader.is_none() {
             return;
         }
 
@@ -97,83 +175,195 @@ impl<'a> System<'a> for SceneGraph {
             entities,
             parents,
             transformations,
-            solid_objects,
-            mut commands_queue,
-            mut shadow_commands_queue,
+            mut model_to_world,
         ) = data;
 
-        let events = parents
-            .channel()
-            .read(self.parents_reader.as_mut().unwrap());
-
         // Process parenting updates:
 
-        let mut inserted : BitSet = BitSet::new();
-        let mut updated : BitSet = BitSet::new();
-        let mut removed : BitSet = BitSet::new();
+        let mut reparented : BitSet = BitSet::new();
+        let mut dirty : BitSet = BitSet::new();
 
-        for event in events {
+        for event in parents.channel().read(self.parents_reader.as_mut().unwrap()) {
             match event {
-                ComponentEvent::Inserted(id) => {
-                    inserted.add(*id);
-                }
-                ComponentEvent::Modified(id) => {
-                    updated.add(*id);
+                ComponentEvent::Inserted(id) | ComponentEvent::Modified(id) => {
+                    reparented.add(*id);
                 }
                 ComponentEvent::Removed(id) => {
-                    removed.add(*id);
+                    self.remove_node(*id);
+                    dirty.add(*id);
                 }
             }
         }
 
-        for (entity, parent, _) in (&entities, &parents, &inserted).join() {
-            if let Some(parent_entity) = parent.0 {
-                // add entity to graph with parent_entity as its... well... parent
+        for (entity, parent, _) in (&entities, &parents, &reparented).join() {
+            match parent.0 {
+                Some(parent_entity) => self.add_node(entity, parent_entity),
+                None => self.remove_node(entity.id())
             }
+            dirty.add(entity.id());
         }
 
-        for (entity, parent, _) in (&entities, &parents, &updated).join() {
-            if let Some(parent_entity) = parent.0 {
-                //modify graph: E.g. remove children and children of children...
+        for event in transformations.channel().read(self.transformations_reader.as_mut().unwrap()) {
+            match event {
+                ComponentEvent::Inserted(id) |
+                ComponentEvent::Modified(id) |
+                ComponentEvent::Removed(id) => {
+                    dirty.add(*id);
+                }
             }
         }
 
-        for (entity, parent, _) in (&entities, &parents, &removed).join() {
+        // Drop deleted parents, their children are roots now:
 
-        }
-
-        // Traverse tree and update transforms
-
-        for (entity, transform) in (&entities, &transformations).join() {
+        let dead_parents: Vec<Entity> = self.node_to_children
+            .keys()
+            .filter(|node| !entities.is_alive(**node))
+            .copied()
+            .collect();
 
+        for dead_parent in dead_parents {
+            for child in self.node_to_children.remove(&dead_parent).unwrap() {
+                self.node_to_parent.remove(&child.id());
+                dirty.add(child.id());
+            }
         }
 
-        // Compute visibility?
+        // Traverse tree and update transforms. Parents are visited before their children,
+        // so every subtree is only updated once.
 
-        // Compute render order?
+        let mut dirty_nodes: Vec<(usize, Entity)> = (&entities, &dirty)
+            .join()
+            .map(|(entity, _)| (self.depth(entity), entity))
+            .collect();
+        dirty_nodes.sort_by_key(|(depth, _)| *depth);
 
-        for solid_object in (&solid_objects).join() {
-            commands_queue.enqueue_command(RenderMeshCommand {
-                mesh_type: solid_object.mesh_type as u8,
-                material: solid_object.material as u8,
-                object_index: solid_object.object_index as u16,
-                order: 1
-            });
-            shadow_commands_queue.enqueue_command(RenderShadowMeshCommand {
-                mesh_type: solid_object.mesh_type as u8,
-                object_index: solid_object.object_index as u16,
-                order: 1
-            });
-        }
+        let mut updated : BitSet = BitSet::new();
 
+        for (_, entity) in dirty_nodes {
+            if !updated.contains(entity.id()) {
+                self.update_subtree(entity, &entities, &transformations, &mut model_to_world, &mut updated);
+            }
+        }
     }
 
     fn setup(&mut self, world: &mut World) {
         log::info!("Setup on SceneGraph");
         Self::SystemData::setup(world);
         self.parents_reader = Some(
-            WriteStorage::<Parent>::fetch(&world).register_reader()
+            WriteStorage::<Parent>::fetch(world).register_reader()
         );
+        self.transformations_reader = Some(
+            WriteStorage::<Transformation>::fetch(world).register_reader()
+        );
+    }
+
+}
+#[cfg(test)]
+mod tests {
+    use cgmath::{Deg, Euler, Point3, Vector3};
+
+    use super::*;
+
+    fn translation(x: f32) -> Transformation {
+        Transformation {
+            position: Point3::new(x, 0.0, 0.0),
+            rotation: Euler::new(Deg(0.0), Deg(0.0), Deg(0.0)),
+            scale: Point3::new(1.0, 1.0, 1.0)
+        }
+    }
+
+    fn setup() -> (World, SceneGraph) {
+        let mut world = World::new();
+        let mut scene_graph = SceneGraph::default();
+        System::setup(&mut scene_graph, &mut world);
+        (world, scene_graph)
+    }
+
+    fn spawn(world: &mut World, x: f32, parent: Option<Entity>) -> Entity {
+        world.create_entity()
+            .with(translation(x))
+            .with(Parent(parent))
+            .build()
+    }
+
+    fn run(world: &mut World, scene_graph: &mut SceneGraph) {
+        scene_graph.run_now(world);
+        world.maintain();
+    }
+
+    fn world_x(world: &World, entity: Entity) -> f32 {
+        world.read_storage::<ModelToWorld>().get(entity).unwrap().transform.w.x
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn reparenting_moves_the_subtree() {
+        let (mut world, mut scene_graph) = setup();
+        let first = spawn(&mut world, 1.0, None);
+        let second = spawn(&mut world, 10.0, None);
+        let child = spawn(&mut world, 100.0, Some(first));
+        let grandchild = spawn(&mut world, 1000.0, Some(child));
+        run(&mut world, &mut scene_graph);
+
+        assert_eq!(world_x(&world, grandchild), 1101.0);
+
+        world.write_storage::<Parent>().insert(child, Parent(Some(second))).unwrap();
+        run(&mut world, &mut scene_graph);
+
+        assert_eq!(world_x(&world, child), 110.0);
+        assert_eq!(world_x(&world, grandchild), 1110.0);
+        assert!(!scene_graph.node_to_children.contains_key(&first));
+        assert_eq!(scene_graph.node_to_children[&second], vec![child]);
+
+        world.write_storage::<Parent>().insert(child, Parent(None)).unwrap();
+        run(&mut world, &mut scene_graph);
+
+        assert_eq!(world_x(&world, child), 100.0);
+        assert_eq!(world_x(&world, grandchild), 1100.0);
+    }
+
+    #[test]
+    fn parenting_to_a_descendant_keeps_the_previous_parent() {
+        let (mut world, mut scene_graph) = setup();
+        let root = spawn(&mut world, 1.0, None);
+        let node = spawn(&mut world, 10.0, Some(root));
+        let child = spawn(&mut world, 100.0, Some(node));
+        run(&mut world, &mut scene_graph);
+
+        world.write_storage::<Parent>().insert(node, Parent(Some(child))).unwrap();
+        run(&mut world, &mut scene_graph);
+
+        assert_eq!(scene_graph.node_to_parent[&node.id()], root);
+        assert_eq!(scene_graph.node_to_parent[&child.id()], node);
+        assert_eq!(world_x(&world, child), 111.0);
+
+        world.write_storage::<Parent>().insert(node, Parent(Some(node))).unwrap();
+        run(&mut world, &mut scene_graph);
+
+        assert_eq!(scene_graph.node_to_parent[&node.id()], root);
+    }
+
+    #[test]
+    fn parents_are_updated_before_their_children() {
+        let (mut world, mut scene_graph) = setup();
+
+        // Created leaf first, so that the leaf comes first in the storage as well:
+        let leaf = world.create_entity().with(translation(100.0)).build();
+        let node = world.create_entity().with(translation(10.0)).build();
+        let root = spawn(&mut world, 1.0, None);
+        world.write_storage::<Parent>().insert(node, Parent(Some(root))).unwrap();
+        world.write_storage::<Parent>().insert(leaf, Parent(Some(node))).unwrap();
+        run(&mut world, &mut scene_graph);
+
+        assert_eq!(world_x(&world, leaf), 111.0);
+
+        world.write_storage::<Transformation>().insert(leaf, translation(200.0)).unwrap();
+        world.write_storage::<Transformation>().insert(root, translation(2.0)).unwrap();
+        run(&mut world, &mut scene_graph);
+
+        assert_eq!(world_x(&world, node), 12.0);
+        assert_eq!(world_x(&world, leaf), 212.0);
+
+        let transform = world.read_storage::<ModelToWorld>().get(leaf).unwrap().transform;
+        assert_eq!(transform, cgmath::Matrix4::from_translation(Vector3::new(212.0, 0.0, 0.0)));
+    }
+}
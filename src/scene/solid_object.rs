@@ -1,11 +1,14 @@
+use std::collections::{HashMap, HashSet};
+
 use specs::prelude::*;
+use specs::world::Index;
 use specs::Component;
 
-use crate::scene::scene_graph::Transformation;
+use crate::scene::scene_graph::{ModelToWorld, Visible};
 
-use crate::renderer::meshes::{MeshResources, MeshType};
-use crate::renderer::utils::{GpuMatrix4BGA, GpuMatrix4};
-use crate::renderer::geometry::create_cube_geometry;
+use crate::renderer::culling::VisibleInstances;
+use crate::renderer::meshes::MeshResources;
+use crate::renderer::utils::GpuMatrix4;
 
 
 #[derive(Component)]
@@ -13,42 +16,52 @@ use crate::renderer::geometry::create_cube_geometry;
 pub struct SolidObject {
     pub mesh_type: u32,
     pub object_index: u32,
-    pub material: u32
+    pub material: u32,
+    /// See `LAYER_OPAQUE`.
+    pub layer: u32
 }
 
 #[derive(Default)]
 pub struct SolidObjectSystem {
     reader: Option<ReaderId<ComponentEvent>>,
+    model_to_world_reader: Option<ReaderId<ComponentEvent>>,
+    /// The mesh type and object index of every object, since removal events don't tell
+    /// which instance the object had.
+    instances: HashMap<Index, (u32, u32)>,
+    /// The generation of the culling results `Visible` was last updated from.
+    visibility_generation: u64,
 }
 
 impl SolidObjectSystem {
     pub fn new() -> Self {
-        SolidObjectSystem {
-            reader: None
-        }
+        SolidObjectSystem::default()
     }
 }
 
 impl<'a> System<'a> for SolidObjectSystem {
     type SystemData = (
+        Entities<'a>,
         ReadStorage<'a, SolidObject>,
-        ReadStorage<'a, Transformation>,
+        ReadStorage<'a, ModelToWorld>,
+        WriteStorage<'a, Visible>,
         WriteExpect<'a, MeshResources>,
-        ReadExpect<'a, wgpu::Device>,
+        ReadExpect<'a, VisibleInstances>,
         ReadExpect<'a, wgpu::Queue>,
     );
 
     fn run(&mut self, data: Self::SystemData) {
 
-        if self.reader.is_none() {
+        if self.reader.is_none() || self.model_to_world_reader.is_none() {
             return;
         }
 
         let (
+            entities,
             objects,
-            transformations,
+            model_to_world,
+            mut visible,
             mut mesh_resources,
-            device,
+            visible_instances,
             queue
         ) = data;
 
@@ -56,18 +69,15 @@ impl<'a> System<'a> for SolidObjectSystem {
             .channel()
             .read(self.reader.as_mut().unwrap());
 
-        // Process parenting updates:
+        // Process object updates:
 
-        let mut update_transform : BitSet = BitSet::new();
+        let mut changed : BitSet = BitSet::new();
         let mut removed : BitSet = BitSet::new();
 
         for event in events {
             match event {
-                ComponentEvent::Inserted(id) => {
-                    update_transform.add(*id);
-                }
-                ComponentEvent::Modified(id) => {
-                    update_transform.add(*id);
+                ComponentEvent::Inserted(id) | ComponentEvent::Modified(id) => {
+                    changed.add(*id);
                 }
                 ComponentEvent::Removed(id) => {
                     removed.add(*id);
@@ -75,22 +85,74 @@ impl<'a> System<'a> for SolidObjectSystem {
             }
         }
 
-        for (object, transformation, _) in (&objects, &transformations, update_transform).join() {
-            let position = transformation.position;
-            let scale = transformation.scale;
-            let rotation = transformation.rotation;
+        // Free the instances of removed objects, so they are no longer drawn and their index
+        // can be reused. Objects inserted again since then count as changed:
+
+        for id in (&removed).join() {
+            if !objects.mask().contains(id)
+                && let Some((mesh_type, object_index)) = self.instances.remove(&id)
+            {
+                mesh_resources.mesh_types[mesh_type as usize].free_instance(object_index);
+            }
+        }
+
+        for (entity, object, _) in (&entities, &objects, &changed).join() {
+            let instance = (object.mesh_type, object.object_index);
+            if let Some((mesh_type, object_index)) = self.instances.insert(entity.id(), instance)
+                && (mesh_type, object_index) != instance
+            {
+                mesh_resources.mesh_types[mesh_type as usize].free_instance(object_index);
+            }
+        }
+
+        let mut update_transform = changed;
+
+        // World matrices change whenever the object or one of its ancestors moves:
+
+        for event in model_to_world.channel().read(self.model_to_world_reader.as_mut().unwrap()) {
+            match event {
+                ComponentEvent::Inserted(id) | ComponentEvent::Modified(id) => {
+                    update_transform.add(*id);
+                }
+                ComponentEvent::Removed(_) => {}
+            }
+        }
 
-            let matrix = GpuMatrix4::new(
-                 cgmath::Matrix4::from_translation(cgmath::Vector3::new(position.x, position.y, position.z)) *
-                    cgmath::Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z) *
-                    cgmath::Matrix4::from_angle_x(rotation.x) *
-                    cgmath::Matrix4::from_angle_y(rotation.y) *
-                    cgmath::Matrix4::from_angle_z(rotation.z)
-            );
+        for (object, model_to_world, _) in (&objects, &model_to_world, update_transform).join() {
+            let matrix = GpuMatrix4::new(model_to_world.transform);
 
             let mesh_type = mesh_resources.mesh_types.get_mut(object.mesh_type as usize).unwrap();
 
             mesh_type.update_model_matrix(object.object_index, matrix);
+            mesh_type.update_material(object.object_index, object.material);
+            mesh_type.update_layer(object.object_index, object.layer);
+        }
+
+        mesh_resources.upload_instances(&queue);
+
+        // Mark the objects the camera culling found visible, whenever new results arrived:
+
+        let results = visible_instances.0.lock().unwrap();
+
+        if results.generation != self.visibility_generation {
+            self.visibility_generation = results.generation;
+
+            let visible_instances: Vec<HashSet<u32>> = results.mesh_types
+                .iter()
+                .map(|instances| instances.iter().copied().collect())
+                .collect();
+
+            for (entity, object) in (&entities, &objects).join() {
+                let is_visible = visible_instances
+                    .get(object.mesh_type as usize)
+                    .is_some_and(|instances| instances.contains(&object.object_index));
+
+                if is_visible {
+                    visible.insert(entity, Visible).unwrap();
+                } else {
+                    visible.remove(entity);
+                }
+            }
         }
     }
 
@@ -98,7 +160,10 @@ impl<'a> System<'a> for SolidObjectSystem {
         Self::SystemData::setup(world);
 
         self.reader = Some(
-            WriteStorage::<SolidObject>::fetch(&world).register_reader()
+            WriteStorage::<SolidObject>::fetch(world).register_reader()
+        );
+        self.model_to_world_reader = Some(
+            WriteStorage::<ModelToWorld>::fetch(world).register_reader()
         );
     }
 }